@@ -0,0 +1,149 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+//! Image input for the Game Boy Camera
+use snafu::Snafu;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Camera sensor width in pixels
+pub const CAMERA_WIDTH: usize = 128;
+/// Camera sensor height in pixels
+pub const CAMERA_HEIGHT: usize = 112;
+
+#[derive(Debug, Snafu)]
+pub enum CameraError {
+  #[snafu(display("IO error: {}", source))]
+  Io { source: io::Error },
+  #[snafu(display("Invalid PNG image: {}", source))]
+  Png { source: png::DecodingError },
+}
+
+impl From<io::Error> for CameraError {
+  fn from(source: io::Error) -> CameraError {
+    CameraError::Io { source }
+  }
+}
+
+impl From<png::DecodingError> for CameraError {
+  fn from(source: png::DecodingError) -> CameraError {
+    CameraError::Png { source }
+  }
+}
+
+/// A grayscale image seen by the camera sensor, where 0 is black and 255 is white
+#[derive(Clone)]
+pub struct CameraImage {
+  pixels: Box<[u8; CAMERA_WIDTH * CAMERA_HEIGHT]>,
+}
+
+impl fmt::Debug for CameraImage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CameraImage").finish()
+  }
+}
+
+impl CameraImage {
+  /// Creates an image with all pixels set to the same value
+  pub fn uniform(value: u8) -> CameraImage {
+    CameraImage {
+      pixels: Box::new([value; CAMERA_WIDTH * CAMERA_HEIGHT]),
+    }
+  }
+  pub fn from_png_path(path: &Path) -> Result<CameraImage, CameraError> {
+    let mut file = File::open(path)?;
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    CameraImage::from_png(&data)
+  }
+  /// Decodes a PNG image and scales it to the sensor resolution
+  pub fn from_png(data: &[u8]) -> Result<CameraImage, CameraError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer)?;
+
+    let channels = info.color_type.samples();
+    let (width, height) = (info.width as usize, info.height as usize);
+    let luma = |x: usize, y: usize| {
+      let offset = y * info.line_size + x * channels;
+      match channels {
+        1 | 2 => buffer[offset],
+        _ => {
+          let (r, g, b) = (
+            buffer[offset] as u32,
+            buffer[offset + 1] as u32,
+            buffer[offset + 2] as u32,
+          );
+          ((r * 299 + g * 587 + b * 114) / 1000) as u8
+        }
+      }
+    };
+    let mut image = CameraImage::uniform(0);
+    for y in 0..CAMERA_HEIGHT {
+      for x in 0..CAMERA_WIDTH {
+        image.pixels[y * CAMERA_WIDTH + x] =
+          luma(x * width / CAMERA_WIDTH, y * height / CAMERA_HEIGHT);
+      }
+    }
+    Ok(image)
+  }
+  /// Returns a pixel. Coordinates outside the image are clamped to the edges
+  pub fn pixel(&self, x: isize, y: isize) -> u8 {
+    let x = x.max(0).min(CAMERA_WIDTH as isize - 1) as usize;
+    let y = y.max(0).min(CAMERA_HEIGHT as isize - 1) as usize;
+    self.pixels[y * CAMERA_WIDTH + x]
+  }
+}
+
+#[cfg(test)]
+fn encode_png(width: u32, height: u32, color: png::ColorType, data: &[u8]) -> Vec<u8> {
+  let mut png = vec![];
+  {
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(data).unwrap();
+  }
+  png
+}
+
+#[test]
+fn test_png_scaling() {
+  let png = encode_png(2, 2, png::ColorType::Grayscale, &[0x00, 0x40, 0x80, 0xc0]);
+  let image = CameraImage::from_png(&png).unwrap();
+  let (right, bottom) = (CAMERA_WIDTH as isize - 1, CAMERA_HEIGHT as isize - 1);
+  assert_eq!(image.pixel(0, 0), 0x00);
+  assert_eq!(image.pixel(63, 55), 0x00);
+  assert_eq!(image.pixel(64, 0), 0x40);
+  assert_eq!(image.pixel(right, 0), 0x40);
+  assert_eq!(image.pixel(0, 56), 0x80);
+  assert_eq!(image.pixel(right, bottom), 0xc0);
+  // Coordinates are clamped to the edges
+  assert_eq!(image.pixel(-1, -1), 0x00);
+  assert_eq!(image.pixel(right + 1, bottom + 1), 0xc0);
+}
+
+#[test]
+fn test_png_luma() {
+  let png = encode_png(1, 1, png::ColorType::RGB, &[0xff, 0x00, 0x00]);
+  let image = CameraImage::from_png(&png).unwrap();
+  assert_eq!(image.pixel(0, 0), 76);
+  assert!(CameraImage::from_png(b"not a png").is_err());
+}
@@ -0,0 +1,311 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+//! Game Genie and GameShark cheat codes
+use snafu::Snafu;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Snafu)]
+pub enum CheatError {
+  #[snafu(display("IO error: {}", source))]
+  Io { source: io::Error },
+  #[snafu(display("Invalid cheat code \"{}\"", code))]
+  InvalidCode { code: String },
+  #[snafu(display("Invalid cheat file: {}", msg))]
+  InvalidFile { msg: String },
+}
+
+impl From<io::Error> for CheatError {
+  fn from(source: io::Error) -> CheatError {
+    CheatError::Io { source }
+  }
+}
+
+/// Game Genie code, which replaces a byte read from cartridge ROM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameGenieCode {
+  pub addr: u16,
+  pub value: u8,
+  /// The code only applies if the original ROM byte matches
+  pub compare: Option<u8>,
+}
+
+impl GameGenieCode {
+  /// Returns the value seen by the CPU when `original` is read from `addr`
+  pub fn apply(&self, addr: u16, original: u8) -> Option<u8> {
+    if addr == self.addr && self.compare.unwrap_or(original) == original {
+      Some(self.value)
+    } else {
+      None
+    }
+  }
+}
+
+impl FromStr for GameGenieCode {
+  type Err = CheatError;
+  /// Parses a code in the ABC-DEF or ABC-DEF-GHI format
+  fn from_str(code: &str) -> Result<GameGenieCode, CheatError> {
+    let invalid = || CheatError::InvalidCode {
+      code: code.to_string(),
+    };
+    let groups = code.split('-').collect::<Vec<_>>();
+    let valid_groups = match groups.len() {
+      2 => groups[..2].iter().all(|group| group.len() == 3),
+      3 => groups.iter().all(|group| group.len() == 3),
+      _ => false,
+    };
+    if !valid_groups {
+      return Err(invalid());
+    }
+    let nibbles = groups
+      .concat()
+      .chars()
+      .map(|ch| ch.to_digit(16).map(|digit| digit as u8))
+      .collect::<Option<Vec<_>>>()
+      .ok_or_else(invalid)?;
+    let value = nibbles[0] << 4 | nibbles[1];
+    let addr = u16::from(nibbles[5] ^ 0xf) << 12
+      | u16::from(nibbles[2]) << 8
+      | u16::from(nibbles[3]) << 4
+      | u16::from(nibbles[4]);
+    if addr >= 0x8000 {
+      return Err(invalid());
+    }
+    let compare = if nibbles.len() == 9 {
+      // The 7th digit is not used
+      let scrambled = nibbles[6] << 4 | nibbles[8];
+      Some(scrambled.rotate_right(2) ^ 0xba)
+    } else {
+      None
+    };
+    Ok(GameGenieCode {
+      addr,
+      value,
+      compare,
+    })
+  }
+}
+
+/// GameShark code, which writes a byte to RAM once per frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameSharkCode {
+  pub addr: u16,
+  pub value: u8,
+  /// External RAM bank for $A000-$BFFF, or None for the currently mapped bank
+  pub ram_bank: Option<u8>,
+}
+
+impl FromStr for GameSharkCode {
+  type Err = CheatError;
+  /// Parses a code in the TTVVAAAA format, where TT is the code type, VV the value and AAAA the
+  /// little-endian address
+  fn from_str(code: &str) -> Result<GameSharkCode, CheatError> {
+    let invalid = || CheatError::InvalidCode {
+      code: code.to_string(),
+    };
+    if code.len() != 8 {
+      return Err(invalid());
+    }
+    let code_bytes = u32::from_str_radix(code, 16)
+      .map_err(|_| invalid())?
+      .to_be_bytes();
+    let ram_bank = match code_bytes[0] {
+      0x00 | 0x01 => None,
+      code_type @ 0x80..=0x8f => Some(code_type & 0x0f),
+      _ => return Err(invalid()),
+    };
+    Ok(GameSharkCode {
+      addr: u16::from_le_bytes([code_bytes[2], code_bytes[3]]),
+      value: code_bytes[1],
+      ram_bank,
+    })
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheatCode {
+  GameGenie(GameGenieCode),
+  GameShark(GameSharkCode),
+}
+
+impl FromStr for CheatCode {
+  type Err = CheatError;
+  fn from_str(code: &str) -> Result<CheatCode, CheatError> {
+    let code = code.trim();
+    if code.contains('-') {
+      code.parse().map(CheatCode::GameGenie)
+    } else {
+      code.parse().map(CheatCode::GameShark)
+    }
+  }
+}
+
+/// A named cheat consisting of one or more codes
+#[derive(Clone, Debug)]
+pub struct Cheat {
+  pub description: String,
+  pub codes: Vec<CheatCode>,
+  pub enabled: bool,
+}
+
+impl Cheat {
+  /// Creates a cheat from codes separated by '+'
+  pub fn new(description: &str, codes: &str) -> Result<Cheat, CheatError> {
+    let codes = codes
+      .split('+')
+      .map(str::parse)
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(Cheat {
+      description: description.to_string(),
+      codes,
+      enabled: true,
+    })
+  }
+}
+
+/// A list of cheats, which can be toggled at runtime and applied with `Machine::set_cheats`
+#[derive(Clone, Debug, Default)]
+pub struct Cheats {
+  pub cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+  pub fn new() -> Cheats {
+    Cheats::default()
+  }
+  pub fn add(&mut self, cheat: Cheat) {
+    self.cheats.push(cheat);
+  }
+  pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+    if let Some(cheat) = self.cheats.get_mut(index) {
+      cheat.enabled = enabled;
+    }
+  }
+  pub fn game_genie_codes(&self) -> Vec<GameGenieCode> {
+    self
+      .enabled_codes()
+      .filter_map(|code| match code {
+        CheatCode::GameGenie(code) => Some(code),
+        _ => None,
+      })
+      .collect()
+  }
+  pub fn game_shark_codes(&self) -> Vec<GameSharkCode> {
+    self
+      .enabled_codes()
+      .filter_map(|code| match code {
+        CheatCode::GameShark(code) => Some(code),
+        _ => None,
+      })
+      .collect()
+  }
+  fn enabled_codes(&self) -> impl Iterator<Item = CheatCode> + '_ {
+    self
+      .cheats
+      .iter()
+      .filter(|cheat| cheat.enabled)
+      .flat_map(|cheat| cheat.codes.iter().copied())
+  }
+  pub fn from_path(path: &Path) -> Result<Cheats, CheatError> {
+    Cheats::from_cht(&fs::read_to_string(path)?)
+  }
+  /// Parses a .cht file in the libretro format:
+  ///
+  /// ```text
+  /// cheats = 1
+  /// cheat0_desc = "Infinite lives"
+  /// cheat0_code = "010238CD"
+  /// cheat0_enable = true
+  /// ```
+  pub fn from_cht(text: &str) -> Result<Cheats, CheatError> {
+    let mut entries = Vec::new();
+    for line in text.lines().map(str::trim) {
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let mut parts = line.splitn(2, '=');
+      let key = parts.next().unwrap_or_default().trim();
+      let value = parts
+        .next()
+        .ok_or_else(|| CheatError::InvalidFile {
+          msg: format!("Invalid line \"{}\"", line),
+        })?
+        .trim()
+        .trim_matches('"');
+      entries.push((key, value));
+    }
+    let value_of = |key: &str| {
+      entries
+        .iter()
+        .find(|(entry_key, _)| *entry_key == key)
+        .map(|&(_, value)| value)
+    };
+    let count = value_of("cheats")
+      .and_then(|count| count.parse::<usize>().ok())
+      .ok_or_else(|| CheatError::InvalidFile {
+        msg: "Missing cheat count".to_string(),
+      })?;
+    let mut cheats = Cheats::new();
+    for idx in 0..count {
+      let code =
+        value_of(&format!("cheat{}_code", idx)).ok_or_else(|| CheatError::InvalidFile {
+          msg: format!("Missing code for cheat {}", idx),
+        })?;
+      let description = value_of(&format!("cheat{}_desc", idx)).unwrap_or_default();
+      let mut cheat = Cheat::new(description, code)?;
+      cheat.enabled = value_of(&format!("cheat{}_enable", idx)) == Some("true");
+      cheats.add(cheat);
+    }
+    Ok(cheats)
+  }
+}
+
+#[test]
+fn test_parse_codes() {
+  let code: GameGenieCode = "00A-17B-C49".parse().unwrap();
+  assert_eq!(code.addr, 0x4a17);
+  assert_eq!(code.value, 0x00);
+  assert_eq!(code.compare, Some(0xc8));
+  assert_eq!(code.apply(0x4a17, 0xc8), Some(0x00));
+  assert_eq!(code.apply(0x4a17, 0xc9), None);
+  let code: GameGenieCode = "3EA-14B".parse().unwrap();
+  assert_eq!((code.addr, code.value, code.compare), (0x4a14, 0x3e, None));
+  assert!("3EA-147".parse::<GameGenieCode>().is_err());
+  assert!("3EA-14".parse::<GameGenieCode>().is_err());
+
+  let code: GameSharkCode = "010238CD".parse().unwrap();
+  assert_eq!((code.addr, code.value, code.ram_bank), (0xcd38, 0x02, None));
+  let code: GameSharkCode = "8199ADA0".parse().unwrap();
+  assert_eq!((code.addr, code.ram_bank), (0xa0ad, Some(1)));
+  assert!("0102XXCD".parse::<GameSharkCode>().is_err());
+}
+
+#[test]
+fn test_cht_file() {
+  let cheats = Cheats::from_cht(
+    "cheats = 2\n\ncheat0_desc = \"Lives\"\ncheat0_code = \"010238CD+00A-17B-C49\"\n\
+     cheat0_enable = true\ncheat1_desc = \"Money\"\ncheat1_code = \"019938CD\"\n\
+     cheat1_enable = false\n",
+  )
+  .unwrap();
+  assert_eq!(cheats.cheats.len(), 2);
+  assert_eq!(cheats.cheats[0].description, "Lives");
+  assert_eq!(cheats.game_genie_codes().len(), 1);
+  assert_eq!(cheats.game_shark_codes().len(), 1);
+  assert!(!cheats.cheats[1].enabled);
+}
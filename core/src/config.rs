@@ -15,15 +15,24 @@
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
 use std::sync::Arc;
 
+mod archive;
 mod bootrom;
 mod cartridge;
+mod header;
 mod model;
+pub mod patch;
+mod romdb;
 
 pub use self::bootrom::Bootrom;
 pub use self::cartridge::{Cartridge, CartridgeRamSize, CartridgeRomSize, CartridgeType};
+pub use self::header::{CartridgeHeader, CgbFlag, Checksum, Destination, Licensee};
 pub use self::model::{Model, DEFAULT_MODEL_PRIORITY};
+pub use self::romdb::{RomInfo, RomQuirks};
 use crate::hardware::BootromData;
 
+/// Largest accepted ROM size: 8 MiB, the largest size a cartridge header can declare
+const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
 #[derive(Clone)]
 pub struct HardwareConfig {
   pub model: Model,
@@ -0,0 +1,163 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use flate2::read::GzDecoder;
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+use super::cartridge::CartridgeError;
+use super::MAX_ROM_SIZE;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Extracts ROM data from a zip or gzip archive.
+///
+/// Archives are detected by their magic bytes, so any other data is returned as is
+pub fn extract_rom(data: Vec<u8>) -> Result<Vec<u8>, CartridgeError> {
+  if data.starts_with(GZIP_MAGIC) {
+    extract_gzip(&data)
+  } else if data.starts_with(ZIP_MAGIC) {
+    extract_zip(data)
+  } else {
+    Ok(data)
+  }
+}
+
+fn extract_gzip(data: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+  read_rom(GzDecoder::new(data))
+}
+
+fn extract_zip(data: Vec<u8>) -> Result<Vec<u8>, CartridgeError> {
+  let mut archive = ZipArchive::new(Cursor::new(data)).map_err(archive_error)?;
+  let candidates = archive
+    .file_names()
+    .filter(|name| is_rom_file_name(name))
+    .map(String::from)
+    .collect::<Vec<_>>();
+  match candidates.as_slice() {
+    [name] => {
+      let file = archive.by_name(name).map_err(archive_error)?;
+      read_rom(file)
+    }
+    [] => Err(CartridgeError::Archive {
+      msg: "no .gb or .gbc file found".to_string(),
+    }),
+    _ => Err(CartridgeError::Archive {
+      msg: format!("multiple ROM files found: {}", candidates.join(", ")),
+    }),
+  }
+}
+
+/// Reads decompressed ROM data, failing instead of decompressing more than MAX_ROM_SIZE bytes
+fn read_rom<R: Read>(reader: R) -> Result<Vec<u8>, CartridgeError> {
+  let mut rom = vec![];
+  reader.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+  if rom.len() > MAX_ROM_SIZE {
+    return Err(CartridgeError::Archive {
+      msg: format!("ROM is larger than {} bytes", MAX_ROM_SIZE),
+    });
+  }
+  Ok(rom)
+}
+
+/// Returns true for .gb and .gbc files, except macOS resource forks (__MACOSX/ and ._ files)
+fn is_rom_file_name(name: &str) -> bool {
+  let file_name = name.rsplit('/').next().unwrap_or(name);
+  if name.starts_with("__MACOSX/") || file_name.starts_with("._") {
+    return false;
+  }
+  let name = name.to_ascii_lowercase();
+  name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+fn archive_error(err: zip::result::ZipError) -> CartridgeError {
+  CartridgeError::Archive {
+    msg: err.to_string(),
+  }
+}
+
+#[cfg(test)]
+fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+  use std::io::Write;
+  use zip::write::{FileOptions, ZipWriter};
+  let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+  for &(name, data) in files {
+    writer.start_file(name, FileOptions::default()).unwrap();
+    writer.write_all(data).unwrap();
+  }
+  writer.finish().unwrap().into_inner()
+}
+
+#[cfg(test)]
+fn gzip(data: &[u8]) -> Vec<u8> {
+  use flate2::write::GzEncoder;
+  use flate2::Compression;
+  use std::io::Write;
+  let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+  encoder.write_all(data).unwrap();
+  encoder.finish().unwrap()
+}
+
+#[test]
+fn test_rom_file_name() {
+  assert!(is_rom_file_name("game.gb"));
+  assert!(is_rom_file_name("Game/GAME.GBC"));
+  assert!(!is_rom_file_name("game.gba"));
+  assert!(!is_rom_file_name("readme.txt"));
+  assert!(!is_rom_file_name("__MACOSX/game.gb"));
+  assert!(!is_rom_file_name("__MACOSX/Game/._game.gb"));
+  assert!(!is_rom_file_name("._game.gb"));
+  assert!(!is_rom_file_name("Game/._game.gbc"));
+}
+
+#[test]
+fn test_extract_rom() {
+  let rom = (0..0x8000).map(|i| i as u8).collect::<Vec<_>>();
+  assert_eq!(extract_rom(rom.clone()).unwrap(), rom);
+  assert_eq!(extract_rom(gzip(&rom)).unwrap(), rom);
+  let archive = zip(&[
+    ("readme.txt", b"readme"),
+    ("game.gb", &rom),
+    ("__MACOSX/._game.gb", b"resource fork"),
+  ]);
+  assert_eq!(extract_rom(archive).unwrap(), rom);
+
+  let archive = zip(&[("readme.txt", b"readme")]);
+  assert!(matches!(
+    extract_rom(archive),
+    Err(CartridgeError::Archive { .. })
+  ));
+  let archive = zip(&[("game.gb", &rom), ("game.gbc", &rom)]);
+  assert!(matches!(
+    extract_rom(archive),
+    Err(CartridgeError::Archive { .. })
+  ));
+}
+
+#[test]
+fn test_extract_rom_size_limit() {
+  let rom = vec![0; MAX_ROM_SIZE];
+  assert_eq!(extract_rom(gzip(&rom)).unwrap().len(), MAX_ROM_SIZE);
+  let rom = vec![0; MAX_ROM_SIZE + 1];
+  assert!(matches!(
+    extract_rom(gzip(&rom)),
+    Err(CartridgeError::Archive { .. })
+  ));
+  assert!(matches!(
+    extract_rom(zip(&[("game.gb", &rom)])),
+    Err(CartridgeError::Archive { .. })
+  ));
+}
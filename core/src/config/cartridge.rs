@@ -13,15 +13,19 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
-use crc::crc32;
+use log::{info, warn};
 use snafu::Snafu;
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::Arc;
 
+use super::archive::extract_rom;
+use super::header::{has_nintendo_logo, CartridgeHeader};
+use super::patch::{self, PatchError};
+use super::romdb::{self, RomInfo, RomQuirks};
 use crate::gameboy::ROM_BANK_SIZE;
 
 #[derive(Clone, Debug)]
@@ -31,6 +35,13 @@ pub struct Cartridge {
   pub cartridge_type: CartridgeType,
   pub rom_size: CartridgeRomSize,
   pub ram_size: CartridgeRamSize,
+  pub header: Option<CartridgeHeader>,
+  pub rom_info: Option<RomInfo>,
+  /// Path of the battery save file, if the cartridge was loaded from a file
+  pub save_path: Option<PathBuf>,
+  /// Path the ROM is written back to if the cartridge is emulated as a flash cart. Only MBC5
+  /// cartridges can be flash carts
+  pub flash_path: Option<PathBuf>,
 }
 
 #[derive(Debug, Snafu)]
@@ -39,6 +50,10 @@ pub enum CartridgeError {
   Io { source: io::Error },
   #[snafu(display("Invalid cartridge: {}", msg))]
   Validation { msg: String },
+  #[snafu(display("Invalid archive: {}", msg))]
+  Archive { msg: String },
+  #[snafu(display("Failed to apply patch: {}", source))]
+  Patch { source: PatchError },
 }
 
 impl From<io::Error> for CartridgeError {
@@ -47,6 +62,12 @@ impl From<io::Error> for CartridgeError {
   }
 }
 
+impl From<PatchError> for CartridgeError {
+  fn from(source: PatchError) -> CartridgeError {
+    CartridgeError::Patch { source }
+  }
+}
+
 impl Cartridge {
   pub fn no_cartridge() -> Cartridge {
     Cartridge {
@@ -58,27 +79,146 @@ impl Cartridge {
       },
       rom_size: CartridgeRomSize::NoRomBanks,
       ram_size: CartridgeRamSize::NoRam,
+      header: None,
+      rom_info: None,
+      save_path: None,
+      flash_path: None,
     }
   }
+  /// Loads a cartridge from a file.
+  ///
+  /// The file can also be a gzip file, or a zip archive containing a single .gb/.gbc file
   pub fn from_path(path: &Path) -> Result<Cartridge, CartridgeError> {
-    let mut file = File::open(path)?;
-    let mut data = vec![];
-    file.read_to_end(&mut data)?;
-    Cartridge::from_data(data.into())
+    let cartridge = Cartridge::from_data(read_file(path)?.into())?;
+    Ok(cartridge.with_save_path(path))
+  }
+  /// Loads a cartridge from a file, tolerating bad ROM dump sizes.
+  ///
+  /// A patch file next to the ROM (e.g. game.ips for game.gb) is applied automatically.
+  /// See `from_data_lenient`
+  pub fn from_path_lenient(path: &Path) -> Result<Cartridge, CartridgeError> {
+    let patch_path = patch::find_patch(path);
+    Cartridge::from_path_patched(path, patch_path.as_deref())
+  }
+  /// Loads a cartridge from a file like `from_path_lenient`, but applies the given patch file
+  /// instead of looking for one
+  pub fn from_path_patched(
+    path: &Path,
+    patch_path: Option<&Path>,
+  ) -> Result<Cartridge, CartridgeError> {
+    let mut data = read_file(path)?;
+    if let Some(patch_path) = patch_path {
+      info!("Applying patch \"{}\"", patch_path.display());
+      let patch = fs::read(patch_path)?;
+      data = patch::apply(&data, &patch)?;
+    }
+    let cartridge = Cartridge::from_data_lenient(data)?;
+    Ok(cartridge.with_save_path(path))
   }
+  fn with_save_path(self, rom_path: &Path) -> Cartridge {
+    Cartridge {
+      save_path: Some(rom_path.with_extension("sav")),
+      ..self
+    }
+  }
+  /// Loads a cartridge from ROM data, requiring the data length to exactly match the ROM size
+  /// in the header
   pub fn from_data(data: Arc<[u8]>) -> Result<Cartridge, CartridgeError> {
     if data.len() < 0x8000 || data.len() % 0x4000 != 0 {
       return Err(CartridgeError::Validation {
         msg: format!("Invalid length: {} bytes", data.len()),
       });
     }
-    let new_cartridge = data[0x14b] == 0x33;
+    let header_offset = header_offset(&data);
+    let rom_info = romdb::lookup(&data);
+    Cartridge::parse(data, header_offset, rom_info, true)
+  }
+  /// Loads a cartridge from ROM data, fixing up bad ROM dump sizes.
+  ///
+  /// Underdumps are padded with 0xff and overdumps are trimmed to the ROM size in the header.
+  pub fn from_data_lenient(mut data: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+    if data.len() < 0x150 {
+      return Err(CartridgeError::Validation {
+        msg: format!("Invalid length: {} bytes", data.len()),
+      });
+    }
+    let mut rom_info = romdb::lookup(&data);
+    let quirks = rom_info
+      .as_ref()
+      .map_or(RomQuirks::empty(), |info| info.quirks);
+    if unlicensed_mapper(&data, quirks).is_some() {
+      // Unlicensed carts often have a bogus ROM size in the header, so only pad to 32 KiB
+      if data.len() < 0x8000 {
+        data.resize(0x8000, 0xff);
+      }
+      let header_offset = header_offset(&data);
+      return Cartridge::parse(data.into(), header_offset, rom_info, false);
+    }
+    let header_offset = header_offset(&data);
+    if is_mmm01(&data) {
+      // The menu is mapped from the end of the ROM, so padding or trimming would move it away
+      // from the header that was detected
+      return Cartridge::parse(data.into(), header_offset, rom_info, false);
+    }
+    let rom_size = data[header_offset + 0x148];
+    let len_before = data.len();
+    let len = CartridgeRomSize::from_u8(rom_size)
+      .ok_or_else(|| CartridgeError::Validation {
+        msg: format!("Unsupported rom size {:02x}", rom_size),
+      })?
+      .as_usize();
+    if data.len() < len {
+      warn!(
+        "ROM is too small ({} bytes), padding to {} bytes",
+        data.len(),
+        len
+      );
+      data.resize(len, 0xff);
+    } else if data.len() > len {
+      warn!(
+        "ROM is too large ({} bytes), trimming to {} bytes",
+        data.len(),
+        len
+      );
+      data.truncate(len);
+    }
+    if data.len() != len_before {
+      // The fixed-up data can still match a known-good dump
+      rom_info = rom_info.or_else(|| romdb::lookup(&data));
+    }
+    Cartridge::parse(data.into(), header_offset, rom_info, false)
+  }
+  fn parse(
+    data: Arc<[u8]>,
+    header_offset: usize,
+    rom_info: Option<RomInfo>,
+    strict: bool,
+  ) -> Result<Cartridge, CartridgeError> {
+    // MMM01 multicarts boot to a menu at the end of the ROM, so the menu header is used
+    let header_data = &data[header_offset..];
+    let header = CartridgeHeader::from_data(header_data);
+    if !header.logo_valid {
+      warn!("Cartridge has an invalid Nintendo logo");
+    }
+    if !header.header_checksum.is_valid() {
+      warn!(
+        "Header checksum mismatch: stored {:02x}, computed {:02x}",
+        header.header_checksum.stored, header.header_checksum.computed
+      );
+    }
+    if !header.global_checksum.is_valid() {
+      warn!(
+        "Global checksum mismatch: stored {:04x}, computed {:04x}",
+        header.global_checksum.stored, header.global_checksum.computed
+      );
+    }
+    let new_cartridge = header_data[0x14b] == 0x33;
 
     let title = {
       let slice = if new_cartridge {
-        &data[0x134..0x13f]
+        &header_data[0x134..0x13f]
       } else {
-        &data[0x134..0x143]
+        &header_data[0x134..0x143]
       };
       let utf8 = str::from_utf8(slice).map_err(|_| CartridgeError::Validation {
         msg: "Invalid ROM title".to_string(),
@@ -87,20 +227,44 @@ impl Cartridge {
       utf8.trim_end_matches('\0').to_string()
     };
 
+    if let Some(ref info) = rom_info {
+      info!("Known ROM: {} ({})", info.name, info.region);
+    }
+    let quirks = rom_info
+      .as_ref()
+      .map_or(RomQuirks::empty(), |info| info.quirks);
+    if let Some(cartridge_type) = unlicensed_mapper(&data, quirks) {
+      info!("Detected unlicensed mapper: {:?}", cartridge_type);
+      // The header of unlicensed carts can't be trusted, so the ROM size is taken from the data
+      // and these mappers have no RAM
+      let rom_size = CartridgeRomSize::from_len(data.len()).unwrap_or(CartridgeRomSize::NoRomBanks);
+      return Ok(Cartridge {
+        data,
+        title,
+        cartridge_type,
+        rom_size,
+        ram_size: CartridgeRamSize::NoRam,
+        header: Some(header),
+        rom_info,
+        save_path: None,
+        flash_path: None,
+      });
+    }
+
     let mut cartridge_type =
-      CartridgeType::from_u8(data[0x147]).ok_or_else(|| CartridgeError::Validation {
-        msg: format!("Unsupported cartridge type {:02x}", data[0x147]),
+      CartridgeType::from_u8(header_data[0x147]).ok_or_else(|| CartridgeError::Validation {
+        msg: format!("Unsupported cartridge type {:02x}", header_data[0x147]),
       })?;
     if let CartridgeType::Mbc1 { multicart, .. } = &mut cartridge_type {
-      *multicart = is_mbc1_multicart(&data);
+      *multicart = quirks.contains(RomQuirks::MBC1_MULTICART) || is_mbc1_multicart(&data);
     }
     let rom_size =
-      CartridgeRomSize::from_u8(data[0x148]).ok_or_else(|| CartridgeError::Validation {
-        msg: format!("Unsupported rom size {:02x}", data[0x148]),
+      CartridgeRomSize::from_u8(header_data[0x148]).ok_or_else(|| CartridgeError::Validation {
+        msg: format!("Unsupported rom size {:02x}", header_data[0x148]),
       })?;
     let ram_size =
-      CartridgeRamSize::from_u8(data[0x149]).ok_or_else(|| CartridgeError::Validation {
-        msg: format!("Unsupported ram size {:02x}", data[0x149]),
+      CartridgeRamSize::from_u8(header_data[0x149]).ok_or_else(|| CartridgeError::Validation {
+        msg: format!("Unsupported ram size {:02x}", header_data[0x149]),
       })?;
 
     if cartridge_type.has_ram_chip() && ram_size == CartridgeRamSize::NoRam {
@@ -112,11 +276,11 @@ impl Cartridge {
       return Err(CartridgeError::Validation {
         msg: format!(
           "{:?} cartridge with ram size {:02x}",
-          cartridge_type, data[0x149]
+          cartridge_type, header_data[0x149]
         ),
       });
     }
-    if data.len() != rom_size.as_usize() {
+    if strict && data.len() != rom_size.as_usize() {
       return Err(CartridgeError::Validation {
         msg: format!(
           "Expected {} bytes of cartridge ROM, got {:?}",
@@ -132,6 +296,10 @@ impl Cartridge {
       cartridge_type,
       rom_size,
       ram_size,
+      header: Some(header),
+      rom_info,
+      save_path: None,
+      flash_path: None,
     })
   }
 }
@@ -162,8 +330,19 @@ pub enum CartridgeType {
   },
   Mbc6,
   Mbc7,
+  Mmm01 {
+    ram: bool,
+    battery: bool,
+  },
+  PocketCamera,
+  Tama5,
   Huc1,
   Huc3,
+  WisdomTree,
+  Sachen {
+    mmc2: bool,
+  },
+  RocketGames,
 }
 
 impl CartridgeType {
@@ -254,13 +433,40 @@ impl CartridgeType {
         battery: true,
         rumble: true,
       }),
+      0x0b => Some(Mmm01 {
+        ram: false,
+        battery: false,
+      }),
+      0x0c => Some(Mmm01 {
+        ram: true,
+        battery: false,
+      }),
+      0x0d => Some(Mmm01 {
+        ram: true,
+        battery: true,
+      }),
       0x20 => Some(Mbc6),
       0x22 => Some(Mbc7),
+      0xfc => Some(PocketCamera),
+      0xfd => Some(Tama5),
       0xff => Some(Huc1),
       0xfe => Some(Huc3),
       _ => None,
     }
   }
+  pub fn has_battery(&self) -> bool {
+    use self::CartridgeType::*;
+    match *self {
+      NoMbc { battery, .. } => battery,
+      Mbc1 { battery, .. } => battery,
+      Mbc2 { battery } => battery,
+      Mbc3 { battery, .. } => battery,
+      Mbc5 { battery, .. } => battery,
+      Mmm01 { battery, .. } => battery,
+      Mbc6 | Mbc7 | PocketCamera | Tama5 | Huc1 | Huc3 => true,
+      WisdomTree | Sachen { .. } | RocketGames => false,
+    }
+  }
   fn has_ram_chip(&self) -> bool {
     use self::CartridgeType::*;
     match *self {
@@ -269,7 +475,11 @@ impl CartridgeType {
       Mbc2 { .. } => false, // MBC2 has internal RAM and doesn't use a RAM chip
       Mbc3 { ram, .. } => ram,
       Mbc5 { ram, .. } => ram,
-      Mbc6 | Mbc7 | Huc1 | Huc3 => true,
+      Mmm01 { ram, .. } => ram,
+      Mbc7 => false,  // MBC7 uses a serial EEPROM instead of a RAM chip
+      Tama5 => false, // TAMA5 RAM is inside the TAMA6 microcontroller
+      Mbc6 | PocketCamera | Huc1 | Huc3 => true,
+      WisdomTree | Sachen { .. } | RocketGames => false,
     }
   }
 }
@@ -285,6 +495,9 @@ pub enum CartridgeRomSize {
   RomBanks128 = 0x06,
   RomBanks256 = 0x07,
   RomBanks512 = 0x08,
+  RomBanks72 = 0x52,
+  RomBanks80 = 0x53,
+  RomBanks96 = 0x54,
 }
 
 impl fmt::Debug for CartridgeRomSize {
@@ -303,6 +516,9 @@ impl fmt::Debug for CartridgeRomSize {
         RomBanks128 => "16 Mbit",
         RomBanks256 => "32 Mbit",
         RomBanks512 => "64 Mbit",
+        RomBanks72 => "9 Mbit",
+        RomBanks80 => "10 Mbit",
+        RomBanks96 => "12 Mbit",
       }
     )
   }
@@ -321,9 +537,18 @@ impl CartridgeRomSize {
       0x06 => Some(RomBanks128),
       0x07 => Some(RomBanks256),
       0x08 => Some(RomBanks512),
+      0x52 => Some(RomBanks72),
+      0x53 => Some(RomBanks80),
+      0x54 => Some(RomBanks96),
       _ => None,
     }
   }
+  fn from_len(len: usize) -> Option<CartridgeRomSize> {
+    (0x00..=0x08)
+      .chain(0x52..=0x54)
+      .filter_map(CartridgeRomSize::from_u8)
+      .find(|size| size.as_usize() == len)
+  }
   pub fn banks(&self) -> usize {
     use self::CartridgeRomSize::*;
     match *self {
@@ -336,6 +561,9 @@ impl CartridgeRomSize {
       RomBanks128 => 128,
       RomBanks256 => 256,
       RomBanks512 => 512,
+      RomBanks72 => 72,
+      RomBanks80 => 80,
+      RomBanks96 => 96,
     }
   }
   pub fn as_usize(&self) -> usize {
@@ -397,6 +625,79 @@ impl CartridgeRamSize {
   }
 }
 
+fn read_file(path: &Path) -> Result<Vec<u8>, CartridgeError> {
+  let mut file = File::open(path)?;
+  let mut data = vec![];
+  file.read_to_end(&mut data)?;
+  extract_rom(data)
+}
+
+/// Returns the offset of the cartridge header used for loading.
+///
+/// MMM01 multicarts map the last 32 KiB of the ROM at boot, so the menu header is at the end of
+/// the ROM, and the header at the start belongs to one of the games
+///
+/// Sachen carts store the real logo and header at $0184-$01CF, which the mapper shows at
+/// $0104-$014F during boot
+fn header_offset(rom: &[u8]) -> usize {
+  if is_mmm01(rom) {
+    rom.len() - 0x8000
+  } else if is_sachen(rom) {
+    0x80
+  } else {
+    0
+  }
+}
+
+/// Detects unlicensed mappers that have no cartridge type of their own, by header heuristics or
+/// by ROM database quirks
+fn unlicensed_mapper(rom: &[u8], quirks: RomQuirks) -> Option<CartridgeType> {
+  if quirks.contains(RomQuirks::WISDOM_TREE) {
+    Some(CartridgeType::WisdomTree)
+  } else if quirks.contains(RomQuirks::SACHEN_MMC1) {
+    Some(CartridgeType::Sachen { mmc2: false })
+  } else if quirks.contains(RomQuirks::SACHEN_MMC2) {
+    Some(CartridgeType::Sachen { mmc2: true })
+  } else if quirks.contains(RomQuirks::ROCKET_GAMES) {
+    Some(CartridgeType::RocketGames)
+  } else if is_sachen(rom) {
+    // MMC2 carts are Game Boy Color compatible
+    let mmc2 = rom[0x80 + 0x143] & 0x80 != 0;
+    Some(CartridgeType::Sachen { mmc2 })
+  } else if is_wisdom_tree(rom) {
+    Some(CartridgeType::WisdomTree)
+  } else {
+    None
+  }
+}
+
+fn is_sachen(rom: &[u8]) -> bool {
+  rom.len() >= 0x8000 && !has_nintendo_logo(rom, 0) && has_nintendo_logo(rom, 0x80)
+}
+
+fn is_wisdom_tree(rom: &[u8]) -> bool {
+  // Wisdom Tree carts claim to have no MBC, but are larger than 32 KiB and credit the publisher
+  // in the first bank
+  if rom.len() <= 0x8000 || rom[0x147] != 0x00 {
+    return false;
+  }
+  let bank0 = &rom[..0x4000];
+  [&b"WISDOM TREE"[..], &b"WISDOM\0TREE"[..]]
+    .iter()
+    .any(|needle| bank0.windows(needle.len()).any(|window| window == *needle))
+}
+
+fn is_mmm01(rom: &[u8]) -> bool {
+  if rom.len() < 0x10000 {
+    return false;
+  }
+  let offset = rom.len() - 0x8000;
+  match rom[offset + 0x147] {
+    0x0b..=0x0d => has_nintendo_logo(rom, offset),
+    _ => false,
+  }
+}
+
 fn is_mbc1_multicart(rom: &[u8]) -> bool {
   // Only 8 Mbit MBC1 multicarts exist. Since it's not clear how other ROM sizes would be wired,
   // it's pointless to try to support them
@@ -405,15 +706,80 @@ fn is_mbc1_multicart(rom: &[u8]) -> bool {
   }
 
   let nintendo_logo_count = (0..4)
-    .map(|page| {
-      let start = page * 0x40000 + 0x0104;
-      let end = start + 0x30;
-
-      crc32::checksum_ieee(&rom[start..end])
-    })
-    .filter(|&checksum| checksum == 0x4619_5417)
+    .filter(|&page| has_nintendo_logo(rom, page * 0x40000))
     .count();
 
   // A multicart should have at least two games + a menu with valid logo data
   nintendo_logo_count >= 3
 }
+
+#[test]
+fn test_unlicensed_detection() {
+  use super::header::NINTENDO_LOGO;
+  // Sachen carts have a broken logo at $0104 and the real one at $0184
+  let mut rom = vec![0; 0x10000];
+  rom[0x0184..0x01b4].copy_from_slice(&NINTENDO_LOGO);
+  rom[0x01b4..0x01b8].copy_from_slice(b"SACH");
+  rom[0x01c3] = 0x80;
+  assert_eq!(header_offset(&rom), 0x80);
+  let cartridge = Cartridge::from_data_lenient(rom).unwrap();
+  assert_eq!(
+    cartridge.cartridge_type,
+    CartridgeType::Sachen { mmc2: true }
+  );
+  assert_eq!(cartridge.title, "SACH");
+
+  let mut rom = vec![0; 0x10000];
+  rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+  rom[0x0184..0x01b4].copy_from_slice(&NINTENDO_LOGO);
+  assert_eq!(header_offset(&rom), 0);
+  assert_eq!(unlicensed_mapper(&rom, RomQuirks::empty()), None);
+
+  // Wisdom Tree carts claim to have no MBC
+  rom[0x2000..0x200b].copy_from_slice(b"WISDOM TREE");
+  assert_eq!(
+    unlicensed_mapper(&rom, RomQuirks::empty()),
+    Some(CartridgeType::WisdomTree)
+  );
+  rom[0x0147] = 0x01;
+  assert_eq!(unlicensed_mapper(&rom, RomQuirks::empty()), None);
+  assert_eq!(
+    unlicensed_mapper(&rom, RomQuirks::ROCKET_GAMES),
+    Some(CartridgeType::RocketGames)
+  );
+}
+
+#[test]
+fn test_mmm01_detection() {
+  use super::header::NINTENDO_LOGO;
+  // The menu header is in the last 32 KiB, while the header at the start belongs to a game
+  let mut rom = vec![0; 0x30000];
+  let menu = rom.len() - 0x8000;
+  rom[menu + 0x0104..menu + 0x0134].copy_from_slice(&NINTENDO_LOGO);
+  rom[menu + 0x0134..menu + 0x0138].copy_from_slice(b"MENU");
+  rom[menu + 0x0147] = 0x0b;
+  rom[menu + 0x0148] = 0x02;
+  assert!(is_mmm01(&rom));
+  assert_eq!(header_offset(&rom), menu);
+  // Padding or trimming would move the menu away from the end of the ROM
+  let cartridge = Cartridge::from_data_lenient(rom.clone()).unwrap();
+  assert_eq!(
+    cartridge.cartridge_type,
+    CartridgeType::Mmm01 {
+      ram: false,
+      battery: false
+    }
+  );
+  assert_eq!(cartridge.title, "MENU");
+  assert_eq!(cartridge.data.len(), 0x30000);
+
+  let mut broken = rom.clone();
+  broken[menu + 0x0104] = 0x00;
+  assert!(!is_mmm01(&broken));
+  assert_eq!(header_offset(&broken), 0);
+  let mut broken = rom.clone();
+  broken[menu + 0x0147] = 0x01;
+  assert!(!is_mmm01(&broken));
+  // A 32 KiB ROM is all menu, so there's nothing to detect
+  assert!(!is_mmm01(&rom[menu..]));
+}
@@ -0,0 +1,273 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crc::crc32;
+use std::fmt;
+
+/// CRC32 of the Nintendo logo at $0104-$0133
+const NINTENDO_LOGO_CRC32: u32 = 0x4619_5417;
+
+#[cfg(test)]
+pub const NINTENDO_LOGO: [u8; 0x30] = [
+  0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+  0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+  0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+/// Parsed cartridge header ($0100-$014F)
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+  pub logo_valid: bool,
+  pub cgb_flag: CgbFlag,
+  pub sgb_flag: bool,
+  pub licensee: Licensee,
+  pub destination: Destination,
+  pub mask_rom_version: u8,
+  pub header_checksum: Checksum<u8>,
+  pub global_checksum: Checksum<u16>,
+}
+
+impl CartridgeHeader {
+  /// Parses the header of a ROM image.
+  ///
+  /// The data must contain at least the header area
+  pub fn from_data(data: &[u8]) -> CartridgeHeader {
+    let licensee = match data[0x14b] {
+      0x33 => Licensee::New([data[0x144], data[0x145]]),
+      code => Licensee::Old(code),
+    };
+    CartridgeHeader {
+      logo_valid: has_nintendo_logo(data, 0x0000),
+      cgb_flag: CgbFlag::from_u8(data[0x143]),
+      // SGB functions are only enabled if the old licensee code is 0x33
+      sgb_flag: data[0x146] == 0x03 && data[0x14b] == 0x33,
+      licensee,
+      destination: Destination::from_u8(data[0x14a]),
+      mask_rom_version: data[0x14c],
+      header_checksum: Checksum {
+        stored: data[0x14d],
+        computed: header_checksum(data),
+      },
+      global_checksum: Checksum {
+        stored: u16::from_be_bytes([data[0x14e], data[0x14f]]),
+        computed: global_checksum(data),
+      },
+    }
+  }
+  /// Returns true if the Nintendo logo is valid and the header checksum matches.
+  ///
+  /// The boot ROM refuses to start a cartridge that fails either check, so a mismatch usually
+  /// means a bad dump
+  pub fn is_valid(&self) -> bool {
+    self.logo_valid && self.header_checksum.is_valid()
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CgbFlag {
+  DmgOnly,
+  CgbSupported,
+  CgbOnly,
+}
+
+impl CgbFlag {
+  fn from_u8(value: u8) -> CgbFlag {
+    match value {
+      0x80 => CgbFlag::CgbSupported,
+      0xc0 => CgbFlag::CgbOnly,
+      _ => CgbFlag::DmgOnly,
+    }
+  }
+}
+
+impl fmt::Display for CgbFlag {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      CgbFlag::DmgOnly => write!(f, "DMG"),
+      CgbFlag::CgbSupported => write!(f, "DMG/CGB"),
+      CgbFlag::CgbOnly => write!(f, "CGB only"),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Licensee {
+  /// Old licensee code at $014B
+  Old(u8),
+  /// New two-character licensee code at $0144-$0145, used if the old code is 0x33
+  New([u8; 2]),
+}
+
+impl fmt::Display for Licensee {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Licensee::Old(code) => write!(f, "{:02X}", code),
+      Licensee::New(code) => write!(
+        f,
+        "{}{}",
+        char::from(code[0]).escape_default(),
+        char::from(code[1]).escape_default()
+      ),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+  Japan,
+  Overseas,
+  Unknown(u8),
+}
+
+impl Destination {
+  fn from_u8(value: u8) -> Destination {
+    match value {
+      0x00 => Destination::Japan,
+      0x01 => Destination::Overseas,
+      _ => Destination::Unknown(value),
+    }
+  }
+}
+
+impl fmt::Display for Destination {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Destination::Japan => write!(f, "Japan"),
+      Destination::Overseas => write!(f, "Overseas"),
+      Destination::Unknown(value) => write!(f, "Unknown ({:02x})", value),
+    }
+  }
+}
+
+/// A checksum stored in the header, and the value computed from the ROM data
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Checksum<T> {
+  pub stored: T,
+  pub computed: T,
+}
+
+impl<T: PartialEq> Checksum<T> {
+  pub fn is_valid(&self) -> bool {
+    self.stored == self.computed
+  }
+}
+
+/// Returns true if the ROM data has a valid Nintendo logo in the header at the given offset
+pub fn has_nintendo_logo(rom: &[u8], offset: usize) -> bool {
+  let start = offset + 0x0104;
+  let end = start + 0x30;
+  crc32::checksum_ieee(&rom[start..end]) == NINTENDO_LOGO_CRC32
+}
+
+/// Header checksum over $0134-$014C, verified by the boot ROM
+fn header_checksum(rom: &[u8]) -> u8 {
+  rom[0x134..=0x14c]
+    .iter()
+    .fold(0u8, |acc, &value| acc.wrapping_sub(value).wrapping_sub(1))
+}
+
+/// Global checksum: 16-bit sum of all ROM bytes except the checksum itself
+fn global_checksum(rom: &[u8]) -> u16 {
+  rom
+    .iter()
+    .enumerate()
+    .filter(|&(addr, _)| addr != 0x14e && addr != 0x14f)
+    .fold(0u16, |acc, (_, &value)| acc.wrapping_add(value as u16))
+}
+
+/// Returns a 32 KiB ROM with a valid logo and checksums
+#[cfg(test)]
+fn test_rom() -> Vec<u8> {
+  let mut rom = vec![0; 0x8000];
+  rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+  rom[0x134..0x138].copy_from_slice(b"TEST");
+  rom[0x14d] = header_checksum(&rom);
+  let global = global_checksum(&rom).to_be_bytes();
+  rom[0x14e..0x150].copy_from_slice(&global);
+  rom
+}
+
+#[test]
+fn test_nintendo_logo() {
+  let mut rom = test_rom();
+  assert!(has_nintendo_logo(&rom, 0));
+  assert!(CartridgeHeader::from_data(&rom).is_valid());
+  rom[0x120] ^= 0x01;
+  assert!(!has_nintendo_logo(&rom, 0));
+  let header = CartridgeHeader::from_data(&rom);
+  assert!(!header.logo_valid);
+  assert!(!header.is_valid());
+
+  let mut rom = vec![0; 0x8000];
+  rom[0x184..0x1b4].copy_from_slice(&NINTENDO_LOGO);
+  assert!(!has_nintendo_logo(&rom, 0));
+  assert!(has_nintendo_logo(&rom, 0x80));
+}
+
+#[test]
+fn test_header_checksum() {
+  assert_eq!(header_checksum(&[0; 0x150]), 0xe7);
+  let mut rom = test_rom();
+  assert!(CartridgeHeader::from_data(&rom).header_checksum.is_valid());
+  rom[0x14c] = 0x01;
+  let header = CartridgeHeader::from_data(&rom);
+  assert_eq!(header.header_checksum.computed, rom[0x14d].wrapping_sub(1));
+  assert!(!header.header_checksum.is_valid());
+  assert!(!header.is_valid());
+}
+
+#[test]
+fn test_global_checksum() {
+  let mut rom = vec![0; 0x8000];
+  rom[0x0000] = 0xff;
+  rom[0x7fff] = 0x02;
+  assert_eq!(global_checksum(&rom), 0x0101);
+  // The checksum bytes themselves are excluded
+  rom[0x14e] = 0x12;
+  rom[0x14f] = 0x34;
+  assert_eq!(global_checksum(&rom), 0x0101);
+  let header = CartridgeHeader::from_data(&rom);
+  assert_eq!(header.global_checksum.stored, 0x1234);
+  assert!(!header.global_checksum.is_valid());
+
+  let mut rom = test_rom();
+  assert!(CartridgeHeader::from_data(&rom).global_checksum.is_valid());
+  rom[0x4000] = 0x01;
+  assert!(!CartridgeHeader::from_data(&rom).global_checksum.is_valid());
+  // A bad global checksum doesn't stop the boot ROM
+  assert!(CartridgeHeader::from_data(&rom).is_valid());
+}
+
+#[test]
+fn test_licensee() {
+  let mut rom = test_rom();
+  rom[0x14b] = 0x01;
+  rom[0x146] = 0x03;
+  let header = CartridgeHeader::from_data(&rom);
+  assert_eq!(header.licensee, Licensee::Old(0x01));
+  assert_eq!(header.licensee.to_string(), "01");
+  assert!(!header.sgb_flag);
+
+  rom[0x14b] = 0x33;
+  rom[0x144..0x146].copy_from_slice(b"A4");
+  let header = CartridgeHeader::from_data(&rom);
+  assert_eq!(header.licensee, Licensee::New(*b"A4"));
+  assert_eq!(header.licensee.to_string(), "A4");
+  assert!(header.sgb_flag);
+
+  rom[0x144] = 0x00;
+  let header = CartridgeHeader::from_data(&rom);
+  assert_eq!(header.licensee.to_string(), "\\u{0}4");
+}
@@ -0,0 +1,473 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+//! IPS, UPS and BPS ROM patches
+use crc::crc32;
+use snafu::Snafu;
+use std::path::{Path, PathBuf};
+
+use super::MAX_ROM_SIZE;
+
+#[derive(Debug, Snafu)]
+pub enum PatchError {
+  #[snafu(display("Unknown patch format"))]
+  UnknownFormat,
+  #[snafu(display("Invalid patch: {}", msg))]
+  Invalid { msg: String },
+  #[snafu(display(
+    "{} checksum mismatch: expected {:08x}, got {:08x}",
+    what,
+    expected,
+    actual
+  ))]
+  Checksum {
+    what: &'static str,
+    expected: u32,
+    actual: u32,
+  },
+}
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// Patch file extensions, in the order they are looked up
+const PATCH_EXTENSIONS: &[&str] = &["ips", "ups", "bps"];
+
+/// Applies a patch to ROM data. The patch format is detected from the patch header
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+  if patch.starts_with(IPS_MAGIC) {
+    apply_ips(rom, patch)
+  } else if patch.starts_with(UPS_MAGIC) {
+    apply_ups(rom, patch)
+  } else if patch.starts_with(BPS_MAGIC) {
+    apply_bps(rom, patch)
+  } else {
+    Err(PatchError::UnknownFormat)
+  }
+}
+
+/// Finds a patch file next to a ROM file with the same name, e.g. game.ips for game.gb
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+  PATCH_EXTENSIONS
+    .iter()
+    .map(|extension| rom_path.with_extension(extension))
+    .find(|path| path.is_file())
+}
+
+struct PatchReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+  fn new(data: &'a [u8], pos: usize) -> PatchReader<'a> {
+    PatchReader { data, pos }
+  }
+  fn remaining(&self) -> usize {
+    self.data.len() - self.pos
+  }
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+    if self.remaining() < len {
+      return Err(PatchError::Invalid {
+        msg: "unexpected end of patch".to_string(),
+      });
+    }
+    let bytes = &self.data[self.pos..self.pos + len];
+    self.pos += len;
+    Ok(bytes)
+  }
+  fn u8(&mut self) -> Result<u8, PatchError> {
+    Ok(self.bytes(1)?[0])
+  }
+  fn u16_be(&mut self) -> Result<usize, PatchError> {
+    let bytes = self.bytes(2)?;
+    Ok((bytes[0] as usize) << 8 | bytes[1] as usize)
+  }
+  fn u24_be(&mut self) -> Result<usize, PatchError> {
+    let bytes = self.bytes(3)?;
+    Ok((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+  }
+  /// Variable-length integer used by UPS and BPS
+  fn varint(&mut self) -> Result<usize, PatchError> {
+    let overflow = || PatchError::Invalid {
+      msg: "integer overflow".to_string(),
+    };
+    let mut value = 0usize;
+    let mut shift = 1usize;
+    loop {
+      let byte = self.u8()?;
+      value = (byte as usize & 0x7f)
+        .checked_mul(shift)
+        .and_then(|x| value.checked_add(x))
+        .ok_or_else(overflow)?;
+      if byte & 0x80 != 0 {
+        return Ok(value);
+      }
+      shift = shift.checked_mul(0x80).ok_or_else(overflow)?;
+      value = value.checked_add(shift).ok_or_else(overflow)?;
+    }
+  }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+  let mut output = rom.to_vec();
+  let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+  loop {
+    let offset_bytes = reader.bytes(3)?;
+    if offset_bytes == IPS_EOF {
+      break;
+    }
+    let offset =
+      (offset_bytes[0] as usize) << 16 | (offset_bytes[1] as usize) << 8 | offset_bytes[2] as usize;
+    let len = reader.u16_be()?;
+    if len == 0 {
+      // RLE record
+      let len = reader.u16_be()?;
+      let value = reader.u8()?;
+      write_at(&mut output, offset, &vec![value; len]);
+    } else {
+      write_at(&mut output, offset, reader.bytes(len)?);
+    }
+  }
+  // Optional truncation extension
+  if reader.remaining() >= 3 {
+    output.truncate(reader.u24_be()?);
+  }
+  Ok(output)
+}
+
+fn write_at(output: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+  let end = offset + bytes.len();
+  if output.len() < end {
+    output.resize(end, 0x00);
+  }
+  output[offset..end].copy_from_slice(bytes);
+}
+
+/// Reads the UPS/BPS footer and verifies the source and patch checksums.
+///
+/// Returns the expected target checksum
+fn verify_footer(rom: &[u8], patch: &[u8]) -> Result<u32, PatchError> {
+  if patch.len() < 16 {
+    return Err(PatchError::Invalid {
+      msg: "patch is too short".to_string(),
+    });
+  }
+  let footer = &patch[patch.len() - 12..];
+  let read_u32 = |offset: usize| {
+    u32::from_le_bytes([
+      footer[offset],
+      footer[offset + 1],
+      footer[offset + 2],
+      footer[offset + 3],
+    ])
+  };
+  verify_checksum(
+    "Patch",
+    read_u32(8),
+    crc32::checksum_ieee(&patch[..patch.len() - 4]),
+  )?;
+  verify_checksum("Source ROM", read_u32(0), crc32::checksum_ieee(rom))?;
+  Ok(read_u32(4))
+}
+
+fn check_target_size(target_size: usize) -> Result<(), PatchError> {
+  if target_size > MAX_ROM_SIZE {
+    return Err(PatchError::Invalid {
+      msg: format!("target ROM is too large ({} bytes)", target_size),
+    });
+  }
+  Ok(())
+}
+
+fn verify_checksum(what: &'static str, expected: u32, actual: u32) -> Result<(), PatchError> {
+  if expected == actual {
+    Ok(())
+  } else {
+    Err(PatchError::Checksum {
+      what,
+      expected,
+      actual,
+    })
+  }
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+  let target_checksum = verify_footer(rom, patch)?;
+  let mut reader = PatchReader::new(&patch[..patch.len() - 12], UPS_MAGIC.len());
+  let source_size = reader.varint()?;
+  let target_size = reader.varint()?;
+  check_target_size(target_size)?;
+  if source_size != rom.len() {
+    return Err(PatchError::Invalid {
+      msg: format!(
+        "expected a {} byte source ROM, got {} bytes",
+        source_size,
+        rom.len()
+      ),
+    });
+  }
+  let mut output = rom.to_vec();
+  output.resize(target_size, 0x00);
+  let mut offset = 0usize;
+  while reader.remaining() > 0 {
+    offset = offset
+      .checked_add(reader.varint()?)
+      .ok_or_else(|| PatchError::Invalid {
+        msg: "integer overflow".to_string(),
+      })?;
+    loop {
+      let value = reader.u8()?;
+      if offset < output.len() {
+        output[offset] ^= value;
+      }
+      offset = offset.saturating_add(1);
+      if value == 0 {
+        break;
+      }
+    }
+  }
+  verify_checksum("Target ROM", target_checksum, crc32::checksum_ieee(&output))?;
+  Ok(output)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+  let target_checksum = verify_footer(rom, patch)?;
+  let mut reader = PatchReader::new(&patch[..patch.len() - 12], BPS_MAGIC.len());
+  let source_size = reader.varint()?;
+  let target_size = reader.varint()?;
+  let metadata_size = reader.varint()?;
+  check_target_size(target_size)?;
+  reader.bytes(metadata_size)?;
+  if source_size != rom.len() {
+    return Err(PatchError::Invalid {
+      msg: format!(
+        "expected a {} byte source ROM, got {} bytes",
+        source_size,
+        rom.len()
+      ),
+    });
+  }
+  let invalid = || PatchError::Invalid {
+    msg: "copy out of bounds".to_string(),
+  };
+  let mut output = Vec::with_capacity(target_size);
+  let mut source_offset = 0isize;
+  let mut target_offset = 0isize;
+  while reader.remaining() > 0 {
+    let action = reader.varint()?;
+    let len = (action >> 2) + 1;
+    if len > target_size - output.len() {
+      return Err(PatchError::Invalid {
+        msg: "target ROM is larger than expected".to_string(),
+      });
+    }
+    match action & 0b11 {
+      // SourceRead
+      0 => {
+        let start = output.len();
+        let bytes = rom.get(start..start + len).ok_or_else(invalid)?;
+        output.extend_from_slice(bytes);
+      }
+      // TargetRead
+      1 => output.extend_from_slice(reader.bytes(len)?),
+      // SourceCopy
+      2 => {
+        source_offset = source_offset
+          .checked_add(relative_offset(reader.varint()?))
+          .ok_or_else(invalid)?;
+        let bytes = rom
+          .get(source_offset as usize..)
+          .and_then(|bytes| bytes.get(..len))
+          .ok_or_else(invalid)?;
+        output.extend_from_slice(bytes);
+        source_offset += len as isize;
+      }
+      // TargetCopy
+      _ => {
+        target_offset = target_offset
+          .checked_add(relative_offset(reader.varint()?))
+          .ok_or_else(invalid)?;
+        // The source and target ranges can overlap, so bytes are copied one by one
+        for _ in 0..len {
+          let value = *output.get(target_offset as usize).ok_or_else(invalid)?;
+          output.push(value);
+          target_offset += 1;
+        }
+      }
+    }
+  }
+  if output.len() != target_size {
+    return Err(PatchError::Invalid {
+      msg: format!(
+        "expected a {} byte target ROM, got {} bytes",
+        target_size,
+        output.len()
+      ),
+    });
+  }
+  verify_checksum("Target ROM", target_checksum, crc32::checksum_ieee(&output))?;
+  Ok(output)
+}
+
+fn relative_offset(value: usize) -> isize {
+  let offset = (value >> 1) as isize;
+  if value & 1 != 0 {
+    -offset
+  } else {
+    offset
+  }
+}
+
+#[cfg(test)]
+fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+  patch.extend_from_slice(&crc32::checksum_ieee(source).to_le_bytes());
+  patch.extend_from_slice(&crc32::checksum_ieee(target).to_le_bytes());
+  let checksum = crc32::checksum_ieee(&patch);
+  patch.extend_from_slice(&checksum.to_le_bytes());
+  patch
+}
+
+#[test]
+fn test_ips() {
+  let rom = [0u8; 8];
+  let mut patch = b"PATCH".to_vec();
+  patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xaa, 0xbb]);
+  patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xcc]);
+  patch.extend_from_slice(b"EOF");
+  assert_eq!(
+    apply(&rom, &patch).unwrap(),
+    [0x00, 0xaa, 0xbb, 0x00, 0x00, 0x00, 0xcc, 0xcc, 0xcc, 0xcc]
+  );
+}
+
+#[test]
+fn test_ups() {
+  let rom = [0x01, 0x02, 0x03, 0x04];
+  let target = [0x01, 0x12, 0x03, 0x04, 0x05];
+  let mut patch = b"UPS1".to_vec();
+  // Source size 4, target size 5
+  patch.extend_from_slice(&[0x84, 0x85]);
+  // Skip 1 byte, XOR 0x10, then skip 1 byte and XOR 0x05 into the new byte
+  patch.extend_from_slice(&[0x81, 0x10, 0x00, 0x81, 0x05, 0x00]);
+  let patch = with_footer(patch, &rom, &target);
+  assert_eq!(apply(&rom, &patch).unwrap(), target);
+}
+
+#[test]
+fn test_bps() {
+  let rom = [0x01, 0x02, 0x03, 0x04];
+  let target = [0x01, 0x02, 0xaa, 0xaa, 0xaa, 0x01, 0x02];
+  let mut patch = b"BPS1".to_vec();
+  // Source size 4, target size 7, no metadata
+  patch.extend_from_slice(&[0x84, 0x87, 0x80]);
+  // SourceRead 2, TargetRead 1, TargetCopy 2 from offset 2, SourceCopy 2 from offset 0
+  patch.extend_from_slice(&[0x84, 0x81, 0xaa, 0x87, 0x84, 0x86, 0x80]);
+  let patch = with_footer(patch, &rom, &target);
+  assert_eq!(apply(&rom, &patch).unwrap(), target);
+}
+
+#[test]
+fn test_bps_bad_source_checksum() {
+  let rom = [0x01, 0x02, 0x03, 0x04];
+  let mut patch = b"BPS1".to_vec();
+  patch.extend_from_slice(&[0x84, 0x84, 0x80, 0x8c]);
+  let patch = with_footer(patch, &[0x00; 4], &rom);
+  assert!(matches!(
+    apply(&rom, &patch),
+    Err(PatchError::Checksum {
+      what: "Source ROM",
+      ..
+    })
+  ));
+}
+
+#[cfg(test)]
+fn encode_varint(mut value: usize) -> Vec<u8> {
+  let mut bytes = Vec::new();
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if value == 0 {
+      bytes.push(0x80 | byte);
+      return bytes;
+    }
+    bytes.push(byte);
+    value -= 1;
+  }
+}
+
+#[test]
+fn test_varint() {
+  for &value in &[0, 0x7f, 0x80, 0x4000, 0x12_3456, MAX_ROM_SIZE] {
+    let bytes = encode_varint(value);
+    assert_eq!(PatchReader::new(&bytes, 0).varint().unwrap(), value);
+  }
+  let bytes = encode_varint(usize::MAX);
+  assert_eq!(PatchReader::new(&bytes, 0).varint().unwrap(), usize::MAX);
+  // Too many continuation bytes overflow instead of panicking
+  assert!(matches!(
+    PatchReader::new(&[0x00; 16], 0).varint(),
+    Err(PatchError::Invalid { .. })
+  ));
+  assert!(matches!(
+    PatchReader::new(&[0x7f; 10], 0).varint(),
+    Err(PatchError::Invalid { .. })
+  ));
+}
+
+#[test]
+fn test_target_too_large() {
+  let rom = [0x01, 0x02, 0x03, 0x04];
+  for &magic in &[UPS_MAGIC, BPS_MAGIC] {
+    let mut patch = magic.to_vec();
+    patch.extend_from_slice(&encode_varint(rom.len()));
+    patch.extend_from_slice(&encode_varint(MAX_ROM_SIZE + 1));
+    patch.extend_from_slice(&[0x80; 4]);
+    let patch = with_footer(patch, &rom, &rom);
+    assert!(matches!(
+      apply(&rom, &patch),
+      Err(PatchError::Invalid { .. })
+    ));
+  }
+}
+
+#[test]
+fn test_bps_out_of_bounds() {
+  let rom = [0x01, 0x02, 0x03, 0x04];
+  let bps = |actions: &[u8]| {
+    let mut patch = b"BPS1".to_vec();
+    // Source size 4, target size 4, no metadata
+    patch.extend_from_slice(&[0x84, 0x84, 0x80]);
+    patch.extend_from_slice(actions);
+    apply(&rom, &with_footer(patch, &rom, &rom))
+  };
+  assert_eq!(bps(&[0x8c]).unwrap(), rom);
+  // TargetCopy longer than the target
+  let mut actions = encode_varint((usize::MAX >> 2) << 2 | 0b11);
+  actions.push(0x80);
+  assert!(matches!(bps(&actions), Err(PatchError::Invalid { .. })));
+  // SourceCopy from a negative offset
+  assert!(matches!(
+    bps(&[0x82, 0x83]),
+    Err(PatchError::Invalid { .. })
+  ));
+  // SourceCopy from a huge offset
+  let mut actions = vec![0x82];
+  actions.extend_from_slice(&encode_varint(usize::MAX - 1));
+  assert!(matches!(bps(&actions), Err(PatchError::Invalid { .. })));
+}
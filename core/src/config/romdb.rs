@@ -0,0 +1,210 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use bitflags::bitflags;
+use crc::crc32;
+use directories_next::ProjectDirs;
+use lazy_static::lazy_static;
+use log::warn;
+use std::fs;
+use std::path::PathBuf;
+
+const BUNDLED_DATABASE: &str = include_str!("romdb.tsv");
+
+bitflags!(
+  /// Emulation quirks that can't be detected from the ROM header
+  pub struct RomQuirks: u8 {
+    /// MBC1 multicart, even if the logo heuristic doesn't detect it
+    const MBC1_MULTICART = 1 << 0;
+    /// Wisdom Tree mapper
+    const WISDOM_TREE = 1 << 1;
+    /// Sachen MMC1 mapper
+    const SACHEN_MMC1 = 1 << 2;
+    /// Sachen MMC2 mapper
+    const SACHEN_MMC2 = 1 << 3;
+    /// Rocket Games mapper
+    const ROCKET_GAMES = 1 << 4;
+  }
+);
+
+/// Known-good dump metadata for a ROM
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+  pub name: String,
+  pub region: String,
+  pub crc32: u32,
+  pub sha1: Option<[u8; 20]>,
+  pub quirks: RomQuirks,
+}
+
+impl RomInfo {
+  fn parse(line: &str) -> Option<RomInfo> {
+    let mut columns = line.splitn(5, '\t');
+    let crc32 = u32::from_str_radix(columns.next()?, 16).ok()?;
+    let sha1 = match columns.next()? {
+      "-" => None,
+      hex => Some(parse_sha1(hex)?),
+    };
+    let region = columns.next()?.to_string();
+    let quirks = columns
+      .next()?
+      .split(',')
+      .filter(|&quirk| quirk != "-")
+      .map(|quirk| match quirk {
+        "mbc1-multicart" => Some(RomQuirks::MBC1_MULTICART),
+        "wisdom-tree" => Some(RomQuirks::WISDOM_TREE),
+        "sachen-mmc1" => Some(RomQuirks::SACHEN_MMC1),
+        "sachen-mmc2" => Some(RomQuirks::SACHEN_MMC2),
+        "rocket-games" => Some(RomQuirks::ROCKET_GAMES),
+        _ => None,
+      })
+      .collect::<Option<Vec<_>>>()?
+      .into_iter()
+      .fold(RomQuirks::empty(), |acc, quirk| acc | quirk);
+    let name = columns.next()?.trim().to_string();
+    Some(RomInfo {
+      name,
+      region,
+      crc32,
+      sha1,
+      quirks,
+    })
+  }
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+  if hex.len() != 40 {
+    return None;
+  }
+  let mut sha1 = [0; 20];
+  for (i, byte) in sha1.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+  }
+  Some(sha1)
+}
+
+fn user_database_path() -> Option<PathBuf> {
+  ProjectDirs::from("", "Gekkio", "mooneye-gb").map(|dirs| dirs.data_dir().join("romdb.tsv"))
+}
+
+fn parse_database(text: &str, source: &str) -> Vec<RomInfo> {
+  text
+    .lines()
+    .enumerate()
+    .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+    .filter_map(|(idx, line)| {
+      let info = RomInfo::parse(line);
+      if info.is_none() {
+        warn!("Invalid ROM database entry in {}:{}", source, idx + 1);
+      }
+      info
+    })
+    .collect()
+}
+
+fn load_database() -> Vec<RomInfo> {
+  let mut entries = parse_database(BUNDLED_DATABASE, "bundled ROM database");
+  if let Some(path) = user_database_path() {
+    if let Ok(text) = fs::read_to_string(&path) {
+      entries.extend(parse_database(&text, &path.to_string_lossy()));
+    }
+  }
+  entries
+}
+
+lazy_static! {
+  static ref DATABASE: Vec<RomInfo> = load_database();
+}
+
+fn lookup_in(entries: &[RomInfo], rom: &[u8]) -> Option<RomInfo> {
+  let crc32 = crc32::checksum_ieee(rom);
+  let mut sha1 = None;
+  entries
+    .iter()
+    .filter(|info| info.crc32 == crc32)
+    .find(|info| match info.sha1 {
+      Some(expected) => {
+        expected == *sha1.get_or_insert_with(|| sha1::Sha1::from(rom).digest().bytes())
+      }
+      None => true,
+    })
+    .cloned()
+}
+
+/// Looks up a ROM from the bundled database and the user database in the data directory.
+///
+/// Both databases are parsed on first use. ROMs are matched by CRC32, and by SHA1 if the
+/// database entry has one
+pub fn lookup(rom: &[u8]) -> Option<RomInfo> {
+  lookup_in(&DATABASE, rom)
+}
+
+#[test]
+fn test_parse_entry() {
+  let info = RomInfo::parse(
+    "12345678\t0123456789abcdef0123456789abcdef01234567\tWorld\tmbc1-multicart\tTest ROM (World)",
+  )
+  .unwrap();
+  assert_eq!(info.crc32, 0x1234_5678);
+  assert_eq!(info.sha1.unwrap()[1], 0x23);
+  assert_eq!(info.region, "World");
+  assert_eq!(info.quirks, RomQuirks::MBC1_MULTICART);
+  assert_eq!(info.name, "Test ROM (World)");
+
+  let info = RomInfo::parse("12345678\t-\tAsia\tsachen-mmc1\tTest ROM (Asia)").unwrap();
+  assert_eq!(info.quirks, RomQuirks::SACHEN_MMC1);
+
+  let info = RomInfo::parse("12345678\t-\tJapan\t-\tTest ROM (Japan)").unwrap();
+  assert!(info.sha1.is_none());
+  assert!(info.quirks.is_empty());
+  assert!(RomInfo::parse("12345678\t-\tJapan\tunknown\tTest ROM").is_none());
+}
+
+#[test]
+fn test_bundled_database() {
+  assert_eq!(
+    parse_database(BUNDLED_DATABASE, "bundled ROM database").len(),
+    BUNDLED_DATABASE
+      .lines()
+      .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+      .count()
+  );
+}
+
+#[test]
+fn test_lookup() {
+  let rom = (0..0x8000).map(|i| i as u8).collect::<Vec<_>>();
+  let crc32 = crc32::checksum_ieee(&rom);
+  let sha1 = sha1::Sha1::from(&rom).digest().to_string();
+  let text = format!(
+    "{:08x}\t-\tWorld\tmbc1-multicart\tCRC only\n\
+     {:08x}\t{}\tWorld\twisdom-tree\tCRC and SHA1\n",
+    crc32 ^ 1,
+    crc32,
+    sha1
+  );
+  let entries = parse_database(&text, "test database");
+  let info = lookup_in(&entries, &rom).unwrap();
+  assert_eq!(info.name, "CRC and SHA1");
+  assert_eq!(info.quirks, RomQuirks::WISDOM_TREE);
+
+  let text = format!(
+    "{:08x}\t{}\tWorld\twisdom-tree\tSHA1 mismatch\n",
+    crc32,
+    "0".repeat(40)
+  );
+  assert!(lookup_in(&parse_database(&text, "test database"), &rom).is_none());
+  assert!(lookup_in(&entries, &rom[1..]).is_none());
+}
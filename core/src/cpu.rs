@@ -39,6 +39,8 @@ pub trait CpuContext: CoreContext {
   }
   fn write_cycle_intr(&mut self, addr: u16, data: u8) -> InterruptLine;
   fn tick_cycle(&mut self);
+  /// Called when the instruction at `addr` has been fetched and is about to be executed
+  fn check_execute(&mut self, _addr: u16) {}
   fn has_interrupt(&self) -> bool;
   fn ack_interrupt(&mut self, mask: InterruptLine);
 }
@@ -119,6 +121,14 @@ impl Cpu {
   }
 
   pub fn execute_step<H: CpuContext>(&mut self, ctx: &mut H, step: Step) -> Step {
+    let step = self.step(ctx, step);
+    if step == Step::Running {
+      // The opcode of the next instruction has already been fetched
+      ctx.check_execute(self.regs.pc.wrapping_sub(1));
+    }
+    step
+  }
+  fn step<H: CpuContext>(&mut self, ctx: &mut H, step: Step) -> Step {
     match step {
       Step::Running => self.decode_exec_fetch(ctx),
       Step::InterruptDispatch => {
@@ -512,13 +512,26 @@ impl Cpu {
   ///
   /// Flags: Z N H C
   ///        - - - -
+  ///
+  /// If an interrupt is already pending, HALT exits immediately but fails to
+  /// increment PC (the "HALT bug"):
+  /// - IME=0: the next byte is fetched twice
+  /// - EI followed by HALT: the interrupt returns to the HALT instruction
   pub fn halt<B: CpuContext>(&mut self, ctx: &mut B) -> Step {
+    let halt_bug = ctx.has_interrupt();
     let (interrupts, opcode) = ctx.read_cycle_intr(self.regs.pc);
     self.opcode = opcode;
     if !interrupts.is_empty() {
       if self.ime {
+        if halt_bug {
+          // IME can only be set here with an interrupt already pending if
+          // the previous instruction was EI
+          self.regs.pc = self.regs.pc.wrapping_sub(1);
+        }
         Step::InterruptDispatch
       } else {
+        // PC is not incremented, so the fetched opcode starts executing
+        // with PC still pointing at itself
         self.decode_exec_fetch(ctx)
       }
     } else {
@@ -31,6 +31,9 @@ mod test_cx;
 mod test_ex;
 mod test_fx;
 
+mod test_disasm;
+mod test_halt;
+
 mod test_add16;
 mod test_add16_sp_e;
 mod test_dec16;
@@ -50,6 +53,7 @@ pub struct TestMachine {
 pub struct TestHardware {
   memory: Vec<u8>,
   t_cycles: usize,
+  interrupts: InterruptLine,
 }
 
 impl TestHardware {
@@ -59,6 +63,7 @@ impl TestHardware {
     TestHardware {
       memory,
       t_cycles: 0,
+      interrupts: InterruptLine::empty(),
     }
   }
   fn clock_cycles(&self) -> usize {
@@ -81,7 +86,8 @@ impl CpuContext for TestHardware {
     self.read(addr)
   }
   fn read_cycle_intr(&mut self, addr: u16) -> (InterruptLine, u8) {
-    (InterruptLine::empty(), self.read_cycle(addr))
+    let data = self.read_cycle(addr);
+    (self.interrupts, data)
   }
   fn write_cycle(&mut self, addr: u16, value: u8) {
     self.t_cycles += 4;
@@ -89,15 +95,17 @@ impl CpuContext for TestHardware {
   }
   fn write_cycle_intr(&mut self, addr: u16, value: u8) -> InterruptLine {
     self.write_cycle(addr, value);
-    InterruptLine::empty()
+    self.interrupts
   }
   fn tick_cycle(&mut self) {
     self.t_cycles += 4;
   }
   fn has_interrupt(&self) -> bool {
-    false
+    !self.interrupts.is_empty()
+  }
+  fn ack_interrupt(&mut self, mask: InterruptLine) {
+    self.interrupts -= mask;
   }
-  fn ack_interrupt(&mut self, _: InterruptLine) {}
 }
 
 pub fn run_test<I: Fn(&mut TestMachine) -> ()>(instructions: &[u8], init: I) -> TestMachine {
@@ -0,0 +1,68 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crate::cpu::test::TestHardware;
+use crate::cpu::{Cpu, Step};
+use crate::disasm;
+
+/// Executes one instruction, and returns the machine cycles it took and the address of the next
+/// instruction
+fn execute(instruction: &[u8]) -> (u8, u16) {
+  let mut cpu = Cpu::new();
+  let mut hardware = TestHardware::from_memory(instruction);
+  // The first step executes the NOP the CPU starts with, and fetches the instruction
+  let step = cpu.execute_step(&mut hardware, Step::Running);
+  hardware.t_cycles = 0;
+  cpu.execute_step(&mut hardware, step);
+  ((hardware.t_cycles / 4) as u8, cpu.regs.pc.wrapping_sub(1))
+}
+
+fn check_decoder(instruction: &[u8]) {
+  let expected = disasm::disassemble(instruction, 0x0000).unwrap();
+  let (cycles, next_pc) = execute(instruction);
+  let branch = expected.cycles_taken.is_some()
+    || expected.text.starts_with("jp")
+    || expected.text.starts_with("jr")
+    || expected.text.starts_with("call")
+    || expected.text.starts_with("ret")
+    || expected.text.starts_with("rst");
+  if branch {
+    assert!(
+      cycles == expected.cycles || Some(cycles) == expected.cycles_taken,
+      "{}: {} cycles",
+      expected,
+      cycles
+    );
+  } else {
+    assert_eq!(cycles, expected.cycles, "{}", expected);
+    assert_eq!(next_pc, expected.len as u16, "{}", expected);
+  }
+}
+
+#[test]
+fn test_disasm_matches_decoder() {
+  for opcode in 0x00..=0xff {
+    match opcode {
+      // STOP and undefined opcodes panic, and HALT doesn't complete
+      0x10 | 0x76 | 0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+        continue
+      }
+      _ => check_decoder(&[opcode, 0x00, 0x00]),
+    }
+  }
+  for opcode in 0x00..=0xff {
+    check_decoder(&[0xcb, opcode]);
+  }
+}
@@ -0,0 +1,104 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crate::cpu::test::run_test;
+use crate::cpu::Step;
+use crate::hardware::interrupts::InterruptLine;
+
+#[test]
+fn test_halt_no_interrupt() {
+  let machine = run_test(
+    &[0x76, 0x3c], // HALT; INC A
+    |_| {},
+  );
+  assert_eq!(machine.step, Step::Halt);
+  assert_eq!(machine.cpu.regs.pc, 0x01);
+  assert_eq!(machine.cpu.regs.a, 0x00);
+}
+
+#[test]
+fn test_halt_bug_ime0() {
+  let machine = run_test(
+    &[0x76, 0x3c], // HALT; INC A
+    |machine| {
+      machine.hardware.interrupts = InterruptLine::VBLANK;
+    },
+  );
+  assert_eq!(machine.hardware.clock_cycles(), 12);
+  assert_eq!(machine.cpu.regs.a, 0x02);
+  assert_eq!(machine.cpu.regs.pc, 0x03);
+}
+
+#[test]
+fn test_halt_bug_ime0_operand() {
+  let machine = run_test(
+    &[0x76, 0x3e, 0x3c], // HALT; LD A, n
+    |machine| {
+      machine.hardware.interrupts = InterruptLine::VBLANK;
+    },
+  );
+  // The opcode byte is read again as the operand, and the original operand
+  // is then executed as an opcode (INC A)
+  assert_eq!(machine.cpu.regs.a, 0x3f);
+  assert_eq!(machine.cpu.regs.pc, 0x04);
+}
+
+#[test]
+fn test_halt_bug_ime0_rst() {
+  let machine = run_test(
+    &[0x76, 0xff], // HALT; RST 0x38
+    |machine| {
+      machine.hardware.interrupts = InterruptLine::VBLANK;
+      machine.hardware.memory[0x38] = 0xed;
+      machine.cpu.regs.sp = 0x0010;
+    },
+  );
+  // The return address points to RST itself, so it will run again
+  assert_eq!(machine.cpu.regs.pc, 0x39);
+  assert_eq!(machine.cpu.regs.sp, 0x000e);
+  assert_eq!(machine.hardware.memory[0x0f], 0x00);
+  assert_eq!(machine.hardware.memory[0x0e], 0x01);
+}
+
+#[test]
+fn test_halt_bug_ei() {
+  let machine = run_test(
+    &[0xfb, 0x76, 0x00], // EI; HALT; NOP
+    |machine| {
+      machine.hardware.interrupts = InterruptLine::VBLANK;
+      machine.hardware.memory[0x40] = 0xd9; // RETI
+      machine.cpu.regs.sp = 0x0010;
+    },
+  );
+  // The interrupt returns to HALT, which is executed again and halts normally
+  assert_eq!(machine.step, Step::Halt);
+  assert_eq!(machine.cpu.regs.pc, 0x02);
+  assert!(machine.cpu.ime);
+  assert_eq!(machine.hardware.memory[0x0f], 0x00);
+  assert_eq!(machine.hardware.memory[0x0e], 0x01);
+  assert!(machine.hardware.interrupts.is_empty());
+}
+
+#[test]
+fn test_halt_ime1_no_interrupt() {
+  let machine = run_test(
+    &[0x76], // HALT
+    |machine| {
+      machine.cpu.ime = true;
+    },
+  );
+  assert_eq!(machine.step, Step::Halt);
+  assert_eq!(machine.cpu.regs.pc, 0x01);
+}
@@ -0,0 +1,173 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+//! Breakpoints and watchpoints
+use bitflags::bitflags;
+use std::ops::RangeInclusive;
+
+bitflags!(
+  /// Memory access types that trigger a breakpoint
+  pub struct Access: u8 {
+    const READ    = 0b001;
+    const WRITE   = 0b010;
+    const EXECUTE = 0b100;
+  }
+);
+
+/// A breakpoint or watchpoint on an address range
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+  pub addrs: RangeInclusive<u16>,
+  pub access: Access,
+  /// Cartridge ROM or RAM bank that must be mapped for the breakpoint to trigger. Only applies
+  /// to $0000-$7FFF and $A000-$BFFF
+  pub bank: Option<usize>,
+}
+
+impl Breakpoint {
+  /// Breakpoint on executing the instruction at `addr`
+  pub fn pc(addr: u16) -> Breakpoint {
+    Breakpoint {
+      addrs: addr..=addr,
+      access: Access::EXECUTE,
+      bank: None,
+    }
+  }
+  /// Breakpoint on executing the instruction at `addr` while ROM bank `bank` is mapped there
+  pub fn pc_in_bank(addr: u16, bank: usize) -> Breakpoint {
+    Breakpoint {
+      bank: Some(bank),
+      ..Breakpoint::pc(addr)
+    }
+  }
+  /// Watchpoint on accessing an address range
+  pub fn watch(addrs: RangeInclusive<u16>, access: Access) -> Breakpoint {
+    Breakpoint {
+      addrs,
+      access,
+      bank: None,
+    }
+  }
+  /// Watchpoint on accessing an I/O register at $FF00-$FF7F or IE at $FFFF, given as the low
+  /// byte of the address
+  pub fn io_register(reg: u8, access: Access) -> Breakpoint {
+    let addr = 0xff00 | reg as u16;
+    Breakpoint::watch(addr..=addr, access)
+  }
+  fn matches(&self, addr: u16, access: Access, bank: Option<usize>) -> bool {
+    self.access.intersects(access)
+      && self.addrs.contains(&addr)
+      && (self.bank.is_none() || bank.is_none() || self.bank == bank)
+  }
+}
+
+/// Identifies a breakpoint added with `Breakpoints::add`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BreakpointId(u32);
+
+/// Details of the access that triggered a breakpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BreakpointHit {
+  pub id: BreakpointId,
+  pub addr: u16,
+  pub access: Access,
+  /// Value read or written. None for execution breakpoints
+  pub value: Option<u8>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Breakpoints {
+  breakpoints: Vec<(BreakpointId, Breakpoint)>,
+  next_id: u32,
+  hit: Option<BreakpointHit>,
+}
+
+impl Breakpoints {
+  pub fn add(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+    let id = BreakpointId(self.next_id);
+    self.next_id += 1;
+    self.breakpoints.push((id, breakpoint));
+    id
+  }
+  /// Removes a breakpoint. Returns false if it didn't exist
+  pub fn remove(&mut self, id: BreakpointId) -> bool {
+    let len = self.breakpoints.len();
+    self.breakpoints.retain(|&(bp_id, _)| bp_id != id);
+    self.breakpoints.len() != len
+  }
+  pub fn clear(&mut self) {
+    self.breakpoints.clear();
+  }
+  pub fn is_empty(&self) -> bool {
+    self.breakpoints.is_empty()
+  }
+  pub fn iter(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+    self.breakpoints.iter().map(|(id, bp)| (*id, bp))
+  }
+  /// Returns the last breakpoint hit, if any
+  pub fn hit(&self) -> Option<BreakpointHit> {
+    self.hit
+  }
+  pub fn clear_hit(&mut self) {
+    self.hit = None;
+  }
+  /// Checks an access against the breakpoints, and records the first matching one as hit unless
+  /// an earlier hit hasn't been cleared yet. Returns true if a breakpoint was hit
+  pub fn check(
+    &mut self,
+    addr: u16,
+    access: Access,
+    bank: Option<usize>,
+    value: Option<u8>,
+  ) -> bool {
+    let id = self
+      .breakpoints
+      .iter()
+      .find(|(_, bp)| bp.matches(addr, access, bank))
+      .map(|&(id, _)| id);
+    if let Some(id) = id {
+      if self.hit.is_none() {
+        self.hit = Some(BreakpointHit {
+          id,
+          addr,
+          access,
+          value,
+        });
+      }
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[test]
+fn test_breakpoint_matching() {
+  let mut breakpoints = Breakpoints::default();
+  let pc = breakpoints.add(Breakpoint::pc_in_bank(0x4000, 2));
+  let io = breakpoints.add(Breakpoint::io_register(0x40, Access::WRITE));
+  assert!(!breakpoints.check(0x4000, Access::EXECUTE, Some(1), None));
+  assert!(breakpoints.check(0x4000, Access::EXECUTE, Some(2), None));
+  assert_eq!(breakpoints.hit().unwrap().id, pc);
+  assert!(!breakpoints.check(0xff40, Access::READ, None, Some(0x91)));
+  assert!(breakpoints.check(0xff40, Access::WRITE, None, Some(0x91)));
+  assert_eq!(breakpoints.hit().unwrap().id, pc);
+  breakpoints.clear_hit();
+  assert!(breakpoints.check(0xff40, Access::WRITE, None, Some(0x91)));
+  assert_eq!(breakpoints.hit().unwrap().value, Some(0x91));
+  assert!(breakpoints.remove(io));
+  assert!(!breakpoints.remove(io));
+  assert!(!breakpoints.check(0xff40, Access::WRITE, None, Some(0x91)));
+}
@@ -0,0 +1,268 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+//! SM83 disassembler
+//!
+//! Operands are formatted in RGBDS syntax, and cycle counts are in machine cycles.
+use std::fmt;
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEM: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const COND: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = [
+  "add a,", "adc a,", "sub", "sbc a,", "and", "xor", "or", "cp",
+];
+const ROTATE: [&str; 4] = ["rlca", "rrca", "rla", "rra"];
+const MISC: [&str; 4] = ["daa", "cpl", "scf", "ccf"];
+const SHIFT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// Index of the `[hl]` operand in the 8-bit register encoding
+const R8_HL: usize = 6;
+
+/// A disassembled instruction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+  /// Address of the first byte of the instruction
+  pub addr: u16,
+  /// Instruction length in bytes, including the CB prefix and immediate operands
+  pub len: u8,
+  /// Machine cycles taken, or taken when a conditional branch is not taken
+  pub cycles: u8,
+  /// Machine cycles taken by a conditional instruction when the branch is taken
+  pub cycles_taken: Option<u8>,
+  /// Mnemonic and operands, e.g. `ld a, [hl+]`
+  pub text: String,
+}
+
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(&self.text)
+  }
+}
+
+/// Returns the length in bytes of the instruction starting with `opcode`
+pub fn instruction_len(opcode: u8) -> u8 {
+  match opcode {
+    0xcb | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xe0 | 0xe8 | 0xf0 | 0xf8 => 2,
+    0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e => 2,
+    0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => 2,
+    0x01 | 0x11 | 0x21 | 0x31 | 0x08 | 0xea | 0xfa => 3,
+    0xc2 | 0xc3 | 0xca | 0xd2 | 0xda => 3,
+    0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc => 3,
+    _ => 1,
+  }
+}
+
+/// Disassembles the instruction at the start of `bytes`, which is located at `addr`.
+///
+/// Returns None if `bytes` doesn't contain the complete instruction.
+pub fn disassemble(bytes: &[u8], addr: u16) -> Option<Instruction> {
+  let opcode = *bytes.first()?;
+  let len = instruction_len(opcode);
+  if bytes.len() < len as usize {
+    return None;
+  }
+  let n8 = bytes.get(1).copied().unwrap_or(0);
+  let n16 = u16::from_le_bytes([n8, bytes.get(2).copied().unwrap_or(0)]);
+  let (text, cycles, cycles_taken) = if opcode == 0xcb {
+    let (text, cycles) = decode_cb(n8);
+    (text, cycles, None)
+  } else {
+    decode(opcode, addr, n8, n16)
+  };
+  Some(Instruction {
+    addr,
+    len,
+    cycles,
+    cycles_taken,
+    text,
+  })
+}
+
+/// Returns an iterator that disassembles `bytes` located at `addr` from start to end.
+///
+/// Bytes at the end that don't form a complete instruction are returned as `db` directives.
+pub fn disassemble_all(bytes: &[u8], addr: u16) -> Disassembly<'_> {
+  Disassembly {
+    bytes,
+    addr,
+    offset: 0,
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Disassembly<'a> {
+  bytes: &'a [u8],
+  addr: u16,
+  offset: usize,
+}
+
+impl<'a> Iterator for Disassembly<'a> {
+  type Item = Instruction;
+  fn next(&mut self) -> Option<Instruction> {
+    let bytes = self
+      .bytes
+      .get(self.offset..)
+      .filter(|bytes| !bytes.is_empty())?;
+    let addr = self.addr.wrapping_add(self.offset as u16);
+    let instruction = disassemble(bytes, addr).unwrap_or_else(|| data_byte(bytes[0], addr));
+    self.offset += instruction.len as usize;
+    Some(instruction)
+  }
+}
+
+fn data_byte(value: u8, addr: u16) -> Instruction {
+  Instruction {
+    addr,
+    len: 1,
+    cycles: 1,
+    cycles_taken: None,
+    text: format!("db ${:02x}", value),
+  }
+}
+
+fn signed(value: u8) -> String {
+  let value = value as i8;
+  if value < 0 {
+    format!("-{}", -(value as i16))
+  } else {
+    format!("+{}", value)
+  }
+}
+
+fn decode(opcode: u8, addr: u16, n8: u8, n16: u16) -> (String, u8, Option<u8>) {
+  let x = (opcode >> 6) as usize;
+  let y = ((opcode >> 3) & 0x07) as usize;
+  let z = (opcode & 0x07) as usize;
+  let p = y >> 1;
+  let cc = y & 0x03;
+  let relative = addr.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+  // Extra cycles for accessing [hl] in the destination (y) or source (z) operand
+  let dst_hl = |base: u8, hl: u8| if y == R8_HL { hl } else { base };
+  let src_hl = |base: u8, hl: u8| if z == R8_HL { hl } else { base };
+  match opcode {
+    0x00 => ("nop".into(), 1, None),
+    0x08 => (format!("ld [${:04x}], sp", n16), 5, None),
+    0x10 => ("stop".into(), 1, None),
+    0x18 => (format!("jr ${:04x}", relative), 3, None),
+    0x20 | 0x28 | 0x30 | 0x38 => (format!("jr {}, ${:04x}", COND[cc], relative), 2, Some(3)),
+    0x76 => ("halt".into(), 1, None),
+    0xc3 => (format!("jp ${:04x}", n16), 4, None),
+    0xc9 => ("ret".into(), 4, None),
+    0xcd => (format!("call ${:04x}", n16), 6, None),
+    0xd9 => ("reti".into(), 4, None),
+    0xe0 => (format!("ldh [${:04x}], a", 0xff00 | n8 as u16), 3, None),
+    0xe2 => ("ldh [c], a".into(), 2, None),
+    0xe8 => (format!("add sp, {}", signed(n8)), 4, None),
+    0xe9 => ("jp hl".into(), 1, None),
+    0xea => (format!("ld [${:04x}], a", n16), 4, None),
+    0xf0 => (format!("ldh a, [${:04x}]", 0xff00 | n8 as u16), 3, None),
+    0xf2 => ("ldh a, [c]".into(), 2, None),
+    0xf3 => ("di".into(), 1, None),
+    0xf8 => (format!("ld hl, sp{}", signed(n8)), 3, None),
+    0xf9 => ("ld sp, hl".into(), 2, None),
+    0xfa => (format!("ld a, [${:04x}]", n16), 4, None),
+    0xfb => ("ei".into(), 1, None),
+    0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+      (format!("db ${:02x}", opcode), 1, None)
+    }
+    _ => match (x, z) {
+      (0, 1) if y & 1 == 0 => (format!("ld {}, ${:04x}", R16[p], n16), 3, None),
+      (0, 1) => (format!("add hl, {}", R16[p]), 2, None),
+      (0, 2) if y & 1 == 0 => (format!("ld {}, a", R16_MEM[p]), 2, None),
+      (0, 2) => (format!("ld a, {}", R16_MEM[p]), 2, None),
+      (0, 3) if y & 1 == 0 => (format!("inc {}", R16[p]), 2, None),
+      (0, 3) => (format!("dec {}", R16[p]), 2, None),
+      (0, 4) => (format!("inc {}", R8[y]), dst_hl(1, 3), None),
+      (0, 5) => (format!("dec {}", R8[y]), dst_hl(1, 3), None),
+      (0, 6) => (format!("ld {}, ${:02x}", R8[y], n8), dst_hl(2, 3), None),
+      (0, 7) if y < 4 => (ROTATE[y].into(), 1, None),
+      (0, 7) => (MISC[y - 4].into(), 1, None),
+      (1, _) => (
+        format!("ld {}, {}", R8[y], R8[z]),
+        dst_hl(src_hl(1, 2), 2),
+        None,
+      ),
+      (2, _) => (format!("{} {}", ALU[y], R8[z]), src_hl(1, 2), None),
+      (3, 0) => (format!("ret {}", COND[cc]), 2, Some(5)),
+      (3, 1) => (format!("pop {}", R16_STACK[p]), 3, None),
+      (3, 2) => (format!("jp {}, ${:04x}", COND[cc], n16), 3, Some(4)),
+      (3, 4) => (format!("call {}, ${:04x}", COND[cc], n16), 3, Some(6)),
+      (3, 5) => (format!("push {}", R16_STACK[p]), 4, None),
+      (3, 6) => (format!("{} ${:02x}", ALU[y], n8), 2, None),
+      (3, 7) => (format!("rst ${:02x}", y * 8), 4, None),
+      _ => unreachable!("unhandled opcode {:02x}", opcode),
+    },
+  }
+}
+
+fn decode_cb(opcode: u8) -> (String, u8) {
+  let y = ((opcode >> 3) & 0x07) as usize;
+  let z = (opcode & 0x07) as usize;
+  let cycles = match (opcode >> 6, z) {
+    (1, R8_HL) => 3,
+    (_, R8_HL) => 4,
+    _ => 2,
+  };
+  let text = match opcode >> 6 {
+    0 => format!("{} {}", SHIFT[y], R8[z]),
+    1 => format!("bit {}, {}", y, R8[z]),
+    2 => format!("res {}, {}", y, R8[z]),
+    _ => format!("set {}, {}", y, R8[z]),
+  };
+  (text, cycles)
+}
+
+#[cfg(test)]
+fn check(bytes: &[u8], addr: u16, text: &str, len: u8, cycles: u8, cycles_taken: Option<u8>) {
+  let instruction = disassemble(bytes, addr).unwrap();
+  assert_eq!(instruction.text, text);
+  assert_eq!(instruction.len, len, "{}", text);
+  assert_eq!(instruction.cycles, cycles, "{}", text);
+  assert_eq!(instruction.cycles_taken, cycles_taken, "{}", text);
+}
+
+#[test]
+fn test_disassemble() {
+  check(&[0x00], 0x0100, "nop", 1, 1, None);
+  check(&[0xc3, 0x50, 0x01], 0x0101, "jp $0150", 3, 4, None);
+  check(&[0x20, 0xfe], 0x0150, "jr nz, $0150", 2, 2, Some(3));
+  check(&[0x2a], 0x0000, "ld a, [hl+]", 1, 2, None);
+  check(&[0x36, 0x12], 0x0000, "ld [hl], $12", 2, 3, None);
+  check(&[0x06, 0x12], 0x0000, "ld b, $12", 2, 2, None);
+  check(&[0xb0], 0x0000, "or b", 1, 1, None);
+  check(&[0xb6], 0x0000, "or [hl]", 1, 2, None);
+  check(&[0x70], 0x0000, "ld [hl], b", 1, 2, None);
+  check(&[0x46], 0x0000, "ld b, [hl]", 1, 2, None);
+  check(&[0x34], 0x0000, "inc [hl]", 1, 3, None);
+  check(&[0x3c], 0x0000, "inc a", 1, 1, None);
+  check(&[0xe0, 0x40], 0x0000, "ldh [$ff40], a", 2, 3, None);
+  check(&[0xf8, 0xff], 0x0000, "ld hl, sp-1", 2, 3, None);
+  check(&[0xce, 0x01], 0x0000, "adc a, $01", 2, 2, None);
+  check(&[0xc0], 0x0000, "ret nz", 1, 2, Some(5));
+  check(&[0xf5], 0x0000, "push af", 1, 4, None);
+  check(&[0xff], 0x0000, "rst $38", 1, 4, None);
+  check(&[0xcb, 0x37], 0x0000, "swap a", 2, 2, None);
+  check(&[0xcb, 0x7e], 0x0000, "bit 7, [hl]", 2, 3, None);
+  check(&[0xcb, 0xc6], 0x0000, "set 0, [hl]", 2, 4, None);
+  check(&[0xd3], 0x0000, "db $d3", 1, 1, None);
+  assert_eq!(disassemble(&[0xcd, 0x00], 0x0000), None);
+  let texts: Vec<_> = disassemble_all(&[0x3e, 0x01, 0xcd], 0x0000)
+    .map(|instruction| instruction.text)
+    .collect();
+  assert_eq!(texts, ["ld a, $01", "db $cd"]);
+}
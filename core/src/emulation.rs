@@ -22,9 +22,19 @@ bitflags!(
     const DEBUG_OP         = 0b_0000_0001;
     const VSYNC            = 0b_0000_0010;
     const BOOTROM_DISABLED = 0b_0000_0100;
+    const RUMBLE           = 0b_0000_1000;
+    const BREAKPOINT       = 0b_0001_0000;
   }
 );
 
+/// Rumble motor state of MBC5 rumble cartridges
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RumbleState {
+  pub motor_on: bool,
+  /// Fraction of the last frame the motor was on, from 0.0 to 1.0
+  pub intensity: f32,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct EmuTime {
   pub machine_cycles: u64,
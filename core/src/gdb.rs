@@ -0,0 +1,509 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+//! GDB remote serial protocol server
+//!
+//! GDB doesn't know the SM83, so the server sends a target description with the 16-bit
+//! register pairs AF, BC, DE, HL, SP and PC. Registers are read-only, and memory is read and
+//! written without side effects like `Machine::peek` and `Machine::poke`.
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use crate::debugger::{Access, Breakpoint, BreakpointId};
+use crate::emulation::EmuEvents;
+use crate::machine::Machine;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.mooneye-gb.sm83">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// SIGTRAP, reported for breakpoints and single steps
+const SIGTRAP: u8 = 5;
+/// SIGINT, reported when GDB interrupts the target
+const SIGINT: u8 = 2;
+
+/// GDB breakpoint types used in Z and z packets
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum BreakpointKind {
+  Software,
+  Hardware,
+  WriteWatch,
+  ReadWatch,
+  AccessWatch,
+}
+
+impl BreakpointKind {
+  fn from_packet(value: &str) -> Option<BreakpointKind> {
+    match value {
+      "0" => Some(BreakpointKind::Software),
+      "1" => Some(BreakpointKind::Hardware),
+      "2" => Some(BreakpointKind::WriteWatch),
+      "3" => Some(BreakpointKind::ReadWatch),
+      "4" => Some(BreakpointKind::AccessWatch),
+      _ => None,
+    }
+  }
+  fn breakpoint(self, addr: u16, len: u16) -> Breakpoint {
+    let end = addr.saturating_add(len.max(1) - 1);
+    match self {
+      BreakpointKind::Software | BreakpointKind::Hardware => Breakpoint::pc(addr),
+      BreakpointKind::WriteWatch => Breakpoint::watch(addr..=end, Access::WRITE),
+      BreakpointKind::ReadWatch => Breakpoint::watch(addr..=end, Access::READ),
+      BreakpointKind::AccessWatch => Breakpoint::watch(addr..=end, Access::READ | Access::WRITE),
+    }
+  }
+  /// Returns the stop reason reported to GDB when the breakpoint is hit
+  fn stop_reason(self) -> Option<&'static str> {
+    match self {
+      BreakpointKind::Software | BreakpointKind::Hardware => None,
+      BreakpointKind::WriteWatch => Some("watch"),
+      BreakpointKind::ReadWatch => Some("rwatch"),
+      BreakpointKind::AccessWatch => Some("awatch"),
+    }
+  }
+}
+
+/// A parsed chunk of data received from GDB
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Incoming {
+  /// A complete packet with a valid checksum, and the number of bytes it used
+  Packet(String, usize),
+  /// A packet with an invalid checksum, and the number of bytes it used
+  Corrupted(usize),
+  /// Ctrl-C interrupt request
+  Interrupt,
+  /// An acknowledgement or other byte outside a packet
+  Skip,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Parses the start of the receive buffer. Returns None if more data is needed
+fn parse_incoming(buffer: &[u8]) -> Option<Incoming> {
+  match *buffer.first()? {
+    0x03 => Some(Incoming::Interrupt),
+    b'$' => {
+      let end = buffer.iter().position(|&byte| byte == b'#')?;
+      let checksum_hex = buffer.get(end + 1..end + 3)?;
+      let data = &buffer[1..end];
+      let expected = std::str::from_utf8(checksum_hex)
+        .ok()
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+      match (expected, std::str::from_utf8(data)) {
+        (Some(expected), Ok(packet)) if expected == checksum(data) => {
+          Some(Incoming::Packet(packet.to_owned(), end + 3))
+        }
+        _ => Some(Incoming::Corrupted(end + 3)),
+      }
+    }
+    _ => Some(Incoming::Skip),
+  }
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+  u16::from_str_radix(s, 16).ok()
+}
+
+/// Parses the `addr,len` arguments used by several packets
+fn parse_addr_len(s: &str) -> Option<(u16, u16)> {
+  let mut parts = s.splitn(2, ',');
+  Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+fn hex_u16_le(value: u16) -> String {
+  format!("{:02x}{:02x}", value & 0xff, value >> 8)
+}
+
+/// A GDB connection controlling a machine.
+///
+/// The frontend calls `poll` regularly, handles the events of single steps returned by
+/// `take_step_events`, emulates normally while `is_running` is true, and calls `breakpoint_hit`
+/// when emulation returns `EmuEvents::BREAKPOINT`.
+pub struct GdbServer {
+  stream: TcpStream,
+  buffer: Vec<u8>,
+  running: bool,
+  step_events: EmuEvents,
+  breakpoints: HashMap<(BreakpointKind, u16, u16), BreakpointId>,
+}
+
+impl GdbServer {
+  /// Listens on a TCP port on localhost, and waits until GDB connects. The machine is stopped
+  /// until GDB continues it
+  pub fn listen(port: u16) -> io::Result<GdbServer> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    info!("Waiting for GDB to connect to port {}", port);
+    let (stream, addr) = listener.accept()?;
+    info!("GDB connected from {}", addr);
+    GdbServer::new(stream)
+  }
+  fn new(stream: TcpStream) -> io::Result<GdbServer> {
+    stream.set_nodelay(true)?;
+    Ok(GdbServer {
+      stream,
+      buffer: Vec::new(),
+      running: false,
+      step_events: EmuEvents::empty(),
+      breakpoints: HashMap::new(),
+    })
+  }
+  /// Returns true if GDB has let the machine run
+  pub fn is_running(&self) -> bool {
+    self.running
+  }
+  /// Adds the breakpoints set by GDB to a new machine, e.g. after loading another ROM
+  pub fn attach(&mut self, machine: &mut Machine) {
+    for (&(kind, addr, len), id) in self.breakpoints.iter_mut() {
+      *id = machine.add_breakpoint(kind.breakpoint(addr, len));
+    }
+  }
+  /// Handles all data received from GDB without blocking. Returns false once GDB has detached
+  /// or disconnected, and all its breakpoints have been removed
+  pub fn poll(&mut self, machine: &mut Machine) -> io::Result<bool> {
+    let result = self
+      .receive()
+      .and_then(|connected| Ok(self.process(machine)? && connected));
+    if !matches!(result, Ok(true)) {
+      self.detach(machine);
+    }
+    result
+  }
+  /// Returns the events of single steps done by GDB since the previous call. Breakpoint hits
+  /// have already been reported to GDB, so `EmuEvents::BREAKPOINT` is never included
+  pub fn take_step_events(&mut self) -> EmuEvents {
+    std::mem::replace(&mut self.step_events, EmuEvents::empty())
+  }
+  /// Stops the machine and reports the breakpoint it hit to GDB
+  pub fn breakpoint_hit(&mut self, machine: &Machine) -> io::Result<()> {
+    self.running = false;
+    let reply = self.stop_reply(machine);
+    self.send(&reply)
+  }
+  /// Returns the stop reply for the last `emulate` or `emulate_step` call, with the watchpoint
+  /// that was hit if any
+  fn stop_reply(&self, machine: &Machine) -> String {
+    let kind = machine.breakpoint_hit().and_then(|hit| {
+      self
+        .breakpoints
+        .iter()
+        .find(|&(_, &id)| id == hit.id)
+        .and_then(|(&(kind, _, _), _)| kind.stop_reason().map(|reason| (reason, hit.addr)))
+    });
+    match kind {
+      Some((reason, addr)) => format!("T{:02x}{}:{:04x};", SIGTRAP, reason, addr),
+      None => format!("S{:02x}", SIGTRAP),
+    }
+  }
+  fn detach(&mut self, machine: &mut Machine) {
+    for (_, id) in self.breakpoints.drain() {
+      machine.remove_breakpoint(id);
+    }
+    self.running = true;
+  }
+  /// Reads available data into the buffer. Returns false if GDB closed the connection
+  fn receive(&mut self) -> io::Result<bool> {
+    self.stream.set_nonblocking(true)?;
+    let mut chunk = [0; 1024];
+    let result = loop {
+      match self.stream.read(&mut chunk) {
+        Ok(0) => break Ok(false),
+        Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => break Err(e),
+      }
+    };
+    self.stream.set_nonblocking(false)?;
+    result
+  }
+  /// Handles buffered data. Returns false if GDB detached or killed the target
+  fn process(&mut self, machine: &mut Machine) -> io::Result<bool> {
+    while let Some(incoming) = parse_incoming(&self.buffer) {
+      match incoming {
+        Incoming::Packet(packet, len) => {
+          self.buffer.drain(..len);
+          self.stream.write_all(b"+")?;
+          match packet.as_str() {
+            "D" => {
+              self.send("OK")?;
+              return Ok(false);
+            }
+            "k" => return Ok(false),
+            _ => {
+              if let Some(reply) = self.handle(&packet, machine) {
+                self.send(&reply)?;
+              }
+            }
+          }
+        }
+        Incoming::Corrupted(len) => {
+          self.buffer.drain(..len);
+          self.stream.write_all(b"-")?;
+        }
+        Incoming::Interrupt => {
+          self.buffer.drain(..1);
+          if self.running {
+            self.running = false;
+            self.send(&format!("S{:02x}", SIGINT))?;
+          }
+        }
+        Incoming::Skip => {
+          self.buffer.drain(..1);
+        }
+      }
+    }
+    Ok(true)
+  }
+  fn send(&mut self, data: &str) -> io::Result<()> {
+    let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+    self.stream.write_all(packet.as_bytes())
+  }
+  /// Handles a packet, and returns the reply. Returns None if the reply is sent later
+  fn handle(&mut self, packet: &str, machine: &mut Machine) -> Option<String> {
+    // Empty packets fall through to the empty "unsupported" reply
+    let command_len = packet.chars().next().map_or(0, char::len_utf8);
+    let (command, args) = packet.split_at(command_len);
+    let reply = match command {
+      "?" => format!("S{:02x}", SIGTRAP),
+      "g" => {
+        let regs = machine.regs();
+        let af = (regs.a as u16) << 8 | regs.f.bits() as u16;
+        let pairs = [
+          af,
+          (regs.b as u16) << 8 | regs.c as u16,
+          (regs.d as u16) << 8 | regs.e as u16,
+          (regs.h as u16) << 8 | regs.l as u16,
+          regs.sp,
+          machine.pc(),
+        ];
+        pairs.iter().map(|&value| hex_u16_le(value)).collect()
+      }
+      "p" => {
+        let regs = machine.regs();
+        let value = match parse_hex(args) {
+          Some(0) => Some((regs.a as u16) << 8 | regs.f.bits() as u16),
+          Some(1) => Some((regs.b as u16) << 8 | regs.c as u16),
+          Some(2) => Some((regs.d as u16) << 8 | regs.e as u16),
+          Some(3) => Some((regs.h as u16) << 8 | regs.l as u16),
+          Some(4) => Some(regs.sp),
+          Some(5) => Some(machine.pc()),
+          _ => None,
+        };
+        value.map(hex_u16_le).unwrap_or_else(|| "E01".into())
+      }
+      "m" => match parse_addr_len(args) {
+        Some((addr, len)) => (0..len)
+          .map(|offset| format!("{:02x}", machine.peek(addr.wrapping_add(offset))))
+          .collect(),
+        None => "E01".into(),
+      },
+      "M" => {
+        let mut parts = args.splitn(2, ':');
+        let target = parts.next().and_then(parse_addr_len);
+        let data = parts.next().unwrap_or("");
+        match target {
+          Some((addr, len)) if data.is_ascii() && data.len() == len as usize * 2 => {
+            // Nothing is written unless all the data is valid
+            let values = (0..len as usize)
+              .map(|idx| u8::from_str_radix(&data[idx * 2..idx * 2 + 2], 16).ok())
+              .collect::<Option<Vec<_>>>();
+            match values {
+              Some(values) => {
+                for (offset, value) in (0..len).zip(values) {
+                  machine.poke(addr.wrapping_add(offset), value);
+                }
+                "OK".into()
+              }
+              None => "E01".into(),
+            }
+          }
+          _ => "E01".into(),
+        }
+      }
+      "Z" | "z" => self.handle_breakpoint(command == "Z", args, machine),
+      "c" => {
+        self.running = true;
+        return None;
+      }
+      "s" => {
+        let (events, _) = machine.emulate_step();
+        self.step_events |= events - EmuEvents::BREAKPOINT;
+        self.stop_reply(machine)
+      }
+      "H" => "OK".into(),
+      "q" => self.handle_query(args),
+      _ => String::new(),
+    };
+    Some(reply)
+  }
+  fn handle_breakpoint(&mut self, insert: bool, args: &str, machine: &mut Machine) -> String {
+    let mut parts = args.split(',');
+    let kind = parts.next().and_then(BreakpointKind::from_packet);
+    let addr = parts.next().and_then(parse_hex);
+    let len = parts.next().and_then(parse_hex);
+    let (kind, addr, len) = match (kind, addr, len) {
+      (Some(kind), Some(addr), Some(len)) => (kind, addr, len),
+      _ => return String::new(),
+    };
+    let key = (kind, addr, len);
+    if insert {
+      self
+        .breakpoints
+        .entry(key)
+        .or_insert_with(|| machine.add_breakpoint(kind.breakpoint(addr, len)));
+    } else if let Some(id) = self.breakpoints.remove(&key) {
+      machine.remove_breakpoint(id);
+    }
+    "OK".into()
+  }
+  fn handle_query(&mut self, query: &str) -> String {
+    if query.starts_with("Supported") {
+      "PacketSize=1000;qXfer:features:read+".into()
+    } else if query == "Attached" {
+      "1".into()
+    } else if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+      match parse_addr_len(args) {
+        Some((offset, len)) => {
+          let xml = TARGET_XML.as_bytes();
+          let start = (offset as usize).min(xml.len());
+          let end = (start + len as usize).min(xml.len());
+          let prefix = if end < xml.len() { "m" } else { "l" };
+          format!("{}{}", prefix, String::from_utf8_lossy(&xml[start..end]))
+        }
+        None => "E01".into(),
+      }
+    } else {
+      if query != "C" {
+        warn!("Unsupported GDB query {}", query);
+      }
+      String::new()
+    }
+  }
+}
+
+#[test]
+fn test_parse_incoming() {
+  assert_eq!(parse_incoming(b""), None);
+  assert_eq!(parse_incoming(b"$g#6"), None);
+  assert_eq!(
+    parse_incoming(b"$g#67+"),
+    Some(Incoming::Packet("g".into(), 5))
+  );
+  assert_eq!(parse_incoming(b"$g#00"), Some(Incoming::Corrupted(5)));
+  assert_eq!(parse_incoming(b"\x03"), Some(Incoming::Interrupt));
+  assert_eq!(parse_incoming(b"+"), Some(Incoming::Skip));
+}
+
+#[cfg(test)]
+fn test_connection() -> (GdbServer, TcpStream, Machine) {
+  use crate::config::{Cartridge, HardwareConfig, Model};
+  use std::time::Duration;
+  let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+  client
+    .set_read_timeout(Some(Duration::from_millis(50)))
+    .unwrap();
+  let (stream, _) = listener.accept().unwrap();
+  let machine = Machine::new(HardwareConfig {
+    model: Model::Dmg,
+    bootrom: None,
+    cartridge: Cartridge::no_cartridge(),
+  });
+  (GdbServer::new(stream).unwrap(), client, machine)
+}
+
+/// Sends data to the server, polls it once, and returns the poll result and the reply
+#[cfg(test)]
+fn exchange(
+  server: &mut GdbServer,
+  client: &mut TcpStream,
+  machine: &mut Machine,
+  data: &[u8],
+) -> (bool, String) {
+  client.write_all(data).unwrap();
+  let connected = server.poll(machine).unwrap();
+  let mut reply = Vec::new();
+  let mut chunk = [0; 256];
+  while let Ok(len) = client.read(&mut chunk) {
+    if len == 0 {
+      break;
+    }
+    reply.extend_from_slice(&chunk[..len]);
+  }
+  (connected, String::from_utf8(reply).unwrap())
+}
+
+#[test]
+fn test_malformed_packets() {
+  let (mut server, mut client, mut machine) = test_connection();
+  let mut send = |data: &[u8]| exchange(&mut server, &mut client, &mut machine, data);
+  assert_eq!(send(b"$#00"), (true, "+$#00".into()));
+  assert_eq!(send("$\u{e9}#6c".as_bytes()), (true, "+$#00".into()));
+  assert_eq!(send(b"$mzz#61"), (true, "+$E01#a6".into()));
+  assert_eq!(
+    send("$Mc000,1:\u{e9}#43".as_bytes()),
+    (true, "+$E01#a6".into())
+  );
+  assert_eq!(send(b"$g#00"), (true, "-".into()));
+  assert_eq!(send(b"$mc000,1#bd"), (true, "+$00#60".into()));
+  // An invalid byte fails the whole write
+  assert_eq!(send(b"$Mc000,2:12zz#2f"), (true, "+$E01#a6".into()));
+  assert_eq!(send(b"$mc000,2#be"), (true, "+$0000#c0".into()));
+}
+
+#[test]
+fn test_step_watchpoint() {
+  let (mut server, mut client, mut machine) = test_connection();
+  let mut send = |data: &[u8]| exchange(&mut server, &mut client, &mut machine, data);
+  assert_eq!(send(b"$Z2,fffe,1#ac"), (true, "+$OK#9a".into()));
+  // Without a cartridge the first instruction is RST $38, which pushes PC to $FFFF-$FFFE
+  assert_eq!(send(b"$s#73"), (true, "+$S05#b8".into()));
+  assert_eq!(send(b"$s#73"), (true, "+$T05watch:fffe;#dc".into()));
+  assert!(!server.is_running());
+  assert!(!server.take_step_events().contains(EmuEvents::BREAKPOINT));
+}
+
+#[test]
+fn test_detach_and_kill() {
+  let (mut server, mut client, mut machine) = test_connection();
+  machine.poke(0xc000, 0x00);
+  let (connected, reply) = exchange(&mut server, &mut client, &mut machine, b"$Z2,c000,1#08");
+  assert!(connected);
+  assert_eq!(reply, "+$OK#9a");
+  let (connected, reply) = exchange(&mut server, &mut client, &mut machine, b"$D#44");
+  assert!(!connected);
+  assert_eq!(reply, "+$OK#9a");
+  assert!(server.is_running());
+  assert!(server.breakpoints.is_empty());
+
+  let (mut server, mut client, mut machine) = test_connection();
+  let (connected, reply) = exchange(&mut server, &mut client, &mut machine, b"$k#6b");
+  assert!(!connected);
+  assert_eq!(reply, "+");
+}
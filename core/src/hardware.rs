@@ -13,9 +13,12 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crate::camera::CameraImage;
+use crate::cheats::{Cheats, GameSharkCode};
 use crate::config::HardwareConfig;
 use crate::cpu::CpuContext;
-use crate::emulation::{EmuEvents, EmuTime};
+use crate::debugger::{Access, Breakpoints};
+use crate::emulation::{EmuEvents, EmuTime, RumbleState};
 use crate::gameboy;
 use crate::gameboy::{HiramData, HIRAM_EMPTY};
 use crate::hardware::apu::Apu;
@@ -25,6 +28,7 @@ use crate::hardware::cartridge::Cartridge;
 use crate::hardware::interrupts::{InterruptLine, InterruptRequest, Interrupts};
 use crate::hardware::joypad::Joypad;
 use crate::hardware::ppu::Ppu;
+use crate::hardware::rumble::Rumble;
 use crate::hardware::serial::Serial;
 use crate::hardware::timer::Timer;
 use crate::hardware::work_ram::WorkRam;
@@ -37,6 +41,7 @@ mod cartridge;
 pub mod interrupts;
 mod joypad;
 mod ppu;
+mod rumble;
 mod serial;
 mod timer;
 mod work_ram;
@@ -47,12 +52,15 @@ pub struct Hardware {
   interrupts: Interrupts,
   emu_events: EmuEvents,
   emu_time: EmuTime,
+  rumble: Rumble,
+  game_shark_codes: Vec<GameSharkCode>,
 }
 
 #[derive(Clone)]
 pub struct Peripherals {
   pub bootrom: Bootrom,
   pub cartridge: Cartridge,
+  pub breakpoints: Breakpoints,
   work_ram: WorkRam,
   hiram: HiramData,
   ppu: Ppu,
@@ -70,6 +78,15 @@ struct OamDma {
   requested: Option<u8>,
   starting: Option<u8>,
   addr: u16,
+  transfer: Option<OamDmaTransfer>,
+}
+
+/// Byte copied by OAM DMA during the current machine cycle
+#[derive(Clone, Copy, Debug)]
+struct OamDmaTransfer {
+  bus: ExternalBus,
+  addr: u16,
+  value: u8,
 }
 
 impl OamDma {
@@ -80,6 +97,7 @@ impl OamDma {
       requested: None,
       starting: None,
       addr: 0x0000,
+      transfer: None,
     }
   }
   fn request(&mut self, value: u8) {
@@ -108,6 +126,14 @@ impl OamDma {
   fn is_active(&self) -> bool {
     self.bus.is_some()
   }
+  /// Returns the byte transferred in the current machine cycle, if an access to the given
+  /// address uses the same external bus
+  fn bus_conflict(&self, addr: u16) -> Option<OamDmaTransfer> {
+    match (self.transfer, ExternalBus::from_addr(addr)) {
+      (Some(transfer), Some(bus)) if transfer.bus == bus => Some(transfer),
+      _ => None,
+    }
+  }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -123,13 +149,25 @@ impl ExternalBus {
       _ => ExternalBus::Main,
     }
   }
+  fn from_addr(addr: u16) -> Option<ExternalBus> {
+    match addr >> 8 {
+      0x00..=0x7f | 0xa0..=0xfd => Some(ExternalBus::Main),
+      0x80..=0x9f => Some(ExternalBus::Video),
+      _ => None,
+    }
+  }
 }
 
 impl Peripherals {
   pub fn new(config: HardwareConfig) -> Peripherals {
+    let mut cartridge = Cartridge::new(config.cartridge);
+    if config.bootrom.is_none() {
+      cartridge.bootrom_disabled();
+    }
     Peripherals {
       bootrom: Bootrom::new(config.bootrom),
-      cartridge: Cartridge::new(config.cartridge),
+      cartridge,
+      breakpoints: Breakpoints::default(),
       work_ram: WorkRam::new(),
       hiram: HIRAM_EMPTY,
       ppu: Ppu::new(),
@@ -149,6 +187,8 @@ impl Hardware {
       interrupts: Interrupts::new(),
       emu_events: EmuEvents::empty(),
       emu_time: EmuTime::zero(),
+      rumble: Rumble::new(),
+      game_shark_codes: Vec::new(),
     }
   }
   pub fn ack_emu_events(&mut self) -> EmuEvents {
@@ -169,7 +209,83 @@ impl Hardware {
     self.peripherals.joypad.key_down(key, &mut self.interrupts);
   }
   pub fn key_up(&mut self, key: GbKey) {
-    self.peripherals.joypad.key_up(key);
+    self.peripherals.joypad.key_up(key, &mut self.interrupts);
+  }
+  pub fn save_data(&self) -> Option<Vec<u8>> {
+    self.peripherals.cartridge.save_data()
+  }
+  pub fn load_save_data(&mut self, data: &[u8]) {
+    self.peripherals.cartridge.load_save_data(data);
+  }
+  pub fn set_tilt(&mut self, x: f32, y: f32) {
+    self.peripherals.cartridge.set_tilt(x, y);
+  }
+  pub fn peek(&self, addr: u16) -> u8 {
+    match addr {
+      0xff0f => self.interrupts.get_interrupt_flag(),
+      0xffff => self.interrupts.get_interrupt_enable(),
+      _ => self.peripherals.peek(addr),
+    }
+  }
+  pub fn poke(&mut self, addr: u16, value: u8) {
+    match addr {
+      0xff0f => self.interrupts.set_interrupt_flag(value),
+      0xffff => self.interrupts.set_interrupt_enable(value),
+      _ => self.peripherals.poke(addr, value),
+    }
+  }
+  pub fn vram(&self) -> &[u8] {
+    self.peripherals.ppu.vram()
+  }
+  pub fn oam(&self) -> &[u8] {
+    self.peripherals.ppu.oam()
+  }
+  pub fn wram(&self) -> &[u8] {
+    self.peripherals.work_ram.data()
+  }
+  pub fn hram(&self) -> &[u8] {
+    &self.peripherals.hiram
+  }
+  pub fn rom_bank(&self, bank: usize) -> Option<&[u8]> {
+    self.peripherals.cartridge.rom_bank(bank)
+  }
+  pub fn ram_bank(&self, bank: usize) -> Option<&[u8]> {
+    self.peripherals.cartridge.ram_bank(bank)
+  }
+  pub fn set_cheats(&mut self, cheats: &Cheats) {
+    self
+      .peripherals
+      .cartridge
+      .set_game_genie_codes(cheats.game_genie_codes());
+    self.game_shark_codes = cheats.game_shark_codes();
+  }
+  /// Applies GameShark codes. Called once per frame at VBlank
+  pub fn apply_game_shark_codes(&mut self) {
+    for code in &self.game_shark_codes {
+      self.peripherals.cheat_write(code);
+    }
+  }
+  pub fn flash_data(&self) -> Option<&[u8]> {
+    self.peripherals.cartridge.flash_data()
+  }
+  pub fn rumble_state(&self) -> RumbleState {
+    self.rumble.state(self.emu_time)
+  }
+  /// Tracks rumble motor changes caused by a cartridge control write
+  fn update_rumble(&mut self) {
+    let motor_on = self.peripherals.cartridge.motor_on();
+    if motor_on != self.rumble.is_motor_on() {
+      self.rumble.set_motor(motor_on, self.emu_time);
+    }
+  }
+  /// Raises `EmuEvents::RUMBLE` if the rumble state has changed since the previous poll
+  pub fn poll_rumble(&mut self) {
+    if self.rumble.poll(self.emu_time) {
+      self.emu_events.insert(EmuEvents::RUMBLE);
+    }
+  }
+  pub fn set_camera_frames(&mut self, frames: Vec<CameraImage>) {
+    self.peripherals.cartridge.set_camera_frames(frames);
   }
 }
 
@@ -204,6 +320,7 @@ impl<'a> PeripheralsContext for (&'a mut Interrupts, &'a mut EmuEvents) {
 
 impl Peripherals {
   fn emulate_oam_dma(&mut self) {
+    self.oam_dma.transfer = None;
     if let Some(addr) = self.oam_dma.emulate() {
       let value = match addr >> 8 {
         0x00..=0x3f => self.cartridge.read_0000_3fff(addr),
@@ -216,6 +333,11 @@ impl Peripherals {
         0xf0..=0xff => self.work_ram.read_upper(addr),
         _ => unreachable!("Unreachable OAM DMA read from ${:04x}", addr),
       };
+      self.oam_dma.transfer = Some(OamDmaTransfer {
+        bus: ExternalBus::from_oam_dma_source((addr >> 8) as u8),
+        addr,
+        value,
+      });
       self.ppu.write_oam(addr, value);
     }
     if let Some(source) = self.oam_dma.starting.take() {
@@ -227,7 +349,10 @@ impl Peripherals {
   }
   fn write_high<C: PeripheralsContext>(&mut self, ctx: &mut C, addr: u16, value: u8) {
     match addr as u8 {
-      0x00 => self.generic_mem_cycle(ctx, |hw| hw.joypad.set_register(value)),
+      0x00 => {
+        self.generic_cycle(ctx);
+        self.joypad.set_register(value, ctx);
+      }
       0x01 => self.generic_mem_cycle(ctx, |hw| hw.serial.set_data(value)),
       0x02 => self.generic_mem_cycle(ctx, |hw| hw.serial.set_control(value)),
       0x04 => self.timer_mem_cycle(ctx, |timer, ctx| timer.div_write_cycle(ctx)),
@@ -238,28 +363,30 @@ impl Peripherals {
         self.generic_cycle(ctx);
         ctx.interrupts_mut().set_interrupt_flag(value);
       }
-      0x10 => self.apu_mem_cycle(ctx, |apu| apu.nr10_write_cycle(value)),
-      0x11 => self.apu_mem_cycle(ctx, |apu| apu.nr11_write_cycle(value)),
-      0x12 => self.apu_mem_cycle(ctx, |apu| apu.nr12_write_cycle(value)),
-      0x13 => self.apu_mem_cycle(ctx, |apu| apu.nr13_write_cycle(value)),
-      0x14 => self.apu_mem_cycle(ctx, |apu| apu.nr14_write_cycle(value)),
-      0x16 => self.apu_mem_cycle(ctx, |apu| apu.nr21_write_cycle(value)),
-      0x17 => self.apu_mem_cycle(ctx, |apu| apu.nr22_write_cycle(value)),
-      0x18 => self.apu_mem_cycle(ctx, |apu| apu.nr23_write_cycle(value)),
-      0x19 => self.apu_mem_cycle(ctx, |apu| apu.nr24_write_cycle(value)),
-      0x1a => self.apu_mem_cycle(ctx, |apu| apu.nr30_write_cycle(value)),
-      0x1b => self.apu_mem_cycle(ctx, |apu| apu.nr31_write_cycle(value)),
-      0x1c => self.apu_mem_cycle(ctx, |apu| apu.nr32_write_cycle(value)),
-      0x1d => self.apu_mem_cycle(ctx, |apu| apu.nr33_write_cycle(value)),
-      0x1e => self.apu_mem_cycle(ctx, |apu| apu.nr34_write_cycle(value)),
-      0x20 => self.apu_mem_cycle(ctx, |apu| apu.nr41_write_cycle(value)),
-      0x21 => self.apu_mem_cycle(ctx, |apu| apu.nr42_write_cycle(value)),
-      0x22 => self.apu_mem_cycle(ctx, |apu| apu.nr43_write_cycle(value)),
-      0x23 => self.apu_mem_cycle(ctx, |apu| apu.nr44_write_cycle(value)),
-      0x24 => self.apu_mem_cycle(ctx, |apu| apu.nr50_write_cycle(value)),
-      0x25 => self.apu_mem_cycle(ctx, |apu| apu.nr51_write_cycle(value)),
-      0x26 => self.apu_mem_cycle(ctx, |apu| apu.nr52_write_cycle(value)),
-      0x30..=0x3f => self.apu_mem_cycle(ctx, |apu| apu.wave_ram_write_cycle(addr, value)),
+      0x10 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr10_write_cycle(value, div_apu)),
+      0x11 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr11_write_cycle(value, div_apu)),
+      0x12 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr12_write_cycle(value, div_apu)),
+      0x13 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr13_write_cycle(value, div_apu)),
+      0x14 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr14_write_cycle(value, div_apu)),
+      0x16 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr21_write_cycle(value, div_apu)),
+      0x17 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr22_write_cycle(value, div_apu)),
+      0x18 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr23_write_cycle(value, div_apu)),
+      0x19 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr24_write_cycle(value, div_apu)),
+      0x1a => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr30_write_cycle(value, div_apu)),
+      0x1b => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr31_write_cycle(value, div_apu)),
+      0x1c => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr32_write_cycle(value, div_apu)),
+      0x1d => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr33_write_cycle(value, div_apu)),
+      0x1e => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr34_write_cycle(value, div_apu)),
+      0x20 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr41_write_cycle(value, div_apu)),
+      0x21 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr42_write_cycle(value, div_apu)),
+      0x22 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr43_write_cycle(value, div_apu)),
+      0x23 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr44_write_cycle(value, div_apu)),
+      0x24 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr50_write_cycle(value, div_apu)),
+      0x25 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr51_write_cycle(value, div_apu)),
+      0x26 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr52_write_cycle(value, div_apu)),
+      0x30..=0x3f => self.apu_mem_cycle(ctx, |apu, div_apu| {
+        apu.wave_ram_write_cycle(addr, value, div_apu)
+      }),
       0x40 => self.generic_mem_cycle(ctx, |hw| hw.ppu.set_control(value)),
       0x41 => self.generic_mem_cycle(ctx, |hw| hw.ppu.set_stat(value)),
       0x42 => self.generic_mem_cycle(ctx, |hw| hw.ppu.set_scroll_y(value)),
@@ -276,6 +403,7 @@ impl Peripherals {
         self.generic_cycle(ctx);
         if self.bootrom.is_active() && value & 0b1 != 0 {
           self.bootrom.deactivate();
+          self.cartridge.bootrom_disabled();
           if let Some(callbacks) = ctx.callbacks() {
             callbacks.bootrom_disabled();
           }
@@ -302,28 +430,28 @@ impl Peripherals {
         self.generic_cycle(ctx);
         ctx.interrupts().get_interrupt_flag()
       }
-      0x10 => self.apu_mem_cycle(ctx, |apu| apu.nr10_read_cycle()),
-      0x11 => self.apu_mem_cycle(ctx, |apu| apu.nr11_read_cycle()),
-      0x12 => self.apu_mem_cycle(ctx, |apu| apu.nr12_read_cycle()),
-      0x13 => self.apu_mem_cycle(ctx, |apu| apu.nr13_read_cycle()),
-      0x14 => self.apu_mem_cycle(ctx, |apu| apu.nr14_read_cycle()),
-      0x16 => self.apu_mem_cycle(ctx, |apu| apu.nr21_read_cycle()),
-      0x17 => self.apu_mem_cycle(ctx, |apu| apu.nr22_read_cycle()),
-      0x18 => self.apu_mem_cycle(ctx, |apu| apu.nr23_read_cycle()),
-      0x19 => self.apu_mem_cycle(ctx, |apu| apu.nr24_read_cycle()),
-      0x1a => self.apu_mem_cycle(ctx, |apu| apu.nr30_read_cycle()),
-      0x1b => self.apu_mem_cycle(ctx, |apu| apu.nr31_read_cycle()),
-      0x1c => self.apu_mem_cycle(ctx, |apu| apu.nr32_read_cycle()),
-      0x1d => self.apu_mem_cycle(ctx, |apu| apu.nr33_read_cycle()),
-      0x1e => self.apu_mem_cycle(ctx, |apu| apu.nr34_read_cycle()),
-      0x20 => self.apu_mem_cycle(ctx, |apu| apu.nr41_read_cycle()),
-      0x21 => self.apu_mem_cycle(ctx, |apu| apu.nr42_read_cycle()),
-      0x22 => self.apu_mem_cycle(ctx, |apu| apu.nr43_read_cycle()),
-      0x23 => self.apu_mem_cycle(ctx, |apu| apu.nr44_read_cycle()),
-      0x24 => self.apu_mem_cycle(ctx, |apu| apu.nr50_read_cycle()),
-      0x25 => self.apu_mem_cycle(ctx, |apu| apu.nr51_read_cycle()),
-      0x26 => self.apu_mem_cycle(ctx, |apu| apu.nr52_read_cycle()),
-      0x30..=0x3f => self.apu_mem_cycle(ctx, |apu| apu.wave_ram_read_cycle(addr)),
+      0x10 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr10_read_cycle(div_apu)),
+      0x11 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr11_read_cycle(div_apu)),
+      0x12 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr12_read_cycle(div_apu)),
+      0x13 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr13_read_cycle(div_apu)),
+      0x14 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr14_read_cycle(div_apu)),
+      0x16 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr21_read_cycle(div_apu)),
+      0x17 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr22_read_cycle(div_apu)),
+      0x18 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr23_read_cycle(div_apu)),
+      0x19 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr24_read_cycle(div_apu)),
+      0x1a => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr30_read_cycle(div_apu)),
+      0x1b => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr31_read_cycle(div_apu)),
+      0x1c => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr32_read_cycle(div_apu)),
+      0x1d => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr33_read_cycle(div_apu)),
+      0x1e => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr34_read_cycle(div_apu)),
+      0x20 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr41_read_cycle(div_apu)),
+      0x21 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr42_read_cycle(div_apu)),
+      0x22 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr43_read_cycle(div_apu)),
+      0x23 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr44_read_cycle(div_apu)),
+      0x24 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr50_read_cycle(div_apu)),
+      0x25 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr51_read_cycle(div_apu)),
+      0x26 => self.apu_mem_cycle(ctx, |apu, div_apu| apu.nr52_read_cycle(div_apu)),
+      0x30..=0x3f => self.apu_mem_cycle(ctx, |apu, div_apu| apu.wave_ram_read_cycle(addr, div_apu)),
       0x40 => self.generic_mem_cycle(ctx, |hw| hw.ppu.get_control()),
       0x41 => self.generic_mem_cycle(ctx, |hw| hw.ppu.get_stat()),
       0x42 => self.generic_mem_cycle(ctx, |hw| hw.ppu.get_scroll_y()),
@@ -345,16 +473,72 @@ impl Peripherals {
     }
   }
   fn write<C: PeripheralsContext>(&mut self, ctx: &mut C, addr: u16, value: u8) {
+    self.write_memory(ctx, addr, value);
+    if !self.breakpoints.is_empty() {
+      self.check_breakpoint(ctx, addr, Access::WRITE, Some(value));
+    }
+  }
+  fn read<C: PeripheralsContext>(&mut self, ctx: &mut C, addr: u16) -> u8 {
+    let value = self.read_memory(ctx, addr);
+    if !self.breakpoints.is_empty() {
+      self.check_breakpoint(ctx, addr, Access::READ, Some(value));
+    }
+    value
+  }
+  /// Checks the instruction about to be executed against the breakpoints
+  fn check_execute<C: CoreContext>(&mut self, ctx: &mut C, addr: u16) {
+    if !self.breakpoints.is_empty() {
+      self.check_breakpoint(ctx, addr, Access::EXECUTE, None);
+    }
+  }
+  /// Returns the cartridge ROM or RAM bank mapped at an address, or None if the address isn't
+  /// banked cartridge memory
+  pub fn bank_at(&self, addr: u16) -> Option<usize> {
+    if addr < 0x0100 && self.bootrom.is_active() {
+      None
+    } else {
+      self.cartridge.bank_at(addr)
+    }
+  }
+  fn check_breakpoint<C: CoreContext>(
+    &mut self,
+    ctx: &mut C,
+    addr: u16,
+    access: Access,
+    value: Option<u8>,
+  ) {
+    let bank = self.bank_at(addr);
+    if self.breakpoints.check(addr, access, bank, value) {
+      if let Some(callbacks) = ctx.callbacks() {
+        callbacks.trigger_emu_events(EmuEvents::BREAKPOINT);
+      }
+    }
+  }
+  fn write_memory<C: PeripheralsContext>(&mut self, ctx: &mut C, addr: u16, value: u8) {
     match (addr >> 8) as u8 {
       0x00 if self.bootrom.is_active() => self.generic_cycle(ctx),
-      0x00..=0x7f => self.generic_mem_cycle(ctx, |hw| hw.cartridge.write_control(addr, value)),
-      0x80..=0x9f => self.generic_mem_cycle(ctx, |hw| hw.ppu.write_video_ram(addr, value)),
-      0xa0..=0xbf => self.generic_mem_cycle(ctx, |hw| hw.cartridge.write_a000_bfff(addr, value)),
-      0xc0..=0xcf => self.generic_mem_cycle(ctx, |hw| hw.work_ram.write_lower(addr, value)),
-      0xd0..=0xdf => self.generic_mem_cycle(ctx, |hw| hw.work_ram.write_upper(addr, value)),
+      0x00..=0x7f => self.external_write_cycle(ctx, addr, value, |hw| {
+        hw.cartridge.write_control(addr, value)
+      }),
+      0x80..=0x9f => {
+        self.external_write_cycle(ctx, addr, value, |hw| hw.ppu.write_video_ram(addr, value))
+      }
+      0xa0..=0xbf => self.external_write_cycle(ctx, addr, value, |hw| {
+        hw.cartridge.write_a000_bfff(addr, value)
+      }),
+      0xc0..=0xcf => {
+        self.external_write_cycle(ctx, addr, value, |hw| hw.work_ram.write_lower(addr, value))
+      }
+      0xd0..=0xdf => {
+        self.external_write_cycle(ctx, addr, value, |hw| hw.work_ram.write_upper(addr, value))
+      }
       // Echo RAM
-      0xe0..=0xef => self.generic_mem_cycle(ctx, |hw| hw.work_ram.write_lower(addr, value)),
-      0xf0..=0xfd => self.generic_mem_cycle(ctx, |hw| hw.work_ram.write_upper(addr, value)),
+      0xe0..=0xef => {
+        self.external_write_cycle(ctx, addr, value, |hw| hw.work_ram.write_lower(addr, value))
+      }
+      0xf0..=0xfd => {
+        self.external_write_cycle(ctx, addr, value, |hw| hw.work_ram.write_upper(addr, value))
+      }
       0xfe => match addr & 0xff {
         0x00..=0x9f => self.generic_mem_cycle(ctx, |hw| {
           if !hw.oam_dma.is_active() {
@@ -366,18 +550,20 @@ impl Peripherals {
       0xff => self.write_high(ctx, addr, value),
     }
   }
-  fn read<C: PeripheralsContext>(&mut self, ctx: &mut C, addr: u16) -> u8 {
+  fn read_memory<C: PeripheralsContext>(&mut self, ctx: &mut C, addr: u16) -> u8 {
     match (addr >> 8) as u8 {
       0x00 if self.bootrom.is_active() => self.generic_mem_cycle(ctx, |hw| hw.bootrom[addr]),
-      0x00..=0x3f => self.generic_mem_cycle(ctx, |hw| hw.cartridge.read_0000_3fff(addr)),
-      0x40..=0x7f => self.generic_mem_cycle(ctx, |hw| hw.cartridge.read_4000_7fff(addr)),
-      0x80..=0x9f => self.generic_mem_cycle(ctx, |hw| hw.ppu.read_video_ram(addr)),
-      0xa0..=0xbf => self.generic_mem_cycle(ctx, |hw| hw.cartridge.read_a000_bfff(addr, 0xff)),
-      0xc0..=0xcf => self.generic_mem_cycle(ctx, |hw| hw.work_ram.read_lower(addr)),
-      0xd0..=0xdf => self.generic_mem_cycle(ctx, |hw| hw.work_ram.read_upper(addr)),
+      0x00..=0x3f => self.external_read_cycle(ctx, addr, |hw| hw.cartridge.read_0000_3fff(addr)),
+      0x40..=0x7f => self.external_read_cycle(ctx, addr, |hw| hw.cartridge.read_4000_7fff(addr)),
+      0x80..=0x9f => self.external_read_cycle(ctx, addr, |hw| hw.ppu.read_video_ram(addr)),
+      0xa0..=0xbf => {
+        self.external_read_cycle(ctx, addr, |hw| hw.cartridge.read_a000_bfff(addr, 0xff))
+      }
+      0xc0..=0xcf => self.external_read_cycle(ctx, addr, |hw| hw.work_ram.read_lower(addr)),
+      0xd0..=0xdf => self.external_read_cycle(ctx, addr, |hw| hw.work_ram.read_upper(addr)),
       // Echo RAM
-      0xe0..=0xef => self.generic_mem_cycle(ctx, |hw| hw.work_ram.read_lower(addr)),
-      0xf0..=0xfd => self.generic_mem_cycle(ctx, |hw| hw.work_ram.read_upper(addr)),
+      0xe0..=0xef => self.external_read_cycle(ctx, addr, |hw| hw.work_ram.read_lower(addr)),
+      0xf0..=0xfd => self.external_read_cycle(ctx, addr, |hw| hw.work_ram.read_upper(addr)),
       0xfe => {
         match addr & 0xff {
           0x00..=0x9f => self.generic_mem_cycle(ctx, |hw| {
@@ -395,11 +581,90 @@ impl Peripherals {
       0xff => self.read_high(ctx, addr),
     }
   }
+  /// Reads memory without advancing time or triggering side effects. VRAM, OAM and wave RAM
+  /// access restrictions don't apply
+  fn peek(&self, addr: u16) -> u8 {
+    match addr >> 8 {
+      0x00 if self.bootrom.is_active() => self.bootrom[addr],
+      0x00..=0x3f => self.cartridge.read_0000_3fff(addr),
+      0x40..=0x7f => self.cartridge.read_4000_7fff(addr),
+      0x80..=0x9f => self.ppu.vram()[addr as usize & 0x1fff],
+      0xa0..=0xbf => self.cartridge.read_a000_bfff(addr, 0xff),
+      0xc0..=0xfd => self.work_ram.data()[addr as usize & 0x1fff],
+      0xfe => match addr & 0xff {
+        0x00..=0x9f => self.ppu.oam()[addr as usize & 0xff],
+        _ => 0xff,
+      },
+      _ => self.peek_high(addr),
+    }
+  }
+  fn peek_high(&self, addr: u16) -> u8 {
+    match addr as u8 {
+      0x00 => self.joypad.get_register(),
+      0x01 => self.serial.get_data(),
+      0x02 => self.serial.get_control(),
+      0x04..=0x07 => self.timer.peek_reg(addr),
+      0x10..=0x3f => self.apu.peek_reg(addr),
+      0x40 => self.ppu.get_control(),
+      0x41 => self.ppu.get_stat(),
+      0x42 => self.ppu.get_scroll_y(),
+      0x43 => self.ppu.get_scroll_x(),
+      0x44 => self.ppu.get_current_line(),
+      0x45 => self.ppu.get_compare_line(),
+      0x46 => self.oam_dma.source,
+      0x47 => self.ppu.get_bg_palette(),
+      0x48 => self.ppu.get_obj_palette0(),
+      0x49 => self.ppu.get_obj_palette1(),
+      0x4a => self.ppu.get_window_y(),
+      0x4b => self.ppu.get_window_x(),
+      0x80..=0xfe => self.hiram[(addr as usize) & 0x7f],
+      _ => 0xff,
+    }
+  }
+  /// Writes memory without advancing time. Only RAM can be written: ROM and MBC registers are
+  /// left untouched, and I/O register writes are ignored
+  fn poke(&mut self, addr: u16, value: u8) {
+    match addr >> 8 {
+      0x80..=0x9f => self.ppu.vram_mut()[addr as usize & 0x1fff] = value,
+      0xa0..=0xbf => self.cartridge.poke_ram(None, addr, value),
+      0xc0..=0xfd => self.work_ram.data_mut()[addr as usize & 0x1fff] = value,
+      0xfe => {
+        if let 0x00..=0x9f = addr & 0xff {
+          self.ppu.oam_mut()[addr as usize & 0xff] = value;
+        }
+      }
+      0xff if (0xff80..=0xfffe).contains(&addr) => self.hiram[(addr as usize) & 0x7f] = value,
+      _ => (),
+    }
+  }
+  /// Writes a GameShark code value to RAM without any timing side effects
+  fn cheat_write(&mut self, code: &GameSharkCode) {
+    let addr = code.addr;
+    match addr >> 8 {
+      0xa0..=0xbf => self.cartridge.poke_ram(code.ram_bank, addr, code.value),
+      0xc0..=0xcf => self.work_ram.write_lower(addr, code.value),
+      0xd0..=0xdf => self.work_ram.write_upper(addr, code.value),
+      0xff if (0xff80..=0xfffe).contains(&addr) => self.hiram[(addr as usize) & 0x7f] = code.value,
+      _ => (),
+    }
+  }
+  /// CPU write to the same external bus as an OAM DMA transfer (DMG behaviour).
+  ///
+  /// The DMA controller drives the address bus, so the write never reaches the CPU's target
+  /// address. Instead, the byte being copied to OAM gets corrupted with the value on the data
+  /// bus. The cartridge isn't written either: whether it sees a write strobe at the DMA source
+  /// address hasn't been verified, and turning such writes into MBC bank switches would be
+  /// far more disruptive than dropping them
+  fn write_oam_dma_conflict(&mut self, transfer: OamDmaTransfer, value: u8) {
+    let corrupted = transfer.value & value;
+    self.ppu.write_oam(transfer.addr, corrupted);
+  }
   fn generic_cycle<C: PeripheralsContext>(&mut self, ctx: &mut C) {
     self.emulate_oam_dma();
+    self.cartridge.tick_cycle();
     self.ppu.emulate(ctx);
     self.timer.tick_cycle(ctx);
-    self.apu.tick_cycle();
+    self.apu.tick_cycle(self.timer.div_apu());
   }
   fn generic_mem_cycle<T, C: PeripheralsContext, F: FnOnce(&mut Self) -> T>(
     &mut self,
@@ -409,15 +674,43 @@ impl Peripherals {
     self.generic_cycle(ctx);
     f(self)
   }
-  fn apu_mem_cycle<T, C: PeripheralsContext, F: FnOnce(&mut Apu) -> T>(
+  /// Memory cycle on the main or video bus. If OAM DMA uses the same bus in this cycle, the CPU
+  /// sees the byte being transferred instead of the byte at the requested address
+  fn external_read_cycle<C: PeripheralsContext, F: FnOnce(&mut Self) -> u8>(
+    &mut self,
+    ctx: &mut C,
+    addr: u16,
+    f: F,
+  ) -> u8 {
+    self.generic_cycle(ctx);
+    match self.oam_dma.bus_conflict(addr) {
+      Some(transfer) => transfer.value,
+      None => f(self),
+    }
+  }
+  fn external_write_cycle<C: PeripheralsContext, F: FnOnce(&mut Self)>(
+    &mut self,
+    ctx: &mut C,
+    addr: u16,
+    value: u8,
+    f: F,
+  ) {
+    self.generic_cycle(ctx);
+    match self.oam_dma.bus_conflict(addr) {
+      Some(transfer) => self.write_oam_dma_conflict(transfer, value),
+      None => f(self),
+    }
+  }
+  fn apu_mem_cycle<T, C: PeripheralsContext, F: FnOnce(&mut Apu, bool) -> T>(
     &mut self,
     ctx: &mut C,
     f: F,
   ) -> T {
     self.emulate_oam_dma();
+    self.cartridge.tick_cycle();
     self.ppu.emulate(ctx);
     self.timer.tick_cycle(ctx);
-    f(&mut self.apu)
+    f(&mut self.apu, self.timer.div_apu())
   }
   fn timer_mem_cycle<T, C: PeripheralsContext, F: FnOnce(&mut Timer, &mut C) -> T>(
     &mut self,
@@ -425,9 +718,10 @@ impl Peripherals {
     f: F,
   ) -> T {
     self.emulate_oam_dma();
+    self.cartridge.tick_cycle();
     self.ppu.emulate(ctx);
     let result = f(&mut self.timer, ctx);
-    self.apu.tick_cycle();
+    self.apu.tick_cycle(self.timer.div_apu());
     result
   }
 }
@@ -490,7 +784,7 @@ impl CpuContext for Hardware {
   fn read_cycle_high(&mut self, addr: u8) -> u8 {
     self.emu_time += EmuTime::from_machine_cycles(1);
     let mut ctx = (&mut self.interrupts, &mut self.emu_events);
-    self.peripherals.read_high(&mut ctx, 0xff00 | (addr as u16))
+    self.peripherals.read(&mut ctx, 0xff00 | (addr as u16))
   }
   fn read_cycle_intr(&mut self, addr: u16) -> (InterruptLine, u8) {
     self.emu_time += EmuTime::from_machine_cycles(1);
@@ -505,14 +799,17 @@ impl CpuContext for Hardware {
   fn write_cycle(&mut self, addr: u16, data: u8) {
     self.emu_time += EmuTime::from_machine_cycles(1);
     let mut ctx = (&mut self.interrupts, &mut self.emu_events);
-    self.peripherals.write(&mut ctx, addr, data)
+    self.peripherals.write(&mut ctx, addr, data);
+    if addr < 0x8000 {
+      self.update_rumble();
+    }
   }
   fn write_cycle_high(&mut self, addr: u8, data: u8) {
     self.emu_time += EmuTime::from_machine_cycles(1);
     let mut ctx = (&mut self.interrupts, &mut self.emu_events);
     self
       .peripherals
-      .write_high(&mut ctx, 0xff00 | (addr as u16), data);
+      .write(&mut ctx, 0xff00 | (addr as u16), data);
   }
   fn write_cycle_intr(&mut self, addr: u16, data: u8) -> InterruptLine {
     self.emu_time += EmuTime::from_machine_cycles(1);
@@ -522,13 +819,21 @@ impl CpuContext for Hardware {
       emu_events: &mut self.emu_events,
     };
     self.peripherals.write(&mut ctx, addr, data);
-    ctx.check.get_interrupt()
+    let interrupt = ctx.check.get_interrupt();
+    if addr < 0x8000 {
+      self.update_rumble();
+    }
+    interrupt
   }
   fn tick_cycle(&mut self) {
     self.emu_time += EmuTime::from_machine_cycles(1);
     let mut ctx = (&mut self.interrupts, &mut self.emu_events);
     self.peripherals.generic_cycle(&mut ctx);
   }
+  fn check_execute(&mut self, addr: u16) {
+    let mut ctx = (&mut self.interrupts, &mut self.emu_events);
+    self.peripherals.check_execute(&mut ctx, addr);
+  }
   fn has_interrupt(&self) -> bool {
     !self.interrupts.get_interrupt().is_empty()
   }
@@ -536,3 +841,122 @@ impl CpuContext for Hardware {
     self.interrupts.ack_interrupt(mask);
   }
 }
+
+#[cfg(test)]
+fn oam_dma_test_hardware() -> Hardware {
+  use crate::config::{Cartridge, Model};
+  let mut hw = Hardware::new(HardwareConfig {
+    model: Model::Dmg,
+    bootrom: None,
+    cartridge: Cartridge::no_cartridge(),
+  });
+  // Turn the LCD off during VBlank, so OAM is always accessible
+  hw.write_cycle_high(0x40, 0x80);
+  while hw.peripherals.ppu.get_stat() & 0x03 != 0x01 {
+    hw.tick_cycle();
+  }
+  hw.write_cycle_high(0x40, 0x00);
+  for addr in 0xc000..0xc200 {
+    hw.poke(addr, addr as u8 ^ 0x5a);
+  }
+  hw
+}
+
+/// Writes $FF46 and returns once the first byte has been transferred
+#[cfg(test)]
+fn start_oam_dma(hw: &mut Hardware, source: u8) {
+  hw.write_cycle_high(0x46, source);
+  hw.tick_cycle();
+  // The DMA transfer starts at the end of this cycle, so there's no conflict yet
+  assert_eq!(hw.read_cycle(0xc123), 0x23 ^ 0x5a);
+}
+
+#[test]
+fn test_oam_dma_conflicting_read() {
+  let mut hw = oam_dma_test_hardware();
+  start_oam_dma(&mut hw, 0xc1);
+  for offset in 0x00..0xa0 {
+    assert_eq!(hw.read_cycle(0xc000), offset ^ 0x5a);
+  }
+  // The last byte has been transferred, so the bus is free again
+  assert_eq!(hw.read_cycle(0xc000), 0x5a);
+  assert_eq!(&hw.oam()[..0xa0], &hw.wram()[0x100..0x1a0]);
+}
+
+#[test]
+fn test_oam_dma_read_other_bus() {
+  let mut hw = oam_dma_test_hardware();
+  hw.poke(0x8000, 0x42);
+  start_oam_dma(&mut hw, 0xc0);
+  assert_eq!(hw.read_cycle(0x8000), 0x42);
+  assert_eq!(hw.read_cycle(0xff80), hw.peek(0xff80));
+  assert_eq!(hw.read_cycle(0xc1ff), 0x02 ^ 0x5a);
+}
+
+#[test]
+fn test_oam_dma_restart() {
+  let mut hw = oam_dma_test_hardware();
+  start_oam_dma(&mut hw, 0x80);
+  // A new transfer from work RAM replaces the transfer from video RAM. The old transfer keeps
+  // using the video bus until the new one starts
+  hw.write_cycle_high(0x46, 0xc0);
+  hw.tick_cycle();
+  assert_eq!(hw.read_cycle(0xc123), 0x23 ^ 0x5a);
+  assert_eq!(hw.read_cycle(0xc123), 0x5a);
+  assert_eq!(hw.read_cycle(0x8000), hw.peek(0x8000));
+}
+
+#[test]
+fn test_oam_dma_conflicting_write() {
+  let mut hw = oam_dma_test_hardware();
+  start_oam_dma(&mut hw, 0xc0);
+  hw.write_cycle(0xc1ff, 0x0f);
+  assert_eq!(hw.peek(0xc1ff), 0xff ^ 0x5a);
+  assert_eq!(hw.oam()[0x00], 0x5a & 0x0f);
+  // Writes to the cartridge don't reach it during a conflict
+  hw.write_cycle(0x2000, 0x00);
+  assert_eq!(hw.oam()[0x01], 0x00);
+  for _ in 0x02..0xa0 {
+    hw.tick_cycle();
+  }
+  hw.write_cycle(0xc1ff, 0x0f);
+  assert_eq!(hw.peek(0xc1ff), 0x0f);
+  assert_eq!(hw.oam()[0x9f], 0x9f ^ 0x5a);
+}
+
+#[test]
+fn test_rumble_write() {
+  use crate::config::{Cartridge, CartridgeRamSize, CartridgeRomSize, CartridgeType, Model};
+  let mut hw = Hardware::new(HardwareConfig {
+    model: Model::Dmg,
+    bootrom: None,
+    cartridge: Cartridge {
+      data: vec![0; 0x8000].into(),
+      title: String::new(),
+      cartridge_type: CartridgeType::Mbc5 {
+        ram: false,
+        battery: false,
+        rumble: true,
+      },
+      rom_size: CartridgeRomSize::NoRomBanks,
+      ram_size: CartridgeRamSize::NoRam,
+      header: None,
+      rom_info: None,
+      save_path: None,
+      flash_path: None,
+    },
+  });
+  // Writes outside the cartridge control area don't touch the motor
+  hw.write_cycle(0xc000, 0x08);
+  assert!(!hw.rumble_state().motor_on);
+  hw.write_cycle(0x4000, 0x08);
+  assert!(hw.rumble_state().motor_on);
+  hw.poll_rumble();
+  assert!(hw.ack_emu_events().contains(EmuEvents::RUMBLE));
+  hw.write_cycle(0x4000, 0x00);
+  hw.write_cycle(0x4000, 0x08);
+  hw.poll_rumble();
+  assert!(!hw.ack_emu_events().contains(EmuEvents::RUMBLE));
+  hw.write_cycle(0x4000, 0x00);
+  assert!(!hw.rumble_state().motor_on);
+}
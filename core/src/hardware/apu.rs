@@ -25,6 +25,7 @@ mod ch2;
 mod ch3;
 mod ch4;
 mod envelope;
+mod length_counter;
 mod sweep;
 mod wave_duty;
 
@@ -41,7 +42,8 @@ pub struct Apu {
   ch2: Ch2,
   ch3: Ch3,
   ch4: Ch4,
-  cycles: usize,
+  frame_sequencer: u8,
+  div_apu: bool,
 }
 
 #[derive(Clone, Copy)]
@@ -87,234 +89,302 @@ impl Apu {
       ch2: Ch2::new(),
       ch3: Ch3::new(),
       ch4: Ch4::new(),
-      cycles: 4096,
+      frame_sequencer: 0,
+      div_apu: false,
     }
   }
-  pub fn tick_cycle(&mut self) {
-    if self.cycles > 0 {
-      self.cycles -= 1;
-    } else {
-      self.cycles = 4096;
-      self.ch1.clock();
-      self.ch2.clock();
-      self.ch3.clock();
-      self.ch4.clock();
+  /// Ticks the APU for one machine cycle.
+  ///
+  /// The frame sequencer is not driven by its own clock, but by a falling edge of the
+  /// DIV-APU signal coming from the timer (DIV bit 4 on DMG). Resetting DIV can therefore
+  /// clock the frame sequencer early.
+  pub fn tick_cycle(&mut self, div_apu: bool) {
+    let falling_edge = self.div_apu && !div_apu;
+    self.div_apu = div_apu;
+    if !self.enabled {
+      return;
+    }
+    self.ch3.tick_cycle();
+    if falling_edge {
+      self.step_frame_sequencer();
+    }
+  }
+  fn step_frame_sequencer(&mut self) {
+    match self.frame_sequencer {
+      0 | 4 => self.clock_length(),
+      2 | 6 => {
+        self.clock_length();
+        self.ch1.clock_sweep();
+      }
+      7 => {
+        self.ch1.envelope.clock();
+        self.ch2.envelope.clock();
+        self.ch4.envelope.clock();
+      }
+      _ => (),
+    }
+    self.frame_sequencer = (self.frame_sequencer + 1) & 0x07;
+  }
+  fn clock_length(&mut self) {
+    self.ch1.clock_length();
+    self.ch2.clock_length();
+    self.ch3.clock_length();
+    self.ch4.clock_length();
+  }
+  /// Returns true if the next frame sequencer step doesn't clock the length counters
+  fn extra_length_clock(&self) -> bool {
+    self.frame_sequencer & 0x01 != 0
+  }
+  /// Returns the value of an APU register or wave RAM byte without ticking the APU. Wave RAM
+  /// access restrictions don't apply
+  pub fn peek_reg(&self, addr: u16) -> u8 {
+    match addr & 0xff {
+      0x10 => self.ch1.sweep.read_reg(),
+      0x11 => self.ch1.read_reg1(),
+      0x12 => self.ch1.envelope.read_reg(),
+      0x14 => self.ch1.read_reg4(),
+      0x16 => self.ch2.read_reg1(),
+      0x17 => self.ch2.envelope.read_reg(),
+      0x19 => self.ch2.read_reg4(),
+      0x1a => self.ch3.read_reg0(),
+      0x1c => self.ch3.read_reg2(),
+      0x1e => self.ch3.read_reg4(),
+      0x21 => self.ch4.envelope.read_reg(),
+      0x22 => self.ch4.read_reg3(),
+      0x23 => self.ch4.read_reg4(),
+      0x24 => self.get_ctrl_volume(),
+      0x25 => self.get_terminal_channels(),
+      0x26 => self.get_ctrl_master(),
+      0x30..=0x3f => self.ch3.peek_wave_ram(addr - 0xff30),
+      _ => 0xff,
     }
   }
-  pub fn nr10_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr10_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch1.sweep.read_reg()
   }
-  pub fn nr10_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr10_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch1.sweep.write_reg(value);
+      self.ch1.write_reg0(value);
     }
   }
-  pub fn nr11_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr11_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch1.read_reg1()
   }
-  pub fn nr11_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr11_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch1.write_reg1(value);
+    } else {
+      // DMG: length counters can be written while the APU is powered off
+      self.ch1.length.load(value);
     }
   }
-  pub fn nr12_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr12_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch1.envelope.read_reg()
   }
-  pub fn nr12_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr12_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch1.envelope.write_reg(value);
+      self.ch1.write_reg2(value);
     }
   }
-  pub fn nr13_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr13_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     0xff
   }
-  pub fn nr13_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr13_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch1.write_reg3(value);
     }
   }
-  pub fn nr14_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr14_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch1.read_reg4()
   }
-  pub fn nr14_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr14_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch1.write_reg4(value);
+      self.ch1.write_reg4(value, self.extra_length_clock());
     }
   }
-  pub fn nr21_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr21_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch2.read_reg1()
   }
-  pub fn nr21_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr21_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch2.write_reg1(value);
+    } else {
+      // DMG: length counters can be written while the APU is powered off
+      self.ch2.length.load(value);
     }
   }
-  pub fn nr22_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr22_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch2.envelope.read_reg()
   }
-  pub fn nr22_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr22_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch2.envelope.write_reg(value);
+      self.ch2.write_reg2(value);
     }
   }
-  pub fn nr23_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr23_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     0xff
   }
-  pub fn nr23_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr23_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch2.write_reg3(value);
     }
   }
-  pub fn nr24_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr24_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch2.read_reg4()
   }
-  pub fn nr24_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr24_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch2.write_reg4(value);
+      self.ch2.write_reg4(value, self.extra_length_clock());
     }
   }
-  pub fn nr30_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr30_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch3.read_reg0()
   }
-  pub fn nr30_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr30_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch3.write_reg0(value);
     }
   }
-  pub fn nr31_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr31_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     0xff
   }
-  pub fn nr31_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr31_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch3.write_reg1(value);
+    } else {
+      // DMG: length counters can be written while the APU is powered off
+      self.ch3.length.load(value);
     }
   }
-  pub fn nr32_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr32_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch3.read_reg2()
   }
-  pub fn nr32_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr32_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch3.write_reg2(value);
     }
   }
-  pub fn nr33_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr33_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     0xff
   }
-  pub fn nr33_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr33_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch3.write_reg3(value);
     }
   }
-  pub fn nr34_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr34_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch3.read_reg4()
   }
-  pub fn nr34_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr34_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch3.write_reg4(value);
+      self.ch3.write_reg4(value, self.extra_length_clock());
     }
   }
-  pub fn nr41_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr41_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     0xff
   }
-  pub fn nr41_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr41_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch4.write_reg1(value);
+    } else {
+      // DMG: length counters can be written while the APU is powered off
+      self.ch4.length.load(value);
     }
   }
-  pub fn nr42_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr42_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch4.envelope.read_reg()
   }
-  pub fn nr42_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr42_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch4.envelope.write_reg(value);
+      self.ch4.write_reg2(value);
     }
   }
-  pub fn nr43_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr43_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch4.read_reg3()
   }
-  pub fn nr43_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr43_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.ch4.write_reg3(value);
     }
   }
-  pub fn nr44_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr44_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch4.read_reg4()
   }
-  pub fn nr44_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr44_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
-      self.ch4.write_reg4(value);
+      self.ch4.write_reg4(value, self.extra_length_clock());
     }
   }
-  pub fn nr50_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr50_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.get_ctrl_volume()
   }
-  pub fn nr50_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr50_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.set_ctrl_volume(value);
     }
   }
-  pub fn nr51_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr51_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.get_terminal_channels()
   }
-  pub fn nr51_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr51_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     if self.enabled {
       self.set_terminal_channels(value);
     }
   }
-  pub fn nr52_read_cycle(&mut self) -> u8 {
-    self.tick_cycle();
+  pub fn nr52_read_cycle(&mut self, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.get_ctrl_master()
   }
-  pub fn nr52_write_cycle(&mut self, value: u8) {
-    self.tick_cycle();
+  pub fn nr52_write_cycle(&mut self, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     self.set_ctrl_master(value);
   }
-  pub fn wave_ram_read_cycle(&mut self, addr: u16) -> u8 {
-    self.tick_cycle();
+  pub fn wave_ram_read_cycle(&mut self, addr: u16, div_apu: bool) -> u8 {
+    self.tick_cycle(div_apu);
     self.ch3.read_wave_ram(addr - 0xff30)
   }
-  pub fn wave_ram_write_cycle(&mut self, addr: u16, value: u8) {
-    self.tick_cycle();
+  pub fn wave_ram_write_cycle(&mut self, addr: u16, value: u8, div_apu: bool) {
+    self.tick_cycle(div_apu);
     self.ch3.write_wave_ram(addr - 0xff30, value);
   }
 
@@ -336,19 +406,22 @@ impl Apu {
       | if self.ch1.status { 1 << 0 } else { 0 }
   }
   pub fn set_ctrl_master(&mut self, value: u8) {
-    self.enabled = value & (1 << 7) != 0;
-    if !self.enabled {
-      self.ch1.reset();
-      self.ch2.reset();
-      self.ch3.reset();
-      self.ch4.reset();
+    let enabled = value & (1 << 7) != 0;
+    if self.enabled && !enabled {
+      self.ch1.power_off();
+      self.ch2.power_off();
+      self.ch3.power_off();
+      self.ch4.power_off();
       self.term1_volume = Volume::Vol0;
       self.term2_volume = Volume::Vol0;
       self.term1_vin = false;
       self.term2_vin = false;
       self.term1_channels = Channels::empty();
       self.term2_channels = Channels::empty();
+    } else if !self.enabled && enabled {
+      self.frame_sequencer = 0;
     }
+    self.enabled = enabled;
   }
   pub fn get_ctrl_volume(&self) -> u8 {
     (self.term1_volume as u8)
@@ -372,3 +445,167 @@ bitflags!(
     const CH_4 = 1 << 3;
   }
 );
+
+#[cfg(test)]
+fn powered_on_apu() -> Apu {
+  let mut apu = Apu::new();
+  apu.nr52_write_cycle(0x80, false);
+  apu
+}
+
+/// Clocks the frame sequencer once with a falling edge of DIV-APU
+#[cfg(test)]
+fn clock_frame_sequencer(apu: &mut Apu) {
+  apu.tick_cycle(true);
+  apu.tick_cycle(false);
+}
+
+#[test]
+fn test_frame_sequencer_falling_edge() {
+  let mut apu = powered_on_apu();
+  apu.nr21_write_cycle(0x3f, false);
+  apu.nr22_write_cycle(0xf0, false);
+  apu.nr24_write_cycle(0xc0, false);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf2);
+  // Rising edge and a stable high level don't clock the frame sequencer
+  apu.tick_cycle(true);
+  apu.tick_cycle(true);
+  assert_eq!(apu.frame_sequencer, 0);
+  assert_eq!(apu.nr52_read_cycle(true), 0xf2);
+  // Falling edge clocks step 0, which clocks the length counters
+  apu.tick_cycle(false);
+  assert_eq!(apu.frame_sequencer, 1);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf0);
+}
+
+#[test]
+fn test_frame_sequencer_steps() {
+  let mut apu = powered_on_apu();
+  // Envelope period 1, increasing volume
+  apu.nr22_write_cycle(0x09, false);
+  apu.nr24_write_cycle(0x80, false);
+  for _ in 0..7 {
+    clock_frame_sequencer(&mut apu);
+  }
+  assert_eq!(apu.ch2.envelope.read_reg(), 0x09);
+  assert_eq!(apu.frame_sequencer, 7);
+  clock_frame_sequencer(&mut apu);
+  assert_eq!(apu.frame_sequencer, 0);
+  // Powering the APU on resets the frame sequencer
+  clock_frame_sequencer(&mut apu);
+  apu.nr52_write_cycle(0x00, false);
+  apu.nr52_write_cycle(0x80, false);
+  assert_eq!(apu.frame_sequencer, 0);
+  // The frame sequencer doesn't run while the APU is powered off
+  apu.nr52_write_cycle(0x00, false);
+  clock_frame_sequencer(&mut apu);
+  assert_eq!(apu.frame_sequencer, 0);
+}
+
+#[test]
+fn test_power_off_registers() {
+  let mut apu = powered_on_apu();
+  apu.nr10_write_cycle(0x7f, false);
+  apu.nr11_write_cycle(0xff, false);
+  apu.nr12_write_cycle(0xff, false);
+  apu.nr14_write_cycle(0x40, false);
+  apu.nr30_write_cycle(0x80, false);
+  apu.nr32_write_cycle(0x60, false);
+  apu.nr42_write_cycle(0xff, false);
+  apu.nr43_write_cycle(0xff, false);
+  apu.nr50_write_cycle(0xff, false);
+  apu.nr51_write_cycle(0xff, false);
+  assert_eq!(apu.nr10_read_cycle(false), 0xff);
+  assert_eq!(apu.nr11_read_cycle(false), 0xff);
+  assert_eq!(apu.nr14_read_cycle(false), 0xff);
+  assert_eq!(apu.nr30_read_cycle(false), 0xff);
+  assert_eq!(apu.nr32_read_cycle(false), 0xff);
+  assert_eq!(apu.nr50_read_cycle(false), 0xff);
+  assert_eq!(apu.nr51_read_cycle(false), 0xff);
+  apu.wave_ram_write_cycle(0xff30, 0x12, false);
+
+  apu.nr52_write_cycle(0x00, false);
+  assert_eq!(apu.nr10_read_cycle(false), 0x80);
+  assert_eq!(apu.nr11_read_cycle(false), 0x3f);
+  assert_eq!(apu.nr12_read_cycle(false), 0x00);
+  assert_eq!(apu.nr13_read_cycle(false), 0xff);
+  assert_eq!(apu.nr14_read_cycle(false), 0xbf);
+  assert_eq!(apu.nr30_read_cycle(false), 0x7f);
+  assert_eq!(apu.nr31_read_cycle(false), 0xff);
+  assert_eq!(apu.nr32_read_cycle(false), 0x9f);
+  assert_eq!(apu.nr34_read_cycle(false), 0xbf);
+  assert_eq!(apu.nr41_read_cycle(false), 0xff);
+  assert_eq!(apu.nr42_read_cycle(false), 0x00);
+  assert_eq!(apu.nr43_read_cycle(false), 0x00);
+  assert_eq!(apu.nr44_read_cycle(false), 0xbf);
+  assert_eq!(apu.nr50_read_cycle(false), 0x00);
+  assert_eq!(apu.nr51_read_cycle(false), 0x00);
+  assert_eq!(apu.nr52_read_cycle(false), 0x70);
+  // Wave RAM is not cleared
+  assert_eq!(apu.wave_ram_read_cycle(0xff30, false), 0x12);
+
+  // Registers other than length counters and NR52 can't be written while powered off
+  apu.nr10_write_cycle(0x7f, false);
+  apu.nr50_write_cycle(0xff, false);
+  apu.nr51_write_cycle(0xff, false);
+  assert_eq!(apu.nr10_read_cycle(false), 0x80);
+  assert_eq!(apu.nr50_read_cycle(false), 0x00);
+  assert_eq!(apu.nr51_read_cycle(false), 0x00);
+}
+
+#[test]
+fn test_length_extra_clock() {
+  let mut apu = powered_on_apu();
+  apu.nr22_write_cycle(0xf0, false);
+  apu.nr21_write_cycle(0x3f, false);
+  apu.nr24_write_cycle(0x80, false);
+  // The next step (0) clocks length, so enabling length doesn't clock it
+  apu.nr24_write_cycle(0x40, false);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf2);
+
+  apu.nr24_write_cycle(0x00, false);
+  clock_frame_sequencer(&mut apu);
+  assert_eq!(apu.frame_sequencer, 1);
+  // The next step (1) doesn't clock length, so enabling length clocks it once, and the
+  // length counter expires
+  apu.nr24_write_cycle(0x40, false);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf0);
+  // Enabling an already enabled counter doesn't clock it again
+  apu.nr21_write_cycle(0x3e, false);
+  apu.nr24_write_cycle(0xc0, false);
+  apu.nr24_write_cycle(0x40, false);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf2);
+
+  // A trigger with length enabled reloads an expired counter with 63 instead of 64
+  apu.nr21_write_cycle(0x3f, false);
+  clock_frame_sequencer(&mut apu);
+  clock_frame_sequencer(&mut apu);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf0);
+  assert_eq!(apu.frame_sequencer, 3);
+  apu.nr24_write_cycle(0xc0, false);
+  // Length is clocked on every other step, starting with the one after the next
+  for _ in 0..125 {
+    clock_frame_sequencer(&mut apu);
+  }
+  assert_eq!(apu.nr52_read_cycle(false), 0xf2);
+  clock_frame_sequencer(&mut apu);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf0);
+}
+
+#[test]
+fn test_sweep_negate_disable() {
+  let mut apu = powered_on_apu();
+  apu.nr12_write_cycle(0xf0, false);
+  // Subtraction with shift 1: the trigger calculates a new frequency
+  apu.nr10_write_cycle(0x19, false);
+  apu.nr14_write_cycle(0x84, false);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf1);
+  apu.nr10_write_cycle(0x11, false);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf0);
+
+  // With shift 0, the trigger doesn't calculate anything
+  apu.nr10_write_cycle(0x18, false);
+  apu.nr14_write_cycle(0x84, false);
+  apu.nr10_write_cycle(0x10, false);
+  assert_eq!(apu.nr52_read_cycle(false), 0xf1);
+}
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
 use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
 use super::sweep::Sweep;
 use super::wave_duty::WaveDuty;
 
@@ -22,9 +23,8 @@ pub struct Ch1 {
   pub sweep: Sweep,
   wave_duty: WaveDuty,
   pub envelope: Envelope,
+  pub length: LengthCounter,
   freq_bits: u16,
-  use_counter: bool,
-  counter: usize,
   pub status: bool,
 }
 
@@ -34,14 +34,23 @@ impl Ch1 {
       sweep: Sweep::new(),
       wave_duty: WaveDuty::HalfQuarter,
       envelope: Envelope::new(),
+      length: LengthCounter::new(64),
       freq_bits: 0,
-      use_counter: false,
-      counter: 0,
       status: false,
     }
   }
-  pub fn reset(&mut self) {
-    *self = Ch1::new();
+  pub fn power_off(&mut self) {
+    let mut length = self.length.clone();
+    length.power_off();
+    *self = Ch1 {
+      length,
+      ..Ch1::new()
+    };
+  }
+  pub fn write_reg0(&mut self, value: u8) {
+    if !self.sweep.write_reg(value) {
+      self.status = false;
+    }
   }
   pub fn read_reg1(&self) -> u8 {
     const REG1_MASK: u8 = 0x3F;
@@ -50,7 +59,13 @@ impl Ch1 {
   }
   pub fn write_reg1(&mut self, value: u8) {
     self.wave_duty = WaveDuty::from_u8((value >> 6) & 0x03).unwrap();
-    self.counter = 64 - (value & 0x3f) as usize;
+    self.length.load(value);
+  }
+  pub fn write_reg2(&mut self, value: u8) {
+    self.envelope.write_reg(value);
+    if !self.envelope.dac_enabled() {
+      self.status = false;
+    }
   }
   pub fn write_reg3(&mut self, value: u8) {
     self.freq_bits = (self.freq_bits & 0x700) | value as u16;
@@ -58,23 +73,32 @@ impl Ch1 {
   pub fn read_reg4(&self) -> u8 {
     const REG4_MASK: u8 = 0xBF;
 
-    REG4_MASK | if self.use_counter { 1 << 6 } else { 0 }
+    REG4_MASK | if self.length.is_enabled() { 1 << 6 } else { 0 }
+  }
+  pub fn write_reg4(&mut self, value: u8, extra_length_clock: bool) {
+    self.freq_bits = (self.freq_bits & 0xff) | (((value & 0x07) as u16) << 8);
+    if self.length.write_control(value, extra_length_clock) {
+      self.status = false;
+    }
+    if value & (1 << 7) != 0 {
+      self.trigger();
+    }
   }
-  pub fn write_reg4(&mut self, value: u8) {
-    self.status = value & (1 << 7) != 0;
-    self.use_counter = value & (1 << 6) != 0;
-    self.freq_bits = (self.freq_bits & 0xff) | ((value as u16) << 8);
-    if self.status && self.counter == 0 {
-      self.counter = 64;
+  fn trigger(&mut self) {
+    self.status = self.envelope.dac_enabled();
+    self.envelope.trigger();
+    if !self.sweep.trigger(self.freq_bits) {
+      self.status = false;
     }
   }
-  pub fn clock(&mut self) {
-    if self.use_counter && self.counter > 0 {
-      self.counter -= 1;
-
-      if self.counter == 0 {
-        self.status = false;
-      }
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.status = false;
+    }
+  }
+  pub fn clock_sweep(&mut self) {
+    if self.status && !self.sweep.clock(&mut self.freq_bits) {
+      self.status = false;
     }
   }
 }
@@ -14,15 +14,15 @@
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
 use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
 use super::wave_duty::WaveDuty;
 
 #[derive(Clone)]
 pub struct Ch2 {
   wave_duty: WaveDuty,
   pub envelope: Envelope,
+  pub length: LengthCounter,
   freq_bits: u16,
-  use_counter: bool,
-  counter: usize,
   pub status: bool,
 }
 
@@ -31,14 +31,18 @@ impl Ch2 {
     Ch2 {
       wave_duty: WaveDuty::HalfQuarter,
       envelope: Envelope::new(),
+      length: LengthCounter::new(64),
       freq_bits: 0,
-      use_counter: false,
-      counter: 0,
       status: false,
     }
   }
-  pub fn reset(&mut self) {
-    *self = Ch2::new()
+  pub fn power_off(&mut self) {
+    let mut length = self.length.clone();
+    length.power_off();
+    *self = Ch2 {
+      length,
+      ..Ch2::new()
+    };
   }
   pub fn read_reg1(&self) -> u8 {
     const REG1_MASK: u8 = 0x3F;
@@ -47,7 +51,13 @@ impl Ch2 {
   }
   pub fn write_reg1(&mut self, value: u8) {
     self.wave_duty = WaveDuty::from_u8((value >> 6) & 0x03).unwrap();
-    self.counter = 64 - (value & 0x3f) as usize;
+    self.length.load(value);
+  }
+  pub fn write_reg2(&mut self, value: u8) {
+    self.envelope.write_reg(value);
+    if !self.envelope.dac_enabled() {
+      self.status = false;
+    }
   }
   pub fn write_reg3(&mut self, value: u8) {
     self.freq_bits = (self.freq_bits & 0x700) | value as u16;
@@ -55,23 +65,24 @@ impl Ch2 {
   pub fn read_reg4(&self) -> u8 {
     const REG4_MASK: u8 = 0xBF;
 
-    REG4_MASK | if self.use_counter { 1 << 6 } else { 0 }
+    REG4_MASK | if self.length.is_enabled() { 1 << 6 } else { 0 }
   }
-  pub fn write_reg4(&mut self, value: u8) {
-    self.status = value & (1 << 7) != 0;
-    self.use_counter = value & (1 << 6) != 0;
-    self.freq_bits = (self.freq_bits & 0xff) | ((value as u16) << 8);
-    if self.status && self.counter == 0 {
-      self.counter = 64;
+  pub fn write_reg4(&mut self, value: u8, extra_length_clock: bool) {
+    self.freq_bits = (self.freq_bits & 0xff) | (((value & 0x07) as u16) << 8);
+    if self.length.write_control(value, extra_length_clock) {
+      self.status = false;
+    }
+    if value & (1 << 7) != 0 {
+      self.trigger();
     }
   }
-  pub fn clock(&mut self) {
-    if self.use_counter && self.counter > 0 {
-      self.counter -= 1;
-
-      if self.counter == 0 {
-        self.status = false;
-      }
+  fn trigger(&mut self) {
+    self.status = self.envelope.dac_enabled();
+    self.envelope.trigger();
+  }
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.status = false;
     }
   }
 }
@@ -13,6 +13,8 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use super::length_counter::LengthCounter;
+
 #[derive(Clone, Copy)]
 enum Volume {
   None = 0,
@@ -40,8 +42,10 @@ pub struct Ch3 {
   enabled: bool,
   volume: Volume,
   freq_bits: u16,
-  use_counter: bool,
-  counter: usize,
+  timer: u16,
+  position: u8,
+  wave_ram_accessed: bool,
+  pub length: LengthCounter,
   pub status: bool,
 }
 
@@ -52,23 +56,61 @@ impl Ch3 {
       enabled: false,
       volume: Volume::None,
       freq_bits: 0,
-      use_counter: false,
-      counter: 0,
+      timer: 0,
+      position: 0,
+      wave_ram_accessed: false,
+      length: LengthCounter::new(256),
       status: false,
     }
   }
-  pub fn reset(&mut self) {
-    self.enabled = false;
-    self.volume = Volume::None;
-    self.use_counter = false;
-    self.counter = 0;
-    self.status = false;
+  pub fn power_off(&mut self) {
+    let mut length = self.length.clone();
+    length.power_off();
+    *self = Ch3 {
+      wave_ram: self.wave_ram,
+      length,
+      ..Ch3::new()
+    };
+  }
+  /// Ticks the wave channel for one machine cycle.
+  ///
+  /// The frequency timer is clocked at 2 MHz, so it is clocked twice per machine cycle.
+  pub fn tick_cycle(&mut self) {
+    self.wave_ram_accessed = false;
+    if !self.status {
+      return;
+    }
+    for _ in 0..2 {
+      self.timer -= 1;
+      if self.timer == 0 {
+        self.timer = self.period();
+        self.position = (self.position + 1) & 0x1f;
+        self.wave_ram_accessed = true;
+      }
+    }
   }
+  /// Reads wave RAM directly, ignoring access restrictions while the channel is playing
+  pub fn peek_wave_ram(&self, reladdr: u16) -> u8 {
+    self.wave_ram[reladdr as usize & 0x0f]
+  }
+  /// DMG: while the channel is playing, wave RAM accesses go to the byte the channel is
+  /// currently reading, and only work in the cycle the channel itself reads wave RAM.
+  /// Otherwise reads return 0xff and writes are ignored.
   pub fn read_wave_ram(&self, reladdr: u16) -> u8 {
-    self.wave_ram[reladdr as usize]
+    if !self.status {
+      self.wave_ram[reladdr as usize]
+    } else if self.wave_ram_accessed {
+      self.wave_ram[self.current_index()]
+    } else {
+      0xff
+    }
   }
   pub fn write_wave_ram(&mut self, reladdr: u16, value: u8) {
-    self.wave_ram[reladdr as usize] = value;
+    if !self.status {
+      self.wave_ram[reladdr as usize] = value;
+    } else if self.wave_ram_accessed {
+      self.wave_ram[self.current_index()] = value;
+    }
   }
   pub fn read_reg0(&self) -> u8 {
     const REG0_MASK: u8 = 0x7f;
@@ -77,9 +119,12 @@ impl Ch3 {
   }
   pub fn write_reg0(&mut self, value: u8) {
     self.enabled = value & (1 << 7) != 0;
+    if !self.enabled {
+      self.status = false;
+    }
   }
   pub fn write_reg1(&mut self, value: u8) {
-    self.counter = 256 - value as usize;
+    self.length.load(value);
   }
   pub fn read_reg2(&self) -> u8 {
     const REG2_MASK: u8 = 0x9f;
@@ -95,23 +140,130 @@ impl Ch3 {
   pub fn read_reg4(&self) -> u8 {
     const REG4_MASK: u8 = 0xbf;
 
-    REG4_MASK | if self.use_counter { 1 << 6 } else { 0 }
-  }
-  pub fn write_reg4(&mut self, value: u8) {
-    self.status = value & (1 << 7) != 0;
-    self.use_counter = value & (1 << 6) != 0;
-    self.freq_bits = (self.freq_bits & 0xff) | ((value as u16) << 8);
-    if self.status && self.counter == 0 {
-      self.counter = 256;
+    REG4_MASK | if self.length.is_enabled() { 1 << 6 } else { 0 }
+  }
+  pub fn write_reg4(&mut self, value: u8, extra_length_clock: bool) {
+    self.freq_bits = (self.freq_bits & 0xff) | (((value & 0x07) as u16) << 8);
+    if self.length.write_control(value, extra_length_clock) {
+      self.status = false;
+    }
+    if value & (1 << 7) != 0 {
+      self.trigger();
     }
   }
-  pub fn clock(&mut self) {
-    if self.use_counter && self.counter > 0 {
-      self.counter -= 1;
-
-      if self.counter == 0 {
-        self.status = false;
+  fn trigger(&mut self) {
+    if self.status && self.timer == 1 {
+      self.corrupt_wave_ram();
+    }
+    self.status = self.enabled;
+    self.position = 0;
+    // The first sample is read with a delay of 6 T-cycles (3 timer clocks)
+    self.timer = self.period() + 3;
+  }
+  /// DMG: retriggering the channel right when it's about to read wave RAM corrupts the
+  /// first bytes of wave RAM.
+  ///
+  /// If the byte about to be read is one of the first four, only the first byte is
+  /// overwritten with it. Otherwise the first four bytes are overwritten with the aligned
+  /// four-byte block containing it.
+  fn corrupt_wave_ram(&mut self) {
+    let index = (((self.position + 1) & 0x1f) >> 1) as usize;
+    if index < 4 {
+      self.wave_ram[0] = self.wave_ram[index];
+    } else {
+      let start = index & !0x03;
+      for i in 0..4 {
+        self.wave_ram[i] = self.wave_ram[start + i];
       }
     }
   }
+  fn current_index(&self) -> usize {
+    (self.position >> 1) as usize
+  }
+  fn period(&self) -> u16 {
+    2048 - self.freq_bits
+  }
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.status = false;
+    }
+  }
+}
+
+/// Returns a playing channel with a timer period of 4, so wave RAM is read every other cycle
+#[cfg(test)]
+fn playing_ch3() -> Ch3 {
+  let mut ch3 = Ch3::new();
+  for reladdr in 0..16 {
+    ch3.write_wave_ram(reladdr, reladdr as u8 * 0x11);
+  }
+  ch3.write_reg0(0x80);
+  ch3.write_reg3(0xfc);
+  ch3.write_reg4(0x87, false);
+  ch3
+}
+
+#[cfg(test)]
+fn tick_until(ch3: &mut Ch3, position: u8, timer: u16) {
+  while ch3.position != position || ch3.timer != timer {
+    ch3.tick_cycle();
+  }
+}
+
+#[test]
+fn test_wave_ram_access_window() {
+  let mut ch3 = playing_ch3();
+  // The first sample is read in the fourth cycle after the trigger
+  for _ in 0..3 {
+    ch3.tick_cycle();
+    assert_eq!(ch3.read_wave_ram(0x05), 0xff);
+  }
+  ch3.tick_cycle();
+  assert_eq!(ch3.position, 1);
+  assert_eq!(ch3.read_wave_ram(0x05), 0x00);
+  // Writes outside the access window are ignored
+  ch3.tick_cycle();
+  assert_eq!(ch3.read_wave_ram(0x05), 0xff);
+  ch3.write_wave_ram(0x05, 0xab);
+  // Accesses inside the window go to the byte the channel is reading, not the requested one
+  ch3.tick_cycle();
+  assert_eq!(ch3.position, 2);
+  ch3.write_wave_ram(0x05, 0xcd);
+  assert_eq!(ch3.read_wave_ram(0x0f), 0xcd);
+  assert_eq!(ch3.peek_wave_ram(0x01), 0xcd);
+  assert_eq!(ch3.peek_wave_ram(0x05), 0x55);
+
+  // Stopping the channel gives direct access again
+  ch3.write_reg0(0x00);
+  ch3.tick_cycle();
+  assert_eq!(ch3.read_wave_ram(0x05), 0x55);
+  ch3.write_wave_ram(0x05, 0xab);
+  assert_eq!(ch3.peek_wave_ram(0x05), 0xab);
+}
+
+#[test]
+fn test_retrigger_corruption() {
+  // About to read one of the first four bytes: only the first byte is overwritten
+  let mut ch3 = playing_ch3();
+  tick_until(&mut ch3, 3, 1);
+  ch3.write_reg4(0x87, false);
+  assert_eq!(ch3.peek_wave_ram(0x00), 0x22);
+  assert_eq!(ch3.peek_wave_ram(0x01), 0x11);
+
+  // About to read a later byte: the first four bytes are overwritten with its aligned block
+  let mut ch3 = playing_ch3();
+  tick_until(&mut ch3, 13, 1);
+  ch3.write_reg4(0x87, false);
+  for reladdr in 0..4 {
+    assert_eq!(ch3.peek_wave_ram(reladdr), (reladdr as u8 + 4) * 0x11);
+  }
+  assert_eq!(ch3.peek_wave_ram(0x07), 0x77);
+
+  // Retriggering at any other time doesn't corrupt wave RAM
+  let mut ch3 = playing_ch3();
+  tick_until(&mut ch3, 13, 3);
+  ch3.write_reg4(0x87, false);
+  for reladdr in 0..16 {
+    assert_eq!(ch3.peek_wave_ram(reladdr), reladdr as u8 * 0x11);
+  }
 }
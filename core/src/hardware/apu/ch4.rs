@@ -14,13 +14,13 @@
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
 use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
 
 #[derive(Clone)]
 pub struct Ch4 {
   pub envelope: Envelope,
+  pub length: LengthCounter,
   noise_opt: u8,
-  use_counter: bool,
-  counter: usize,
   pub status: bool,
 }
 
@@ -28,17 +28,27 @@ impl Ch4 {
   pub fn new() -> Ch4 {
     Ch4 {
       envelope: Envelope::new(),
+      length: LengthCounter::new(64),
       noise_opt: 0,
-      use_counter: false,
-      counter: 0,
       status: false,
     }
   }
-  pub fn reset(&mut self) {
-    *self = Ch4::new()
+  pub fn power_off(&mut self) {
+    let mut length = self.length.clone();
+    length.power_off();
+    *self = Ch4 {
+      length,
+      ..Ch4::new()
+    };
   }
   pub fn write_reg1(&mut self, value: u8) {
-    self.counter = 64 - (value & 0x3f) as usize;
+    self.length.load(value);
+  }
+  pub fn write_reg2(&mut self, value: u8) {
+    self.envelope.write_reg(value);
+    if !self.envelope.dac_enabled() {
+      self.status = false;
+    }
   }
   pub fn read_reg3(&self) -> u8 {
     self.noise_opt
@@ -49,22 +59,20 @@ impl Ch4 {
   pub fn read_reg4(&self) -> u8 {
     const REG4_MASK: u8 = 0xbf;
 
-    REG4_MASK | if self.use_counter { 1 << 6 } else { 0 }
+    REG4_MASK | if self.length.is_enabled() { 1 << 6 } else { 0 }
   }
-  pub fn write_reg4(&mut self, value: u8) {
-    self.status = value & (1 << 7) != 0;
-    self.use_counter = value & (1 << 6) != 0;
-    if self.status && self.counter == 0 {
-      self.counter = 64;
+  pub fn write_reg4(&mut self, value: u8, extra_length_clock: bool) {
+    if self.length.write_control(value, extra_length_clock) {
+      self.status = false;
+    }
+    if value & (1 << 7) != 0 {
+      self.status = self.envelope.dac_enabled();
+      self.envelope.trigger();
     }
   }
-  pub fn clock(&mut self) {
-    if self.use_counter && self.counter > 0 {
-      self.counter -= 1;
-
-      if self.counter == 0 {
-        self.status = false;
-      }
+  pub fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.status = false;
     }
   }
 }
@@ -18,6 +18,8 @@ pub struct Envelope {
   volume: u8,
   increasing: bool,
   length: u8,
+  current_volume: u8,
+  timer: u8,
 }
 
 impl Envelope {
@@ -26,6 +28,8 @@ impl Envelope {
       volume: 0,
       increasing: false,
       length: 0,
+      current_volume: 0,
+      timer: 0,
     }
   }
   pub fn read_reg(&self) -> u8 {
@@ -36,4 +40,35 @@ impl Envelope {
     self.increasing = value & (1 << 3) != 0;
     self.length = value & 0x07;
   }
+  /// The channel DAC is powered off if the upper 5 bits of NRx2 are all zero
+  pub fn dac_enabled(&self) -> bool {
+    self.volume != 0 || self.increasing
+  }
+  pub fn trigger(&mut self) {
+    self.current_volume = self.volume;
+    self.timer = self.period();
+  }
+  pub fn clock(&mut self) {
+    if self.length == 0 {
+      return;
+    }
+    if self.timer > 0 {
+      self.timer -= 1;
+    }
+    if self.timer == 0 {
+      self.timer = self.period();
+      if self.increasing && self.current_volume < 0x0f {
+        self.current_volume += 1;
+      } else if !self.increasing && self.current_volume > 0 {
+        self.current_volume -= 1;
+      }
+    }
+  }
+  fn period(&self) -> u8 {
+    if self.length == 0 {
+      8
+    } else {
+      self.length
+    }
+  }
 }
@@ -0,0 +1,76 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+#[derive(Clone)]
+pub struct LengthCounter {
+  enabled: bool,
+  counter: usize,
+  max: usize,
+}
+
+impl LengthCounter {
+  pub fn new(max: usize) -> LengthCounter {
+    LengthCounter {
+      enabled: false,
+      counter: 0,
+      max,
+    }
+  }
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+  pub fn load(&mut self, value: u8) {
+    self.counter = self.max - (value as usize & (self.max - 1));
+  }
+  /// Handles the length enable and trigger bits of a NRx4 write.
+  ///
+  /// If the next frame sequencer step doesn't clock length counters, enabling the counter
+  /// clocks it once immediately, and a trigger that reloads an expired counter loads one
+  /// less than the maximum value.
+  ///
+  /// Returns true if the extra clock expired the counter without a trigger, in which case
+  /// the channel is disabled.
+  pub fn write_control(&mut self, value: u8, extra_clock: bool) -> bool {
+    let was_enabled = self.enabled;
+    let trigger = value & (1 << 7) != 0;
+    self.enabled = value & (1 << 6) != 0;
+
+    let mut expired = false;
+    if extra_clock && !was_enabled && self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      expired = self.counter == 0 && !trigger;
+    }
+    if trigger && self.counter == 0 {
+      self.counter = self.max;
+      if extra_clock && self.enabled {
+        self.counter -= 1;
+      }
+    }
+    expired
+  }
+  /// Clocks the counter and returns true if it expired
+  pub fn clock(&mut self) -> bool {
+    if self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      self.counter == 0
+    } else {
+      false
+    }
+  }
+  /// DMG: length counters keep their value when the APU is powered off
+  pub fn power_off(&mut self) {
+    self.enabled = false;
+  }
+}
@@ -45,26 +45,84 @@ impl Time {
 #[derive(Clone)]
 pub struct Sweep {
   time: Time,
-  increasing: bool,
+  negate: bool,
   shift: u8,
+  enabled: bool,
+  timer: u8,
+  shadow_freq: u16,
+  negate_used: bool,
 }
 
 impl Sweep {
   pub fn new() -> Sweep {
     Sweep {
       time: Time::None,
-      increasing: false,
+      negate: false,
       shift: 0,
+      enabled: false,
+      timer: 0,
+      shadow_freq: 0,
+      negate_used: false,
     }
   }
   pub fn read_reg(&self) -> u8 {
     const MASK: u8 = 0x80;
 
-    MASK | ((self.time as u8) << 4) | if self.increasing { 1 << 3 } else { 0 } | (self.shift)
+    MASK | ((self.time as u8) << 4) | if self.negate { 1 << 3 } else { 0 } | (self.shift)
   }
-  pub fn write_reg(&mut self, value: u8) {
+  /// Returns false if the channel should be disabled.
+  ///
+  /// Switching from subtraction to addition after a subtraction has been calculated since
+  /// the last trigger disables the channel.
+  pub fn write_reg(&mut self, value: u8) -> bool {
     self.time = Time::from_u8((value >> 4) & 0x07).unwrap();
-    self.increasing = value & (1 << 3) != 0;
+    self.negate = value & (1 << 3) != 0;
     self.shift = value & 0x07;
+    !self.negate_used || self.negate
+  }
+  /// Returns false if the overflow check disables the channel
+  pub fn trigger(&mut self, freq_bits: u16) -> bool {
+    self.shadow_freq = freq_bits;
+    self.timer = self.period();
+    self.enabled = self.time as u8 != 0 || self.shift != 0;
+    self.negate_used = false;
+    self.shift == 0 || self.calculate() <= 0x7ff
+  }
+  /// Returns false if the overflow check disables the channel
+  pub fn clock(&mut self, freq_bits: &mut u16) -> bool {
+    if self.timer > 0 {
+      self.timer -= 1;
+    }
+    if self.timer == 0 {
+      self.timer = self.period();
+      if self.enabled && self.time as u8 != 0 {
+        let new_freq = self.calculate();
+        if new_freq > 0x7ff {
+          return false;
+        }
+        if self.shift != 0 {
+          self.shadow_freq = new_freq;
+          *freq_bits = new_freq;
+          // The new frequency is immediately checked again, but not written back
+          return self.calculate() <= 0x7ff;
+        }
+      }
+    }
+    true
+  }
+  fn calculate(&mut self) -> u16 {
+    let delta = self.shadow_freq >> self.shift;
+    if self.negate {
+      self.negate_used = true;
+      self.shadow_freq - delta
+    } else {
+      self.shadow_freq + delta
+    }
+  }
+  fn period(&self) -> u8 {
+    match self.time {
+      Time::None => 8,
+      time => time as u8,
+    }
   }
 }
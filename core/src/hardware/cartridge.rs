@@ -13,11 +13,30 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crate::camera::CameraImage;
+use crate::cheats::GameGenieCode;
 use crate::config;
 use crate::gameboy::{RAM_BANK_SIZE, ROM_BANK_SIZE};
 use crate::util::int::IntExt;
 use std::sync::Arc;
 
+use self::camera::CameraState;
+use self::flash::{Flash, AM29F016};
+use self::huc3::{Huc3Rtc, HUC3_RTC_SAVE_SIZE};
+use self::mbc6::{Mbc6State, Window, FLASH_SIZE};
+use self::mbc7::{Mbc7State, EEPROM_SIZE};
+use self::sachen::SachenState;
+use self::tama5::{Tama5State, TAMA5_RAM_SIZE, TAMA5_RTC_SAVE_SIZE};
+
+mod camera;
+mod flash;
+mod huc3;
+mod mbc6;
+mod mbc7;
+mod rtc;
+mod sachen;
+mod tama5;
+
 #[derive(Debug, Clone)]
 struct Mbc1State {
   ramg: bool,
@@ -98,6 +117,7 @@ struct Mbc5State {
   romb0: u8,
   romb1: u8,
   ramb: u8,
+  motor_on: bool,
 }
 
 impl Default for Mbc5State {
@@ -107,6 +127,7 @@ impl Default for Mbc5State {
       romb0: 0b0000_0001,
       romb1: 0b0,
       ramb: 0b0000,
+      motor_on: false,
     }
   }
 }
@@ -120,6 +141,97 @@ impl Mbc5State {
   }
 }
 
+#[derive(Debug, Clone, Default)]
+struct Mmm01State {
+  ramg: bool,
+  locked: bool,
+  rom_bank_low: u8,
+  rom_bank_mid: u8,
+  rom_bank_high: u8,
+  rom_bank_mask: u8,
+  ram_bank_low: u8,
+  ram_bank_high: u8,
+  ram_bank_mask: u8,
+  mbc1_mode: bool,
+  mbc1_mode_disable: bool,
+  multiplex: bool,
+}
+
+impl Mmm01State {
+  /// Handles a register write. Most of the configuration bits can only be written in menu mode,
+  /// before the mapping is locked
+  fn write(&mut self, reladdr: u16, value: u8) {
+    match reladdr >> 8 {
+      0x00..=0x1f => {
+        self.ramg = (value & 0b1111) == 0b1010;
+        if !self.locked {
+          self.ram_bank_mask = (value >> 4) & 0b11;
+          self.locked = value & (1 << 6) != 0;
+        }
+      }
+      0x20..=0x3f => {
+        // Masked bits keep their value, so the menu can restrict a game to a range of banks
+        let mask = self.rom_bank_mask << 1;
+        self.rom_bank_low = (self.rom_bank_low & mask) | (value & !mask & 0b1_1111);
+        if !self.locked {
+          self.rom_bank_mid = (value >> 5) & 0b11;
+        }
+      }
+      0x40..=0x5f => {
+        let mask = self.ram_bank_mask;
+        self.ram_bank_low = (self.ram_bank_low & mask) | (value & !mask & 0b11);
+        if !self.locked {
+          self.ram_bank_high = (value >> 2) & 0b11;
+          self.rom_bank_high = (value >> 4) & 0b11;
+          self.mbc1_mode_disable = value & (1 << 6) != 0;
+        }
+      }
+      0x60..=0x7f => {
+        if !self.mbc1_mode_disable {
+          self.mbc1_mode = value & 0b1 != 0;
+        }
+        if !self.locked {
+          self.rom_bank_mask = (value >> 2) & 0b1111;
+          self.multiplex = value & (1 << 6) != 0;
+        }
+      }
+      _ => (),
+    }
+  }
+  fn rom_offsets(&self, rom_len: usize) -> (usize, usize) {
+    if !self.locked {
+      // Menu mode: the last 32 KiB of the ROM is mapped
+      let end = rom_len.next_power_of_two();
+      return (end - 2 * ROM_BANK_SIZE, end - ROM_BANK_SIZE);
+    }
+    let mid = if self.multiplex {
+      self.ram_bank_low
+    } else {
+      self.rom_bank_mid
+    };
+    let upper_bits = (mid as usize) << 5 | (self.rom_bank_high as usize) << 7;
+    let mask = (self.rom_bank_mask << 1) as usize;
+    let lower_bank = upper_bits | (self.rom_bank_low as usize & mask);
+    let upper_low = if self.rom_bank_low & !(mask as u8) & 0b1_1111 == 0 {
+      self.rom_bank_low | 0b1
+    } else {
+      self.rom_bank_low
+    };
+    let upper_bank = upper_bits | upper_low as usize;
+    (ROM_BANK_SIZE * lower_bank, ROM_BANK_SIZE * upper_bank)
+  }
+  fn ram_offset(&self) -> usize {
+    let low = if self.multiplex {
+      self.rom_bank_mid
+    } else if self.mbc1_mode {
+      self.ram_bank_low
+    } else {
+      0b00
+    };
+    RAM_BANK_SIZE * (low | self.ram_bank_high << 2) as usize
+  }
+}
+
 #[derive(Debug, Clone)]
 struct Huc1State {
   mode: u8,
@@ -137,14 +249,63 @@ impl Default for Huc1State {
   }
 }
 
+#[derive(Debug, Clone, Default)]
+struct Huc3State {
+  mode: u8,
+  rom_bank: u8,
+  ram_bank: u8,
+  ir_led: bool,
+  rtc: Huc3Rtc,
+}
+
 #[derive(Debug, Clone)]
 enum Mbc {
   None,
-  Mbc1 { state: Mbc1State, multicart: bool },
-  Mbc2 { state: Mbc2State },
-  Mbc3 { state: Mbc3State, mbc30: bool },
-  Mbc5 { state: Mbc5State },
-  Huc1 { state: Huc1State },
+  Mbc1 {
+    state: Mbc1State,
+    multicart: bool,
+  },
+  Mbc2 {
+    state: Mbc2State,
+  },
+  Mbc3 {
+    state: Mbc3State,
+    mbc30: bool,
+  },
+  Mbc5 {
+    state: Mbc5State,
+    rumble: bool,
+    /// Homebrew flash cart, where the ROM is a writable flash chip
+    flash: Option<Box<Flash>>,
+  },
+  Mmm01 {
+    state: Mmm01State,
+  },
+  Huc1 {
+    state: Huc1State,
+  },
+  Huc3 {
+    state: Huc3State,
+  },
+  Mbc6 {
+    state: Box<Mbc6State>,
+  },
+  Mbc7 {
+    state: Box<Mbc7State>,
+  },
+  PocketCamera {
+    state: Box<CameraState>,
+  },
+  Tama5 {
+    state: Tama5State,
+  },
+  WisdomTree,
+  Sachen {
+    state: SachenState,
+  },
+  RocketGames {
+    rom_bank: u8,
+  },
 }
 
 impl Mbc {
@@ -163,13 +324,41 @@ impl Mbc {
         mbc30: config.ram_size.as_usize() > 65536,
         state: Mbc3State::default(),
       },
-      Mbc5 { .. } => Mbc::Mbc5 {
+      Mbc5 { rumble, .. } => Mbc::Mbc5 {
+        rumble,
+        flash: config.flash_path.as_ref().map(|_| {
+          let mut data = config.data.to_vec();
+          data.resize(data.len().next_power_of_two(), 0xff);
+          Box::new(Flash::new(AM29F016, data.into_boxed_slice()))
+        }),
         state: Mbc5State::default(),
       },
       Huc1 { .. } => Mbc::Huc1 {
         state: Huc1State::default(),
       },
-      _ => panic!("Unsupported cartridge type {:?}", config.cartridge_type),
+      Huc3 => Mbc::Huc3 {
+        state: Huc3State::default(),
+      },
+      Mmm01 { .. } => Mbc::Mmm01 {
+        state: Mmm01State::default(),
+      },
+      Mbc6 => Mbc::Mbc6 {
+        state: Box::new(Mbc6State::default()),
+      },
+      Mbc7 => Mbc::Mbc7 {
+        state: Box::new(Mbc7State::default()),
+      },
+      PocketCamera => Mbc::PocketCamera {
+        state: Box::new(CameraState::default()),
+      },
+      Tama5 => Mbc::Tama5 {
+        state: Tama5State::default(),
+      },
+      WisdomTree => Mbc::WisdomTree,
+      Sachen { .. } => Mbc::Sachen {
+        state: SachenState::new(),
+      },
+      RocketGames => Mbc::RocketGames { rom_bank: 0x01 },
     }
   }
 }
@@ -178,9 +367,12 @@ impl Mbc {
 pub struct Cartridge {
   mbc: Mbc,
   rom: Arc<[u8]>,
+  rom_mask: usize,
   rom_offsets: (usize, usize),
   ram: Box<[u8]>,
   ram_offset: usize,
+  battery: bool,
+  game_genie_codes: Vec<GameGenieCode>,
 }
 
 impl Cartridge {
@@ -188,24 +380,194 @@ impl Cartridge {
     let mbc = Mbc::from_config(&config);
     let ram_size = match mbc {
       Mbc::Mbc2 { .. } => 512,
+      Mbc::Tama5 { .. } => TAMA5_RAM_SIZE,
       _ => config.ram_size.as_usize(),
     };
+    let rom_mask = config.data.len().next_power_of_two() - 1;
+    let battery = config.cartridge_type.has_battery();
+    let rom_offsets = match mbc {
+      Mbc::Mmm01 { ref state } => state.rom_offsets(config.data.len()),
+      _ => (0x0000, 0x4000),
+    };
     Cartridge {
       mbc,
       rom: config.data,
-      rom_offsets: (0x0000, 0x4000),
+      rom_mask,
+      rom_offsets,
       ram: vec![0; ram_size].into_boxed_slice(),
       ram_offset: 0x0000,
+      battery,
+      game_genie_codes: Vec::new(),
+    }
+  }
+  /// Returns the battery-backed data of the cartridge: save RAM followed by any mapper-specific
+  /// state, such as an RTC
+  pub fn save_data(&self) -> Option<Vec<u8>> {
+    if !self.battery {
+      return None;
+    }
+    let mut data = self.ram.to_vec();
+    match self.mbc {
+      Mbc::Huc3 { ref state } => data.extend_from_slice(&state.rtc.save()),
+      Mbc::Tama5 { ref state } => data.extend_from_slice(&state.rtc.save()),
+      Mbc::Mbc6 { ref state } => data.extend_from_slice(&state.flash.data),
+      Mbc::Mbc7 { ref state } => data.extend_from_slice(&state.eeprom.data),
+      _ => (),
+    }
+    Some(data)
+  }
+  /// Restores data returned by `save_data`
+  pub fn load_save_data(&mut self, data: &[u8]) {
+    if !self.battery {
+      return;
+    }
+    let ram_len = self.ram.len().min(data.len());
+    self.ram[..ram_len].copy_from_slice(&data[..ram_len]);
+    let extra = &data[ram_len..];
+    match self.mbc {
+      Mbc::Huc3 { ref mut state } if extra.len() >= HUC3_RTC_SAVE_SIZE => state.rtc.load(extra),
+      Mbc::Tama5 { ref mut state } if extra.len() >= TAMA5_RTC_SAVE_SIZE => state.rtc.load(extra),
+      Mbc::Mbc6 { ref mut state } if extra.len() >= FLASH_SIZE => {
+        state.flash.data.copy_from_slice(&extra[..FLASH_SIZE])
+      }
+      Mbc::Mbc7 { ref mut state } if extra.len() >= EEPROM_SIZE => {
+        state.eeprom.data.copy_from_slice(&extra[..EEPROM_SIZE])
+      }
+      _ => (),
+    }
+  }
+  /// Sets the accelerometer input of MBC7 cartridges
+  pub fn set_tilt(&mut self, x: f32, y: f32) {
+    if let Mbc::Mbc7 { ref mut state } = self.mbc {
+      state.set_tilt(x, y);
+    }
+  }
+  /// Returns the ROM data of flash carts, including any changes made by the program
+  pub fn flash_data(&self) -> Option<&[u8]> {
+    match self.mbc {
+      Mbc::Mbc5 {
+        flash: Some(ref flash),
+        ..
+      } => Some(&flash.data),
+      _ => None,
+    }
+  }
+  /// Returns true if the rumble motor is on
+  pub fn motor_on(&self) -> bool {
+    match self.mbc {
+      Mbc::Mbc5 { ref state, .. } => state.motor_on,
+      _ => false,
+    }
+  }
+  /// Advances cartridge hardware that runs on its own clock, such as an RTC
+  pub fn tick_cycle(&mut self) {
+    match self.mbc {
+      Mbc::Huc3 { ref mut state } => state.rtc.tick_cycle(),
+      Mbc::Tama5 { ref mut state } => state.rtc.tick_cycle(),
+      _ => (),
+    }
+  }
+  /// Notifies the cartridge that the boot ROM has been disabled
+  pub fn bootrom_disabled(&mut self) {
+    if let Mbc::Sachen { ref mut state } = self.mbc {
+      state.locked = false;
+    }
+  }
+  /// Sets the images seen by the Pocket Camera sensor
+  pub fn set_camera_frames(&mut self, frames: Vec<CameraImage>) {
+    if let Mbc::PocketCamera { ref mut state } = self.mbc {
+      state.set_frames(frames);
     }
   }
 
+  /// Returns the ROM or RAM bank currently mapped at an address, or None if the address isn't
+  /// banked cartridge memory
+  pub fn bank_at(&self, addr: u16) -> Option<usize> {
+    let (rom_lower, rom_upper) = self.rom_offsets;
+    match addr >> 8 {
+      0x00..=0x3f => Some(self.rom_addr(rom_lower) / ROM_BANK_SIZE),
+      0x40..=0x7f => Some(self.rom_addr(rom_upper) / ROM_BANK_SIZE),
+      0xa0..=0xbf if !self.ram.is_empty() => Some(self.ram_addr(addr) / RAM_BANK_SIZE),
+      _ => None,
+    }
+  }
+  /// Returns a 16 KiB ROM bank, or None if the ROM doesn't have that bank
+  pub fn rom_bank(&self, bank: usize) -> Option<&[u8]> {
+    let data = self.flash_data().unwrap_or(&self.rom);
+    data.get(bank * ROM_BANK_SIZE..(bank + 1) * ROM_BANK_SIZE)
+  }
+  /// Returns a 8 KiB RAM bank, or None if the RAM doesn't have that bank. Cartridges with less
+  /// than 8 KiB of RAM have a single smaller bank
+  pub fn ram_bank(&self, bank: usize) -> Option<&[u8]> {
+    let start = bank * RAM_BANK_SIZE;
+    let end = (start + RAM_BANK_SIZE).min(self.ram.len());
+    self.ram.get(start..end).filter(|bank| !bank.is_empty())
+  }
+  pub fn set_game_genie_codes(&mut self, codes: Vec<GameGenieCode>) {
+    self.game_genie_codes = codes;
+  }
+  /// Writes to cartridge RAM without going through the mapper. `bank` selects the RAM bank, or the
+  /// currently mapped bank if None
+  pub fn poke_ram(&mut self, bank: Option<u8>, addr: u16, value: u8) {
+    if self.ram.is_empty() {
+      return;
+    }
+    let offset = match bank {
+      Some(bank) => (RAM_BANK_SIZE * bank as usize) | (addr as usize & 0x1fff),
+      None => self.ram_offset | (addr as usize & 0x1fff),
+    };
+    let len = self.ram.len();
+    self.ram[offset % len] = value;
+  }
+  /// Applies Game Genie codes to a ROM read
+  fn apply_game_genie(&self, addr: u16, value: u8) -> u8 {
+    self
+      .game_genie_codes
+      .iter()
+      .find_map(|code| code.apply(addr, value))
+      .unwrap_or(value)
+  }
   pub fn read_0000_3fff(&self, addr: u16) -> u8 {
-    let (rom_lower, _) = self.rom_offsets;
-    self.rom[(rom_lower | (addr as usize & 0x3fff)) & (self.rom.len() - 1)]
+    let value = self.read_rom_0000_3fff(addr);
+    self.apply_game_genie(addr, value)
   }
   pub fn read_4000_7fff(&self, addr: u16) -> u8 {
-    let (_, rom_upper) = self.rom_offsets;
-    self.rom[(rom_upper | (addr as usize & 0x3fff)) & (self.rom.len() - 1)]
+    let value = self.read_rom_4000_7fff(addr);
+    self.apply_game_genie(addr, value)
+  }
+  fn read_rom_0000_3fff(&self, addr: u16) -> u8 {
+    let addr = match self.mbc {
+      Mbc::Sachen { ref state } => state.swizzle(addr),
+      _ => addr,
+    };
+    let (rom_lower, _) = self.rom_offsets;
+    let offset = rom_lower | (addr as usize & 0x3fff);
+    match self.mbc {
+      Mbc::Mbc5 {
+        flash: Some(ref flash),
+        ..
+      } => flash.read(offset),
+      _ => self.rom[self.rom_addr(offset)],
+    }
+  }
+  fn read_rom_4000_7fff(&self, addr: u16) -> u8 {
+    match self.mbc {
+      Mbc::Mbc5 {
+        flash: Some(ref flash),
+        ..
+      } => {
+        let (_, rom_upper) = self.rom_offsets;
+        flash.read(rom_upper | (addr as usize & 0x3fff))
+      }
+      Mbc::Mbc6 { ref state } => match state.window(addr) {
+        Window::Rom(offset) => self.rom[self.rom_addr(offset)],
+        Window::Flash(offset) => state.flash.read(offset),
+      },
+      _ => {
+        let (_, rom_upper) = self.rom_offsets;
+        self.rom[self.rom_addr(rom_upper | (addr as usize & 0x3fff))]
+      }
+    }
   }
   pub fn write_control(&mut self, reladdr: u16, value: u8) {
     match self.mbc {
@@ -272,30 +634,66 @@ impl Cartridge {
         }
         _ => (),
       },
-      Mbc::Mbc5 { ref mut state } => match reladdr >> 8 {
-        0x00..=0x1f => {
-          state.ramg = value == 0x0a;
+      Mbc::Mbc5 {
+        ref mut state,
+        rumble,
+        ref mut flash,
+      } => {
+        // Flash cart writes go to both the MBC and the flash chip
+        if let Some(flash) = flash {
+          let (rom_lower, rom_upper) = self.rom_offsets;
+          let bank_offset = if reladdr < 0x4000 {
+            rom_lower
+          } else {
+            rom_upper
+          };
+          flash.write(bank_offset | (reladdr as usize & 0x3fff), value, true);
         }
-        0x20..=0x2f => {
-          state.romb0 = value;
-          self.rom_offsets = state.rom_offsets();
+        match reladdr >> 8 {
+          0x00..=0x1f => {
+            state.ramg = value == 0x0a;
+          }
+          0x20..=0x2f => {
+            state.romb0 = value;
+            self.rom_offsets = state.rom_offsets();
+          }
+          0x30..=0x3f => {
+            state.romb1 = value & 0b1;
+            self.rom_offsets = state.rom_offsets();
+          }
+          0x40..=0x5f if rumble => {
+            // Bit 3 drives the rumble motor instead of a RAM bank line
+            state.motor_on = value & 0b1000 != 0;
+            state.ramb = value & 0b0111;
+            self.ram_offset = RAM_BANK_SIZE * state.ramb as usize;
+          }
+          0x40..=0x5f => {
+            state.ramb = value & 0b1111;
+            self.ram_offset = RAM_BANK_SIZE * state.ramb as usize;
+          }
+          _ => (),
+        }
+      }
+      Mbc::Huc1 { ref mut state } => match reladdr >> 8 {
+        0x00..=0x1f => {
+          state.mode = value & 0xf;
         }
-        0x30..=0x3f => {
-          state.romb1 = value & 0b1;
-          self.rom_offsets = state.rom_offsets();
+        0x20..=0x3f => {
+          state.rom_bank = value & 0b11_1111;
+          self.rom_offsets = (0x0000, ROM_BANK_SIZE * state.rom_bank as usize);
         }
         0x40..=0x5f => {
-          state.ramb = value & 0b1111;
-          self.ram_offset = RAM_BANK_SIZE * state.ramb as usize;
+          state.ram_bank = value & 0b11;
+          self.ram_offset = RAM_BANK_SIZE * state.ram_bank as usize;
         }
         _ => (),
       },
-      Mbc::Huc1 { ref mut state } => match reladdr >> 8 {
+      Mbc::Huc3 { ref mut state } => match reladdr >> 8 {
         0x00..=0x1f => {
           state.mode = value & 0xf;
         }
         0x20..=0x3f => {
-          state.rom_bank = value & 0b11_1111;
+          state.rom_bank = value & 0b111_1111;
           self.rom_offsets = (0x0000, ROM_BANK_SIZE * state.rom_bank as usize);
         }
         0x40..=0x5f => {
@@ -304,6 +702,59 @@ impl Cartridge {
         }
         _ => (),
       },
+      Mbc::Mmm01 { ref mut state } => {
+        state.write(reladdr, value);
+        self.rom_offsets = state.rom_offsets(self.rom.len());
+        self.ram_offset = state.ram_offset();
+      }
+      Mbc::Mbc6 { ref mut state } => state.write_control(reladdr, value),
+      Mbc::Mbc7 { ref mut state } => match reladdr >> 8 {
+        0x00..=0x1f => {
+          state.ramg1 = value == 0x0a;
+        }
+        0x20..=0x3f => {
+          state.rom_bank = value & 0b111_1111;
+          self.rom_offsets = (0x0000, ROM_BANK_SIZE * state.rom_bank as usize);
+        }
+        0x40..=0x5f => {
+          state.ramg2 = value == 0x40;
+        }
+        _ => (),
+      },
+      Mbc::PocketCamera { ref mut state } => match reladdr >> 8 {
+        0x00..=0x1f => {
+          state.ramg = (value & 0x0f) == 0x0a;
+        }
+        0x20..=0x3f => {
+          state.rom_bank = value & 0b11_1111;
+          self.rom_offsets = (0x0000, ROM_BANK_SIZE * state.rom_bank as usize);
+        }
+        0x40..=0x5f => {
+          state.regs_mapped = value & 0x10 != 0;
+          state.ram_bank = value & 0b1111;
+          self.ram_offset = RAM_BANK_SIZE * state.ram_bank as usize;
+        }
+        _ => (),
+      },
+      // TAMA5 is controlled only through $A000-$A001
+      Mbc::Tama5 { .. } => (),
+      Mbc::WisdomTree => {
+        // The 32 KiB bank is selected by the low address bits, and the value is ignored
+        if reladdr < 0x4000 {
+          let bank = (reladdr & 0xff) as usize;
+          self.rom_offsets = (bank * 0x8000, bank * 0x8000 + ROM_BANK_SIZE);
+        }
+      }
+      Mbc::Sachen { ref mut state } => {
+        state.write(reladdr, value);
+        self.rom_offsets = state.rom_offsets();
+      }
+      Mbc::RocketGames { ref mut rom_bank } => {
+        if let 0x20..=0x3f = reladdr >> 8 {
+          *rom_bank = if value == 0 { 1 } else { value };
+          self.rom_offsets = (0x0000, ROM_BANK_SIZE * *rom_bank as usize);
+        }
+      }
     }
   }
   pub fn read_a000_bfff(&self, addr: u16, default_value: u8) -> u8 {
@@ -317,10 +768,28 @@ impl Cartridge {
         0x04..=0x07 if mbc30 => self.read_ram(addr, default_value),
         _ => default_value,
       },
-      Mbc::Mbc5 { ref state } if state.ramg => self.read_ram(addr, default_value),
+      Mbc::Mbc5 { ref state, .. } if state.ramg => self.read_ram(addr, default_value),
+      Mbc::Mmm01 { ref state } if state.ramg => self.read_ram(addr, default_value),
       Mbc::Huc1 { ref state } if state.mode == 0x00 || state.mode == 0x0a => {
         self.read_ram(addr, default_value)
       }
+      Mbc::Huc3 { ref state } => match state.mode {
+        0x00 | 0x0a => self.read_ram(addr, default_value),
+        0x0c => state.rtc.read_response(),
+        // RTC semaphore: commands complete immediately, so the RTC is always ready
+        0x0d => 0xfe | 0x01,
+        // IR receiver: no light received
+        0x0e => 0xc0,
+        _ => default_value,
+      },
+      Mbc::Mbc6 { ref state } if state.ramg && !self.ram.is_empty() => {
+        self.ram[state.ram_offset(addr) & (self.ram.len() - 1)]
+      }
+      Mbc::Mbc7 { ref state } if state.ramg() && addr < 0xb000 => state.read_reg(addr),
+      Mbc::Tama5 { ref state } if addr < 0xa002 => state.read(addr, &self.ram),
+      Mbc::PocketCamera { ref state } if state.regs_mapped => state.read_reg(addr),
+      // Camera RAM is readable even when writes are disabled
+      Mbc::PocketCamera { .. } => self.read_ram(addr, default_value),
       _ => default_value,
     }
   }
@@ -333,11 +802,43 @@ impl Cartridge {
         0x04..=0x07 if mbc30 => self.write_ram(addr, value),
         _ => (),
       },
-      Mbc::Mbc5 { ref state } if state.ramg => self.write_ram(addr, value),
+      Mbc::Mbc5 { ref state, .. } if state.ramg => self.write_ram(addr, value),
+      Mbc::Mmm01 { ref state } if state.ramg => self.write_ram(addr, value),
       Mbc::Huc1 { ref state } if state.mode == 0x0a => self.write_ram(addr, value),
+      Mbc::Huc3 { ref mut state } => match state.mode {
+        0x0a => self.write_ram(addr, value),
+        0x0b => state.rtc.write_command(value),
+        0x0e => state.ir_led = value & 0b1 != 0,
+        _ => (),
+      },
+      Mbc::Mbc6 { ref state } if state.ramg && !self.ram.is_empty() => {
+        let offset = state.ram_offset(addr) & (self.ram.len() - 1);
+        self.ram[offset] = value;
+      }
+      Mbc::Mbc7 { ref mut state } if state.ramg() && addr < 0xb000 => state.write_reg(addr, value),
+      Mbc::Tama5 { ref mut state } if addr < 0xa002 => {
+        state.write(addr, value, &mut self.ram);
+        self.rom_offsets = (0x0000, ROM_BANK_SIZE * state.rom_bank() as usize);
+      }
+      Mbc::PocketCamera { ref mut state } if state.regs_mapped => {
+        state.write_reg(addr, value, &mut self.ram)
+      }
+      Mbc::PocketCamera { ref state } if state.ramg => self.write_ram(addr, value),
       _ => (),
     }
   }
+  /// Maps a ROM offset to the ROM data.
+  ///
+  /// Offsets wrap around at the next power of two, like unconnected address lines. ROMs with a
+  /// non-power-of-two size mirror the remaining banks over the unpopulated area
+  fn rom_addr(&self, offset: usize) -> usize {
+    let addr = offset & self.rom_mask;
+    if addr < self.rom.len() {
+      addr
+    } else {
+      addr % self.rom.len()
+    }
+  }
   fn ram_addr(&self, addr: u16) -> usize {
     (self.ram_offset | (addr as usize & 0x1fff)) & (self.ram.len() - 1)
   }
@@ -356,3 +857,377 @@ impl Cartridge {
     }
   }
 }
+
+/// Returns a cartridge where every ROM byte is the number of its 16 KiB bank
+#[cfg(test)]
+fn test_cartridge(
+  cartridge_type: config::CartridgeType,
+  rom_banks: usize,
+  ram_size: config::CartridgeRamSize,
+) -> Cartridge {
+  let data = (0..rom_banks * ROM_BANK_SIZE)
+    .map(|offset| (offset / ROM_BANK_SIZE) as u8)
+    .collect::<Vec<_>>();
+  Cartridge::new(config::Cartridge {
+    data: data.into(),
+    title: String::new(),
+    cartridge_type,
+    rom_size: config::CartridgeRomSize::NoRomBanks,
+    ram_size,
+    header: None,
+    rom_info: None,
+    save_path: None,
+    flash_path: None,
+  })
+}
+
+/// Sends a command to the HuC3 RTC and returns the response
+#[cfg(test)]
+fn huc3_command(cartridge: &mut Cartridge, command: u8) -> u8 {
+  cartridge.write_control(0x0000, 0x0b);
+  cartridge.write_a000_bfff(0xa000, command);
+  cartridge.write_control(0x0000, 0x0c);
+  cartridge.read_a000_bfff(0xa000, 0xff)
+}
+
+#[test]
+fn test_huc3_ram() {
+  use config::CartridgeRamSize;
+  let mut cartridge = test_cartridge(config::CartridgeType::Huc3, 8, CartridgeRamSize::Ram32K);
+  cartridge.write_control(0x2000, 0x05);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 0x05);
+  cartridge.write_control(0x4000, 0x02);
+  cartridge.write_control(0x0000, 0x0a);
+  cartridge.write_a000_bfff(0xa123, 0x42);
+  assert_eq!(cartridge.read_a000_bfff(0xa123, 0xff), 0x42);
+  assert_eq!(cartridge.ram[2 * RAM_BANK_SIZE + 0x123], 0x42);
+  // Mode 0 maps RAM read-only
+  cartridge.write_control(0x0000, 0x00);
+  cartridge.write_a000_bfff(0xa123, 0x24);
+  assert_eq!(cartridge.read_a000_bfff(0xa123, 0xff), 0x42);
+  // Other modes don't map RAM
+  cartridge.write_control(0x0000, 0x0b);
+  cartridge.write_a000_bfff(0xa123, 0x24);
+  cartridge.write_control(0x0000, 0x0a);
+  assert_eq!(cartridge.read_a000_bfff(0xa123, 0xff), 0x42);
+}
+
+#[test]
+fn test_huc3_rtc() {
+  use config::CartridgeRamSize;
+  let mut cartridge = test_cartridge(config::CartridgeType::Huc3, 8, CartridgeRamSize::Ram32K);
+  // RTC semaphore: always ready
+  cartridge.write_control(0x0000, 0x0d);
+  assert_eq!(cartridge.read_a000_bfff(0xa000, 0x00), 0xff);
+  assert_eq!(huc3_command(&mut cartridge, 0x62), 0xe1);
+  // Write 1439 minutes (0x59f) and 0x1234 days starting from index 0
+  huc3_command(&mut cartridge, 0x40);
+  huc3_command(&mut cartridge, 0x50);
+  for &nibble in &[0xf, 0x9, 0x5, 0x4, 0x3, 0x2, 0x1] {
+    assert_eq!(huc3_command(&mut cartridge, 0x30 | nibble) & 0xf0, 0xb0);
+  }
+  let read_time = |cartridge: &mut Cartridge| {
+    huc3_command(cartridge, 0x40);
+    huc3_command(cartridge, 0x50);
+    (0..7).fold(0u32, |acc, i| {
+      let response = huc3_command(cartridge, 0x10);
+      assert_eq!(response & 0xf0, 0x90);
+      acc | u32::from(response & 0x0f) << (i * 4)
+    })
+  };
+  assert_eq!(read_time(&mut cartridge), 0x0123_459f);
+
+  // The clock runs on emulated time: the next minute is the next day
+  for _ in 0..60 * rtc::MACHINE_CYCLES_PER_SECOND {
+    cartridge.tick_cycle();
+  }
+  assert_eq!(read_time(&mut cartridge), 0x0123_5000);
+
+  // Save data round-trip
+  cartridge.write_control(0x0000, 0x0a);
+  cartridge.write_a000_bfff(0xa000, 0x42);
+  let data = cartridge.save_data().unwrap();
+  assert_eq!(data.len(), 0x8000 + HUC3_RTC_SAVE_SIZE);
+  let mut loaded = test_cartridge(config::CartridgeType::Huc3, 8, CartridgeRamSize::Ram32K);
+  loaded.load_save_data(&data);
+  loaded.write_control(0x0000, 0x0a);
+  assert_eq!(loaded.read_a000_bfff(0xa000, 0xff), 0x42);
+  assert_eq!(read_time(&mut loaded), 0x0123_5000);
+}
+
+#[test]
+fn test_huc3_ir() {
+  use config::CartridgeRamSize;
+  let mut cartridge = test_cartridge(config::CartridgeType::Huc3, 8, CartridgeRamSize::Ram32K);
+  cartridge.write_control(0x0000, 0x0e);
+  // No light is ever received
+  assert_eq!(cartridge.read_a000_bfff(0xa000, 0xff), 0xc0);
+  cartridge.write_a000_bfff(0xa000, 0x01);
+  let ir_led = |cartridge: &Cartridge| match cartridge.mbc {
+    Mbc::Huc3 { ref state } => state.ir_led,
+    _ => unreachable!(),
+  };
+  assert!(ir_led(&cartridge));
+  cartridge.write_a000_bfff(0xa000, 0x00);
+  assert!(!ir_led(&cartridge));
+}
+
+#[test]
+fn test_rom_mirroring() {
+  // 1.5 MiB ROMs aren't a power of two, so the unmapped upper banks mirror the lower ones
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::Mbc5 {
+      ram: false,
+      battery: false,
+      rumble: false,
+    },
+    96,
+    config::CartridgeRamSize::NoRam,
+  );
+  let mut read_bank = |bank: u16| {
+    cartridge.write_control(0x2000, bank as u8);
+    cartridge.write_control(0x3000, (bank >> 8) as u8);
+    cartridge.read_4000_7fff(0x4000)
+  };
+  assert_eq!(read_bank(1), 1);
+  assert_eq!(read_bank(95), 95);
+  assert_eq!(read_bank(96), 0);
+  assert_eq!(read_bank(100), 4);
+  assert_eq!(read_bank(127), 31);
+  assert_eq!(read_bank(200), 72);
+  assert_eq!(read_bank(0x1ff), 31);
+}
+
+#[test]
+fn test_camera_capture() {
+  use config::CartridgeRamSize;
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::PocketCamera,
+    8,
+    CartridgeRamSize::Ram128K,
+  );
+  cartridge.set_camera_frames(vec![CameraImage::uniform(0x90)]);
+  cartridge.write_control(0x0000, 0x0a);
+  cartridge.write_a000_bfff(0xa002, 0x42);
+
+  // Bank $10 maps the sensor registers over RAM
+  cartridge.write_control(0x4000, 0x10);
+  // Exposure 0x1000 passes pixel values through unchanged
+  cartridge.write_a000_bfff(0xa002, 0x10);
+  cartridge.write_a000_bfff(0xa003, 0x00);
+  for position in 0..16 {
+    let base = 0xa006 + position * 3;
+    // Dither position (1, 0) makes everything black
+    let thresholds = if position == 1 {
+      [0xff, 0xff, 0xff]
+    } else {
+      [0x40, 0x80, 0xc0]
+    };
+    for (i, &threshold) in thresholds.iter().enumerate() {
+      cartridge.write_a000_bfff(base + i as u16, threshold);
+    }
+  }
+  assert_eq!(cartridge.read_a000_bfff(0xa002, 0xff), 0x00);
+  cartridge.write_a000_bfff(0xa000, 0x01);
+  // The capture finishes immediately
+  assert_eq!(cartridge.read_a000_bfff(0xa000, 0xff), 0x00);
+
+  cartridge.write_control(0x4000, 0x00);
+  assert_eq!(cartridge.read_a000_bfff(0xa002, 0xff), 0x42);
+  // Tile rows at $A100 hold the low and high bits of each colour. 0x90 is colour 1, except
+  // for the black pixels at x = 1 and x = 5 of every fourth row
+  assert_eq!(cartridge.read_a000_bfff(0xa100, 0xff), 0xff);
+  assert_eq!(cartridge.read_a000_bfff(0xa101, 0xff), 0x44);
+  assert_eq!(cartridge.read_a000_bfff(0xa102, 0xff), 0xff);
+  assert_eq!(cartridge.read_a000_bfff(0xa103, 0xff), 0x00);
+  assert_eq!(cartridge.read_a000_bfff(0xa108, 0xff), 0xff);
+  assert_eq!(cartridge.read_a000_bfff(0xa109, 0xff), 0x44);
+  // Last tile of the image
+  let last = 0xa100 + 16 * 14 * 16 - 16;
+  assert_eq!(cartridge.read_a000_bfff(last, 0xff), 0xff);
+  assert_eq!(cartridge.read_a000_bfff(last + 1, 0xff), 0x44);
+  assert_eq!(cartridge.read_a000_bfff(last + 16, 0xff), 0x00);
+}
+
+#[test]
+fn test_sachen_logo_swizzle() {
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::Sachen { mmc2: false },
+    4,
+    config::CartridgeRamSize::NoRam,
+  );
+  let mut data = cartridge.rom.to_vec();
+  data[0x0100..0x0150]
+    .iter_mut()
+    .for_each(|value| *value = 0xaa);
+  data[0x0180..0x01d0]
+    .iter_mut()
+    .for_each(|value| *value = 0x55);
+  cartridge.rom = data.into();
+
+  // While locked, A7 is forced high at $0100-$014F so the boot ROM sees the real logo
+  assert_eq!(cartridge.read_0000_3fff(0x0104), 0x55);
+  assert_eq!(cartridge.read_0000_3fff(0x014f), 0x55);
+  assert_eq!(cartridge.read_0000_3fff(0x0150), 0x00);
+  assert_eq!(cartridge.read_0000_3fff(0x0184), 0x55);
+  cartridge.bootrom_disabled();
+  assert_eq!(cartridge.read_0000_3fff(0x0104), 0xaa);
+  assert_eq!(cartridge.read_0000_3fff(0x014f), 0xaa);
+  assert_eq!(cartridge.read_0000_3fff(0x0184), 0x55);
+}
+
+#[test]
+fn test_sachen_banking() {
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::Sachen { mmc2: true },
+    64,
+    config::CartridgeRamSize::NoRam,
+  );
+  cartridge.bootrom_disabled();
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 1);
+  // The outer bank registers ignore writes unless ROM bank bits 4-5 are set
+  cartridge.write_control(0x0000, 0x04);
+  cartridge.write_control(0x4000, 0x0c);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0);
+  cartridge.write_control(0x2000, 0x00);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 1);
+
+  cartridge.write_control(0x2000, 0x31);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 0x31);
+  cartridge.write_control(0x0000, 0x04);
+  cartridge.write_control(0x4000, 0x0c);
+  // The base bank replaces the masked bits of both banks
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0x04);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 0x35);
+  cartridge.write_control(0x2000, 0x01);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 0x05);
+  cartridge.write_control(0x0000, 0x08);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0x04);
+}
+
+#[test]
+fn test_wisdom_tree_banking() {
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::WisdomTree,
+    8,
+    config::CartridgeRamSize::NoRam,
+  );
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 1);
+  // The bank comes from the address, not the value
+  cartridge.write_control(0x0002, 0x00);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 4);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 5);
+  cartridge.write_control(0x4001, 0x00);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 4);
+  cartridge.write_control(0x0001, 0xff);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 2);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 3);
+}
+
+#[test]
+fn test_rocket_games_banking() {
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::RocketGames,
+    8,
+    config::CartridgeRamSize::NoRam,
+  );
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 1);
+  cartridge.write_control(0x2000, 0x03);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 3);
+  cartridge.write_control(0x2000, 0x00);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 1);
+  cartridge.write_control(0x0000, 0x05);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 1);
+}
+
+#[test]
+fn test_mmm01_menu_mode() {
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::Mmm01 {
+      ram: false,
+      battery: false,
+    },
+    32,
+    config::CartridgeRamSize::NoRam,
+  );
+  // The last 32 KiB is mapped until the mapping is locked
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 30);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 31);
+  cartridge.write_control(0x2000, 0x06);
+  cartridge.write_control(0x6000, 0x0c);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 30);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 31);
+  cartridge.write_control(0x0000, 0x40);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 6);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 7);
+}
+
+#[test]
+fn test_mmm01_locked_mapping() {
+  let mut cartridge = test_cartridge(
+    config::CartridgeType::Mmm01 {
+      ram: false,
+      battery: false,
+    },
+    128,
+    config::CartridgeRamSize::NoRam,
+  );
+  // Bits 5-6 select the 512 KiB game, and the mask fixes bits 1-2 of the bank number
+  cartridge.write_control(0x2000, 0x26);
+  cartridge.write_control(0x6000, 0x0c);
+  cartridge.write_control(0x0000, 0x40);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0x26);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 0x27);
+  cartridge.write_control(0x2000, 0x09);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0x26);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 0x2f);
+
+  // Locked configuration bits can't be changed or unlocked
+  cartridge.write_control(0x2000, 0x41);
+  cartridge.write_control(0x6000, 0x00);
+  cartridge.write_control(0x0000, 0x00);
+  assert_eq!(cartridge.read_0000_3fff(0x0000), 0x26);
+  assert_eq!(cartridge.read_4000_7fff(0x4000), 0x27);
+}
+
+#[test]
+fn test_mbc5_flash_program() {
+  let data = (0..8 * ROM_BANK_SIZE)
+    .map(|offset| (offset / ROM_BANK_SIZE) as u8)
+    .collect::<Vec<_>>();
+  let mut cartridge = Cartridge::new(config::Cartridge {
+    data: data.into(),
+    title: String::new(),
+    cartridge_type: config::CartridgeType::Mbc5 {
+      ram: false,
+      battery: false,
+      rumble: false,
+    },
+    rom_size: config::CartridgeRomSize::NoRomBanks,
+    ram_size: config::CartridgeRamSize::NoRam,
+    header: None,
+    rom_info: None,
+    save_path: None,
+    flash_path: Some("test.flash.gb".into()),
+  });
+  cartridge.write_control(0x2000, 0x05);
+  assert_eq!(cartridge.read_4000_7fff(0x4123), 0x05);
+  // The command cycles go to bank 0, and the data byte to the selected bank
+  cartridge.write_control(0x0555, 0xaa);
+  cartridge.write_control(0x02aa, 0x55);
+  cartridge.write_control(0x0555, 0xa0);
+  cartridge.write_control(0x4123, 0x01);
+  assert_eq!(cartridge.read_4000_7fff(0x4123), 0x01);
+  assert_eq!(cartridge.read_4000_7fff(0x4124), 0x05);
+  assert_eq!(cartridge.read_0000_3fff(0x0123), 0x00);
+
+  let flash_data = cartridge.flash_data().unwrap();
+  assert_eq!(flash_data[5 * ROM_BANK_SIZE + 0x0123], 0x01);
+  assert_eq!(flash_data[0x0123], 0x00);
+  // Writes that aren't part of a command only reach the MBC
+  cartridge.write_control(0x2000, 0x03);
+  assert_eq!(cartridge.read_4000_7fff(0x4123), 0x03);
+  assert_eq!(cartridge.flash_data().unwrap()[3 * ROM_BANK_SIZE], 0x03);
+}
@@ -0,0 +1,177 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crate::camera::{CameraImage, CAMERA_HEIGHT, CAMERA_WIDTH};
+
+/// Number of sensor registers at $A000-$A035
+const REG_COUNT: usize = 0x36;
+/// Offset of the captured image in RAM bank 0
+const IMAGE_OFFSET: usize = 0x0100;
+/// Edge enhancement ratios selected by register 4 bits 4-6
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+/// Pocket Camera mapper and M64282FP sensor state.
+///
+/// Captures finish immediately, so the busy flag is never observed as set. The sensor gain and
+/// output voltage reference are not modelled
+#[derive(Debug, Clone)]
+pub struct CameraState {
+  pub ramg: bool,
+  pub rom_bank: u8,
+  pub ram_bank: u8,
+  /// Bank $10 maps the sensor registers instead of RAM
+  pub regs_mapped: bool,
+  regs: [u8; REG_COUNT],
+  frames: Vec<CameraImage>,
+  frame_index: usize,
+}
+
+impl Default for CameraState {
+  fn default() -> CameraState {
+    CameraState {
+      ramg: false,
+      rom_bank: 0b00_0001,
+      ram_bank: 0,
+      regs_mapped: false,
+      regs: [0; REG_COUNT],
+      frames: vec![],
+      frame_index: 0,
+    }
+  }
+}
+
+impl CameraState {
+  pub fn set_frames(&mut self, frames: Vec<CameraImage>) {
+    self.frames = frames;
+    self.frame_index = 0;
+  }
+  /// Reads a sensor register. Only the capture control register is readable
+  pub fn read_reg(&self, addr: u16) -> u8 {
+    match addr & 0x7f {
+      0x00 => self.regs[0] & 0b111,
+      _ => 0x00,
+    }
+  }
+  /// Writes a sensor register. Starting a capture writes the processed image to `ram`
+  pub fn write_reg(&mut self, addr: u16, value: u8, ram: &mut [u8]) {
+    let reg = (addr & 0x7f) as usize;
+    if reg >= REG_COUNT {
+      return;
+    }
+    self.regs[reg] = value;
+    if reg == 0 && value & 0b1 != 0 {
+      self.capture(ram);
+      self.regs[0] &= !0b1;
+    }
+  }
+  fn next_frame(&mut self) -> CameraImage {
+    if self.frames.is_empty() {
+      return CameraImage::uniform(0x80);
+    }
+    let frame = self.frames[self.frame_index].clone();
+    self.frame_index = (self.frame_index + 1) % self.frames.len();
+    frame
+  }
+  fn exposure(&self) -> u32 {
+    u32::from(self.regs[2]) << 8 | u32::from(self.regs[3])
+  }
+  /// Returns the sensor output of a pixel after edge enhancement, inversion and exposure
+  fn sensor_value(&self, image: &CameraImage, x: isize, y: isize) -> u32 {
+    let mut value = f32::from(image.pixel(x, y));
+    if self.regs[1] & 0xe0 == 0xe0 {
+      let ratio = EDGE_RATIOS[((self.regs[4] >> 4) & 0b111) as usize];
+      let neighbours = f32::from(image.pixel(x - 1, y))
+        + f32::from(image.pixel(x + 1, y))
+        + f32::from(image.pixel(x, y - 1))
+        + f32::from(image.pixel(x, y + 1));
+      value += ratio * (4.0 * value - neighbours);
+    }
+    let mut value = value.clamp(0.0, 255.0) as u32;
+    if self.regs[4] & 0b1000 != 0 {
+      value = 255 - value;
+    }
+    (value * self.exposure() / 0x1000).min(255)
+  }
+  /// Converts a sensor value to a 2-bit colour using the 4x4 dither matrix
+  fn dither(&self, value: u32, x: usize, y: usize) -> u8 {
+    let base = 6 + ((y & 0b11) * 4 + (x & 0b11)) * 3;
+    let thresholds = &self.regs[base..base + 3];
+    if value < u32::from(thresholds[0]) {
+      3
+    } else if value < u32::from(thresholds[1]) {
+      2
+    } else if value < u32::from(thresholds[2]) {
+      1
+    } else {
+      0
+    }
+  }
+  /// Captures an image and stores it as 16x14 tiles in RAM bank 0
+  fn capture(&mut self, ram: &mut [u8]) {
+    if ram.len() < IMAGE_OFFSET + CAMERA_WIDTH * CAMERA_HEIGHT / 4 {
+      return;
+    }
+    let image = self.next_frame();
+    for y in 0..CAMERA_HEIGHT {
+      for x in 0..CAMERA_WIDTH {
+        let value = self.sensor_value(&image, x as isize, y as isize);
+        let color = self.dither(value, x, y);
+        let tile = (y / 8) * (CAMERA_WIDTH / 8) + x / 8;
+        let offset = IMAGE_OFFSET + tile * 16 + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        let (lo, hi) = (color & 0b01, (color >> 1) & 0b01);
+        ram[offset] = (ram[offset] & !(1 << bit)) | (lo << bit);
+        ram[offset + 1] = (ram[offset + 1] & !(1 << bit)) | (hi << bit);
+      }
+    }
+  }
+}
+
+#[test]
+fn test_sensor_value() {
+  let mut state = CameraState::default();
+  let image = CameraImage::uniform(0x40);
+  state.regs[2] = 0x10;
+  assert_eq!(state.sensor_value(&image, 0, 0), 0x40);
+  // Exposure scales the output, saturating at 255
+  state.regs[2] = 0x08;
+  assert_eq!(state.sensor_value(&image, 0, 0), 0x20);
+  state.regs[2] = 0x80;
+  assert_eq!(state.sensor_value(&image, 0, 0), 0xff);
+  state.regs[2] = 0x10;
+  state.regs[4] = 0b1000;
+  assert_eq!(state.sensor_value(&image, 0, 0), 0xbf);
+  // Edge enhancement has no effect on a uniform image
+  state.regs[1] = 0xe0;
+  state.regs[4] = 0x70;
+  assert_eq!(state.sensor_value(&image, 0, 0), 0x40);
+}
+
+#[test]
+fn test_dither_matrix() {
+  let mut state = CameraState::default();
+  for (i, reg) in state.regs[6..0x36].iter_mut().enumerate() {
+    *reg = [0x40, 0x80, 0xc0][i % 3];
+  }
+  assert_eq!(state.dither(0x00, 0, 0), 3);
+  assert_eq!(state.dither(0x40, 0, 0), 2);
+  assert_eq!(state.dither(0x80, 0, 0), 1);
+  assert_eq!(state.dither(0xc0, 0, 0), 0);
+  // Thresholds repeat every 4 pixels in both directions
+  state.regs[6 + (2 * 4 + 3) * 3] = 0xff;
+  assert_eq!(state.dither(0x80, 3, 2), 3);
+  assert_eq!(state.dither(0x80, 7, 6), 3);
+  assert_eq!(state.dither(0x80, 2, 3), 1);
+}
@@ -0,0 +1,180 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+/// Flash chip parameters
+#[derive(Debug, Clone, Copy)]
+pub struct FlashChip {
+  pub manufacturer_id: u8,
+  pub device_id: u8,
+  pub sector_size: usize,
+  /// Address bits decoded when matching command cycles
+  pub command_mask: usize,
+  /// First and second unlock cycle addresses
+  pub unlock_addrs: (usize, usize),
+}
+
+/// Macronix MX29F008, used by MBC6
+pub const MX29F008: FlashChip = FlashChip {
+  manufacturer_id: 0xc2,
+  device_id: 0x81,
+  sector_size: 0x2_0000,
+  command_mask: 0x7fff,
+  unlock_addrs: (0x5555, 0x2aaa),
+};
+
+/// AMD Am29F016, a common chip in homebrew flash carts
+pub const AM29F016: FlashChip = FlashChip {
+  manufacturer_id: 0x01,
+  device_id: 0xad,
+  sector_size: 0x1_0000,
+  command_mask: 0x07ff,
+  unlock_addrs: (0x555, 0x2aa),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlashState {
+  Ready,
+  Unlock1,
+  Command,
+  Program,
+  EraseUnlock0,
+  EraseUnlock1,
+  EraseCommand,
+}
+
+/// Flash memory with AMD/JEDEC-style commands.
+///
+/// Program and erase operations complete immediately, so data polling (DQ7) and toggle bit (DQ6)
+/// polling see a finished operation on the first status read
+#[derive(Debug, Clone)]
+pub struct Flash {
+  pub data: Box<[u8]>,
+  chip: FlashChip,
+  state: FlashState,
+  autoselect: bool,
+}
+
+impl Flash {
+  pub fn new(chip: FlashChip, data: Box<[u8]>) -> Flash {
+    debug_assert!(data.len().is_power_of_two());
+    Flash {
+      data,
+      chip,
+      state: FlashState::Ready,
+      autoselect: false,
+    }
+  }
+  /// Creates an erased flash chip
+  pub fn erased(chip: FlashChip, size: usize) -> Flash {
+    Flash::new(chip, vec![0xff; size].into_boxed_slice())
+  }
+  fn mask(&self) -> usize {
+    self.data.len() - 1
+  }
+  pub fn read(&self, offset: usize) -> u8 {
+    if self.autoselect {
+      match offset & 0b11 {
+        0 => self.chip.manufacturer_id,
+        1 => self.chip.device_id,
+        // Sector protection status: unprotected
+        _ => 0x00,
+      }
+    } else {
+      self.data[offset & self.mask()]
+    }
+  }
+  /// Handles a write cycle. Program and erase commands only modify the data if `write_enabled`
+  pub fn write(&mut self, offset: usize, value: u8, write_enabled: bool) {
+    use self::FlashState::*;
+    let offset = offset & self.mask();
+    let command_addr = offset & self.chip.command_mask;
+    let (unlock0, unlock1) = self.chip.unlock_addrs;
+    // 0xF0 is a reset command, unless it's the data byte of a program command
+    if value == 0xf0 && self.state != Program {
+      self.autoselect = false;
+      self.state = Ready;
+      return;
+    }
+    self.state = match (self.state, value) {
+      (Ready, 0xaa) if command_addr == unlock0 => Unlock1,
+      (Unlock1, 0x55) if command_addr == unlock1 => Command,
+      (Command, 0x90) if command_addr == unlock0 => {
+        self.autoselect = true;
+        Ready
+      }
+      (Command, 0xa0) if command_addr == unlock0 => Program,
+      (Command, 0x80) if command_addr == unlock0 => EraseUnlock0,
+      (Program, _) => {
+        if write_enabled {
+          // Programming can only clear bits
+          self.data[offset] &= value;
+        }
+        Ready
+      }
+      (EraseUnlock0, 0xaa) if command_addr == unlock0 => EraseUnlock1,
+      (EraseUnlock1, 0x55) if command_addr == unlock1 => EraseCommand,
+      (EraseCommand, 0x30) => {
+        if write_enabled {
+          let sector_size = self.chip.sector_size.min(self.data.len());
+          let start = offset & !(sector_size - 1);
+          self.erase(start, sector_size);
+        }
+        Ready
+      }
+      (EraseCommand, 0x10) if command_addr == unlock0 => {
+        if write_enabled {
+          self.erase(0, self.data.len());
+        }
+        Ready
+      }
+      _ => Ready,
+    };
+  }
+  fn erase(&mut self, start: usize, len: usize) {
+    for value in &mut self.data[start..start + len] {
+      *value = 0xff;
+    }
+  }
+}
+
+#[test]
+fn test_flash_commands() {
+  let mut flash = Flash::erased(AM29F016, 0x2_0000);
+  let unlock = |flash: &mut Flash| {
+    flash.write(0x555, 0xaa, true);
+    flash.write(0x2aa, 0x55, true);
+  };
+  unlock(&mut flash);
+  flash.write(0x555, 0xa0, true);
+  flash.write(0x1_2345, 0x5a, true);
+  assert_eq!(flash.read(0x1_2345), 0x5a);
+
+  unlock(&mut flash);
+  flash.write(0x555, 0xa0, true);
+  flash.write(0x1_2346, 0xf0, true);
+  assert_eq!(flash.read(0x1_2346), 0xf0);
+
+  unlock(&mut flash);
+  flash.write(0x555, 0x90, true);
+  assert_eq!(flash.read(0x0000), 0x01);
+  assert_eq!(flash.read(0x0001), 0xad);
+  flash.write(0x0000, 0xf0, true);
+
+  unlock(&mut flash);
+  flash.write(0x555, 0x80, true);
+  unlock(&mut flash);
+  flash.write(0x1_0000, 0x30, true);
+  assert_eq!(flash.read(0x1_2345), 0xff);
+}
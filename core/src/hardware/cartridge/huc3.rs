@@ -0,0 +1,130 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use super::rtc::{host_timestamp, RtcCounter};
+
+/// HuC3 RTC, accessed through a nibble-based command/response interface.
+///
+/// The clock counts minutes of the day (0-1439) and days, and runs on emulated time. Save data
+/// stores a host timestamp, so the time the emulator was closed is added when it's loaded.
+#[derive(Debug, Clone, Default)]
+pub struct Huc3Rtc {
+  counter: RtcCounter,
+  access_index: u8,
+  last_command: u8,
+  response: u8,
+}
+
+/// RTC state appended to the save RAM: minutes, days and a UNIX timestamp
+pub const HUC3_RTC_SAVE_SIZE: usize = 12;
+
+const SECONDS_PER_MINUTE: u64 = 60;
+const MINUTES_PER_DAY: u64 = 1440;
+const SECONDS_PER_DAY: u64 = SECONDS_PER_MINUTE * MINUTES_PER_DAY;
+
+impl Huc3Rtc {
+  pub fn tick_cycle(&mut self) {
+    self.counter.tick_cycle();
+  }
+  fn minutes(&self) -> u16 {
+    (self.counter.seconds() / SECONDS_PER_MINUTE % MINUTES_PER_DAY) as u16
+  }
+  fn days(&self) -> u16 {
+    (self.counter.seconds() / SECONDS_PER_DAY) as u16
+  }
+  fn set_time(&mut self, minutes: u16, days: u16) {
+    let seconds = self.counter.seconds() % SECONDS_PER_MINUTE;
+    self.counter.set_seconds(
+      u64::from(days) * SECONDS_PER_DAY + u64::from(minutes) * SECONDS_PER_MINUTE + seconds,
+    );
+  }
+  /// Response to the last command: command in bits 4-6, value in bits 0-3
+  pub fn read_response(&self) -> u8 {
+    0x80 | (self.last_command << 4) | self.response
+  }
+  pub fn write_command(&mut self, value: u8) {
+    let command = (value >> 4) & 0x07;
+    let argument = value & 0x0f;
+    self.last_command = command;
+    match command {
+      // Read value and increment access index
+      0x1 => {
+        self.response = self.read_nibble(self.access_index);
+        self.access_index = self.access_index.wrapping_add(1);
+      }
+      // Write value and increment access index
+      0x3 => {
+        self.write_nibble(self.access_index, argument);
+        self.access_index = self.access_index.wrapping_add(1);
+      }
+      // Set access index low nibble
+      0x4 => self.access_index = (self.access_index & 0xf0) | argument,
+      // Set access index high nibble
+      0x5 => self.access_index = (self.access_index & 0x0f) | (argument << 4),
+      // Extended command. 0x2 is a status check that responds with 1
+      0x6 => self.response = if argument == 0x2 { 0x1 } else { 0x0 },
+      _ => (),
+    }
+  }
+  /// Access indices 0-2 are the minutes, and 3-6 are the days
+  fn read_nibble(&self, index: u8) -> u8 {
+    match index {
+      0..=2 => ((self.minutes() >> (index * 4)) & 0x0f) as u8,
+      3..=6 => ((self.days() >> ((index - 3) * 4)) & 0x0f) as u8,
+      _ => 0x0,
+    }
+  }
+  /// Minutes past 1439 carry into the days
+  fn write_nibble(&mut self, index: u8, value: u8) {
+    let (minutes, days) = (self.minutes(), self.days());
+    match index {
+      0..=2 => {
+        let shift = index * 4;
+        self.set_time(
+          (minutes & !(0x0f << shift)) | ((value as u16) << shift),
+          days,
+        );
+      }
+      3..=6 => {
+        let shift = (index - 3) * 4;
+        self.set_time(
+          minutes,
+          (days & !(0x0f << shift)) | ((value as u16) << shift),
+        );
+      }
+      _ => (),
+    }
+  }
+  pub fn save(&self) -> [u8; HUC3_RTC_SAVE_SIZE] {
+    let mut data = [0; HUC3_RTC_SAVE_SIZE];
+    data[0..2].copy_from_slice(&self.minutes().to_le_bytes());
+    data[2..4].copy_from_slice(&self.days().to_le_bytes());
+    data[4..12].copy_from_slice(&host_timestamp().to_le_bytes());
+    data
+  }
+  pub fn load(&mut self, data: &[u8]) {
+    if data.len() < HUC3_RTC_SAVE_SIZE {
+      return;
+    }
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&data[4..12]);
+    let minutes = u64::from(u16::from_le_bytes([data[0], data[1]])) % MINUTES_PER_DAY;
+    let days = u64::from(u16::from_le_bytes([data[2], data[3]]));
+    self.counter.load(
+      days * SECONDS_PER_DAY + minutes * SECONDS_PER_MINUTE,
+      u64::from_le_bytes(timestamp),
+    );
+  }
+}
@@ -0,0 +1,150 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use super::flash::{Flash, MX29F008};
+
+/// MX29F008 flash size: 128 banks of 8 KiB
+pub const FLASH_SIZE: usize = 0x10_0000;
+const FLASH_BANK_SIZE: usize = 0x2000;
+/// MBC6 RAM banks are 4 KiB, and A000-AFFF and B000-BFFF are banked separately
+const RAM_BANK_SIZE: usize = 0x1000;
+
+#[derive(Debug, Clone)]
+pub struct Mbc6State {
+  pub ramg: bool,
+  ram_bank_a: u8,
+  ram_bank_b: u8,
+  flash_enable: bool,
+  flash_write_enable: bool,
+  bank_a: u8,
+  bank_b: u8,
+  flash_a: bool,
+  flash_b: bool,
+  pub flash: Flash,
+}
+
+impl Default for Mbc6State {
+  fn default() -> Mbc6State {
+    Mbc6State {
+      ramg: false,
+      ram_bank_a: 0b000,
+      ram_bank_b: 0b000,
+      flash_enable: false,
+      flash_write_enable: false,
+      bank_a: 0b000_0010,
+      bank_b: 0b000_0011,
+      flash_a: false,
+      flash_b: false,
+      flash: Flash::erased(MX29F008, FLASH_SIZE),
+    }
+  }
+}
+
+/// Target of a 8 KiB window at $4000-$5FFF or $6000-$7FFF
+pub enum Window {
+  Rom(usize),
+  Flash(usize),
+}
+
+impl Mbc6State {
+  pub fn write_control(&mut self, addr: u16, value: u8) {
+    match addr >> 8 {
+      0x00..=0x03 => self.ramg = value & 0x0f == 0x0a,
+      0x04..=0x07 => self.ram_bank_a = value & 0b111,
+      0x08..=0x0b => self.ram_bank_b = value & 0b111,
+      0x0c..=0x0f => self.flash_enable = value & 0b1 != 0,
+      0x10 => self.flash_write_enable = value & 0b1 != 0,
+      0x20..=0x27 => self.bank_a = value & 0b111_1111,
+      0x28..=0x2f => self.flash_a = value == 0x08,
+      0x30..=0x37 => self.bank_b = value & 0b111_1111,
+      0x38..=0x3f => self.flash_b = value == 0x08,
+      0x40..=0x7f => {
+        if let Window::Flash(offset) = self.window(addr) {
+          if self.flash_enable {
+            self.flash.write(offset, value, self.flash_write_enable);
+          }
+        }
+      }
+      _ => (),
+    }
+  }
+  /// Maps an address in $4000-$7FFF to ROM or flash
+  pub fn window(&self, addr: u16) -> Window {
+    let (bank, flash) = if addr < 0x6000 {
+      (self.bank_a, self.flash_a)
+    } else {
+      (self.bank_b, self.flash_b)
+    };
+    let offset = FLASH_BANK_SIZE * bank as usize + (addr as usize & 0x1fff);
+    if flash {
+      Window::Flash(offset)
+    } else {
+      Window::Rom(offset)
+    }
+  }
+  /// Maps an address in $A000-$BFFF to a RAM offset
+  pub fn ram_offset(&self, addr: u16) -> usize {
+    let bank = if addr < 0xb000 {
+      self.ram_bank_a
+    } else {
+      self.ram_bank_b
+    };
+    RAM_BANK_SIZE * bank as usize + (addr as usize & 0x0fff)
+  }
+}
+
+#[test]
+fn test_mbc6_banking() {
+  let mut mbc6 = Mbc6State::default();
+  mbc6.write_control(0x2000, 0x05);
+  mbc6.write_control(0x3000, 0x7f);
+  assert!(matches!(mbc6.window(0x4123), Window::Rom(0x0_a123)));
+  assert!(matches!(mbc6.window(0x6123), Window::Rom(0xf_e123)));
+  mbc6.write_control(0x2800, 0x08);
+  assert!(matches!(mbc6.window(0x4123), Window::Flash(0x0_a123)));
+  assert!(matches!(mbc6.window(0x6123), Window::Rom(0xf_e123)));
+
+  mbc6.write_control(0x0400, 0x03);
+  mbc6.write_control(0x0800, 0x0e);
+  assert_eq!(mbc6.ram_offset(0xa123), 0x3123);
+  assert_eq!(mbc6.ram_offset(0xb123), 0x6123);
+}
+
+#[test]
+fn test_mbc6_flash_program() {
+  let mut mbc6 = Mbc6State::default();
+  // The unlock cycles go to $5555 and $2AAA in flash, so map flash banks 2 and 1
+  mbc6.write_control(0x2000, 0x02);
+  mbc6.write_control(0x2800, 0x08);
+  mbc6.write_control(0x3000, 0x01);
+  mbc6.write_control(0x3800, 0x08);
+  let program = |mbc6: &mut Mbc6State, addr: u16, value: u8| {
+    mbc6.write_control(0x5555, 0xaa);
+    mbc6.write_control(0x6aaa, 0x55);
+    mbc6.write_control(0x5555, 0xa0);
+    mbc6.write_control(addr, value);
+  };
+  // Writes are ignored until flash is enabled, and don't modify data without write enable
+  program(&mut mbc6, 0x4010, 0x12);
+  mbc6.write_control(0x0c00, 0x01);
+  program(&mut mbc6, 0x4010, 0x12);
+  assert_eq!(mbc6.flash.read(0x4010), 0xff);
+
+  mbc6.write_control(0x1000, 0x01);
+  program(&mut mbc6, 0x4010, 0x12);
+  program(&mut mbc6, 0x4011, 0xf0);
+  assert_eq!(mbc6.flash.read(0x4010), 0x12);
+  assert_eq!(mbc6.flash.read(0x4011), 0xf0);
+}
@@ -0,0 +1,392 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+/// Accelerometer value when the cartridge is level
+const ACCEL_CENTER: u16 = 0x81d0;
+/// Accelerometer value change for 1 g
+const ACCEL_1G: f32 = 112.0;
+/// Value of the latch registers after an erase
+const LATCH_ERASED: u16 = 0x8000;
+
+/// 93LC56 EEPROM size in bytes (128 16-bit words)
+pub const EEPROM_SIZE: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct Mbc7State {
+  pub ramg1: bool,
+  pub ramg2: bool,
+  pub rom_bank: u8,
+  accel_x: u16,
+  accel_y: u16,
+  latch_x: u16,
+  latch_y: u16,
+  latch_erased: bool,
+  pub eeprom: Eeprom,
+}
+
+impl Default for Mbc7State {
+  fn default() -> Mbc7State {
+    Mbc7State {
+      ramg1: false,
+      ramg2: false,
+      rom_bank: 0b000_0001,
+      accel_x: ACCEL_CENTER,
+      accel_y: ACCEL_CENTER,
+      latch_x: LATCH_ERASED,
+      latch_y: LATCH_ERASED,
+      latch_erased: true,
+      eeprom: Eeprom::default(),
+    }
+  }
+}
+
+impl Mbc7State {
+  pub fn ramg(&self) -> bool {
+    self.ramg1 && self.ramg2
+  }
+  /// Sets the accelerometer input in units of g
+  pub fn set_tilt(&mut self, x: f32, y: f32) {
+    let to_accel = |g: f32| (ACCEL_CENTER as f32 + g * ACCEL_1G) as u16;
+    self.accel_x = to_accel(x);
+    self.accel_y = to_accel(y);
+  }
+  /// Reads a register at $A000-$AFFF. Address bits 4-7 select the register
+  pub fn read_reg(&self, addr: u16) -> u8 {
+    match (addr >> 4) & 0x0f {
+      0x2 => self.latch_x as u8,
+      0x3 => (self.latch_x >> 8) as u8,
+      0x4 => self.latch_y as u8,
+      0x5 => (self.latch_y >> 8) as u8,
+      0x6 => 0x00,
+      0x8 => self.eeprom.read(),
+      _ => 0xff,
+    }
+  }
+  pub fn write_reg(&mut self, addr: u16, value: u8) {
+    match (addr >> 4) & 0x0f {
+      0x0 if value == 0x55 => {
+        self.latch_x = LATCH_ERASED;
+        self.latch_y = LATCH_ERASED;
+        self.latch_erased = true;
+      }
+      // The latch can only be updated after an erase
+      0x1 if value == 0xaa && self.latch_erased => {
+        self.latch_x = self.accel_x;
+        self.latch_y = self.accel_y;
+        self.latch_erased = false;
+      }
+      0x8 => self.eeprom.write(value),
+      _ => (),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+enum EepromState {
+  Idle,
+  /// Receiving the 2-bit opcode and 8-bit address after the start bit
+  Command {
+    bits: u16,
+    count: u8,
+  },
+  /// Shifting out a word, followed by the next words
+  Read {
+    addr: u8,
+    value: u16,
+    count: u8,
+  },
+  /// Shifting in a word. A missing address means all words are written
+  Write {
+    addr: Option<u8>,
+    value: u16,
+    count: u8,
+  },
+}
+
+/// 93LC56 serial EEPROM in 16-bit mode.
+///
+/// The register at $Ax8x drives the chip pins: bit 7 is CS, bit 6 is CLK, bit 1 is DI and
+/// bit 0 is DO. Input bits are sampled on the rising edge of CLK.
+#[derive(Debug, Clone)]
+pub struct Eeprom {
+  pub data: [u8; EEPROM_SIZE],
+  cs: bool,
+  clk: bool,
+  di: bool,
+  dout: bool,
+  write_enabled: bool,
+  state: EepromState,
+}
+
+impl Default for Eeprom {
+  fn default() -> Eeprom {
+    Eeprom {
+      data: [0xff; EEPROM_SIZE],
+      cs: false,
+      clk: false,
+      di: false,
+      dout: true,
+      write_enabled: false,
+      state: EepromState::Idle,
+    }
+  }
+}
+
+impl Eeprom {
+  fn read(&self) -> u8 {
+    (if self.cs { 1 << 7 } else { 0 })
+      | (if self.clk { 1 << 6 } else { 0 })
+      | (if self.di { 1 << 1 } else { 0 })
+      | (if self.dout { 1 << 0 } else { 0 })
+  }
+  fn write(&mut self, value: u8) {
+    let cs = value & (1 << 7) != 0;
+    let clk = value & (1 << 6) != 0;
+    let rising_edge = !self.clk && clk;
+    self.cs = cs;
+    self.clk = clk;
+    self.di = value & (1 << 1) != 0;
+    if !cs {
+      self.state = EepromState::Idle;
+    } else if rising_edge {
+      self.clock();
+    }
+  }
+  fn clock(&mut self) {
+    let di = self.di as u16;
+    self.state = match self.state {
+      EepromState::Idle if self.di => {
+        self.dout = true;
+        EepromState::Command { bits: 0, count: 0 }
+      }
+      EepromState::Idle => EepromState::Idle,
+      EepromState::Command { bits, count } if count < 9 => EepromState::Command {
+        bits: (bits << 1) | di,
+        count: count + 1,
+      },
+      EepromState::Command { bits, .. } => self.execute((bits << 1) | di),
+      EepromState::Read { addr, value, count } => {
+        self.dout = value & 0x8000 != 0;
+        if count > 1 {
+          EepromState::Read {
+            addr,
+            value: value << 1,
+            count: count - 1,
+          }
+        } else {
+          // Sequential read continues with the next word
+          let addr = addr.wrapping_add(1) & 0x7f;
+          EepromState::Read {
+            addr,
+            value: self.read_word(addr),
+            count: 16,
+          }
+        }
+      }
+      EepromState::Write { addr, value, count } => {
+        let value = (value << 1) | di;
+        if count < 15 {
+          EepromState::Write {
+            addr,
+            value,
+            count: count + 1,
+          }
+        } else {
+          if self.write_enabled {
+            match addr {
+              Some(addr) => self.write_word(addr, value),
+              None => (0..0x80).for_each(|addr| self.write_word(addr, value)),
+            }
+          }
+          self.dout = true;
+          EepromState::Idle
+        }
+      }
+    }
+  }
+  fn execute(&mut self, command: u16) -> EepromState {
+    let addr = (command & 0x7f) as u8;
+    match command >> 8 {
+      // READ: a dummy 0 bit is output before the data
+      0b10 => {
+        self.dout = false;
+        EepromState::Read {
+          addr,
+          value: self.read_word(addr),
+          count: 16,
+        }
+      }
+      // WRITE
+      0b01 => EepromState::Write {
+        addr: Some(addr),
+        value: 0,
+        count: 0,
+      },
+      // ERASE
+      0b11 => {
+        if self.write_enabled {
+          self.write_word(addr, 0xffff);
+        }
+        EepromState::Idle
+      }
+      _ => match (command >> 6) & 0b11 {
+        // EWDS
+        0b00 => {
+          self.write_enabled = false;
+          EepromState::Idle
+        }
+        // WRAL
+        0b01 => EepromState::Write {
+          addr: None,
+          value: 0,
+          count: 0,
+        },
+        // ERAL
+        0b10 => {
+          if self.write_enabled {
+            (0..0x80).for_each(|addr| self.write_word(addr, 0xffff));
+          }
+          EepromState::Idle
+        }
+        // EWEN
+        _ => {
+          self.write_enabled = true;
+          EepromState::Idle
+        }
+      },
+    }
+  }
+  fn read_word(&self, addr: u8) -> u16 {
+    let offset = addr as usize * 2;
+    u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+  }
+  fn write_word(&mut self, addr: u8, value: u16) {
+    let offset = addr as usize * 2;
+    self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+  }
+}
+
+/// Writes bits to the EEPROM through the $Ax8x register, MSB first
+#[cfg(test)]
+fn eeprom_send(mbc7: &mut Mbc7State, bits: u32, count: u8) {
+  for i in (0..count).rev() {
+    let di = ((bits >> i) & 0b1) as u8;
+    mbc7.write_reg(0xa080, 0x80 | (di << 1));
+    mbc7.write_reg(0xa080, 0xc0 | (di << 1));
+  }
+}
+
+/// Clocks bits out of the EEPROM, MSB first
+#[cfg(test)]
+fn eeprom_receive(mbc7: &mut Mbc7State, count: u8) -> u32 {
+  (0..count).fold(0, |acc, _| {
+    mbc7.write_reg(0xa080, 0x80);
+    mbc7.write_reg(0xa080, 0xc0);
+    (acc << 1) | u32::from(mbc7.read_reg(0xa080) & 0b1)
+  })
+}
+
+/// Sends a start bit and a 10-bit command, optionally followed by 16 data bits
+#[cfg(test)]
+fn eeprom_command(mbc7: &mut Mbc7State, command: u32, data: Option<u16>) {
+  mbc7.write_reg(0xa080, 0x00);
+  eeprom_send(mbc7, 0b1 << 10 | command, 11);
+  if let Some(data) = data {
+    eeprom_send(mbc7, u32::from(data), 16);
+  }
+}
+
+#[cfg(test)]
+fn eeprom_read(mbc7: &mut Mbc7State, addr: u8, words: u8) -> Vec<u16> {
+  eeprom_command(mbc7, 0b10 << 8 | u32::from(addr), None);
+  // Dummy 0 bit before the data
+  assert_eq!(mbc7.read_reg(0xa080) & 0b1, 0);
+  (0..words)
+    .map(|_| eeprom_receive(mbc7, 16) as u16)
+    .collect()
+}
+
+#[test]
+fn test_eeprom_protocol() {
+  const EWEN: u32 = 0b00_1100_0000;
+  const EWDS: u32 = 0b00_0000_0000;
+  const WRAL: u32 = 0b00_0100_0000;
+  const ERAL: u32 = 0b00_1000_0000;
+  const WRITE: u32 = 0b01 << 8;
+  const ERASE: u32 = 0b11 << 8;
+  let mut mbc7 = Mbc7State::default();
+
+  // Writes are ignored until enabled
+  eeprom_command(&mut mbc7, WRITE | 0x05, Some(0x1234));
+  assert_eq!(eeprom_read(&mut mbc7, 0x05, 1), [0xffff]);
+  eeprom_command(&mut mbc7, EWEN, None);
+  eeprom_command(&mut mbc7, WRITE | 0x05, Some(0x1234));
+  eeprom_command(&mut mbc7, WRITE | 0x06, Some(0xabcd));
+  assert_eq!(mbc7.eeprom.data[0x0a..0x0e], [0x34, 0x12, 0xcd, 0xab]);
+  // Sequential read continues with the next words
+  assert_eq!(eeprom_read(&mut mbc7, 0x05, 3), [0x1234, 0xabcd, 0xffff]);
+  // Reads wrap around at the end of the EEPROM
+  assert_eq!(eeprom_read(&mut mbc7, 0x7f, 2), [0xffff, 0xffff]);
+
+  eeprom_command(&mut mbc7, ERASE | 0x05, None);
+  assert_eq!(eeprom_read(&mut mbc7, 0x05, 2), [0xffff, 0xabcd]);
+  eeprom_command(&mut mbc7, WRAL, Some(0x5a5a));
+  assert!(mbc7.eeprom.data.iter().all(|&byte| byte == 0x5a));
+  eeprom_command(&mut mbc7, ERAL, None);
+  assert!(mbc7.eeprom.data.iter().all(|&byte| byte == 0xff));
+
+  eeprom_command(&mut mbc7, EWDS, None);
+  eeprom_command(&mut mbc7, WRITE | 0x05, Some(0x1234));
+  eeprom_command(&mut mbc7, ERAL, None);
+  assert_eq!(eeprom_read(&mut mbc7, 0x05, 1), [0xffff]);
+
+  // Dropping CS aborts a command
+  eeprom_command(&mut mbc7, EWEN, None);
+  mbc7.write_reg(0xa080, 0x00);
+  eeprom_send(&mut mbc7, 0b1 << 10 | WRITE | 0x05, 11);
+  eeprom_send(&mut mbc7, 0x12, 8);
+  mbc7.write_reg(0xa080, 0x00);
+  assert_eq!(eeprom_read(&mut mbc7, 0x05, 1), [0xffff]);
+}
+
+#[test]
+fn test_accelerometer_latch() {
+  let mut mbc7 = Mbc7State::default();
+  let read_latch = |mbc7: &Mbc7State| {
+    let x = u16::from_le_bytes([mbc7.read_reg(0xa020), mbc7.read_reg(0xa030)]);
+    let y = u16::from_le_bytes([mbc7.read_reg(0xa040), mbc7.read_reg(0xa050)]);
+    (x, y)
+  };
+  assert_eq!(read_latch(&mbc7), (0x8000, 0x8000));
+  mbc7.set_tilt(0.5, -0.25);
+  // The latch keeps its value until it's latched
+  assert_eq!(read_latch(&mbc7), (0x8000, 0x8000));
+  mbc7.write_reg(0xa010, 0xaa);
+  assert_eq!(read_latch(&mbc7), (0x81d0 + 56, 0x81d0 - 28));
+
+  // Latching again requires an erase first
+  mbc7.set_tilt(0.0, 0.0);
+  mbc7.write_reg(0xa010, 0xaa);
+  assert_eq!(read_latch(&mbc7), (0x81d0 + 56, 0x81d0 - 28));
+  mbc7.write_reg(0xa000, 0x55);
+  assert_eq!(read_latch(&mbc7), (0x8000, 0x8000));
+  mbc7.write_reg(0xa010, 0xaa);
+  assert_eq!(read_latch(&mbc7), (0x81d0, 0x81d0));
+  // Other values don't erase or latch
+  mbc7.write_reg(0xa000, 0x54);
+  assert_eq!(read_latch(&mbc7), (0x81d0, 0x81d0));
+  assert_eq!(mbc7.read_reg(0xa060), 0x00);
+  assert_eq!(mbc7.read_reg(0xa070), 0xff);
+}
@@ -0,0 +1,81 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::gameboy;
+
+pub const MACHINE_CYCLES_PER_SECOND: u32 = (gameboy::CPU_SPEED_HZ / 4) as u32;
+
+/// Seconds counter of a cartridge RTC, driven by emulated time.
+///
+/// Host time is only used to catch up on the time that passed while the emulator was closed,
+/// based on the timestamp stored in the save data
+#[derive(Debug, Clone, Default)]
+pub struct RtcCounter {
+  seconds: u64,
+  cycles: u32,
+}
+
+impl RtcCounter {
+  pub fn tick_cycle(&mut self) {
+    self.cycles += 1;
+    if self.cycles == MACHINE_CYCLES_PER_SECOND {
+      self.cycles = 0;
+      self.seconds += 1;
+    }
+  }
+  pub fn seconds(&self) -> u64 {
+    self.seconds
+  }
+  /// Sets the counter and restarts the current second
+  pub fn set_seconds(&mut self, seconds: u64) {
+    self.seconds = seconds;
+    self.cycles = 0;
+  }
+  /// Sets the counter from save data, adding the host time that has passed since the save
+  /// data was written
+  pub fn load(&mut self, seconds: u64, timestamp: u64) {
+    self.set_seconds(seconds.saturating_add(host_timestamp().saturating_sub(timestamp)));
+  }
+}
+
+/// Returns the current host time as a UNIX timestamp, for storing in save data
+pub fn host_timestamp() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs()
+}
+
+#[test]
+fn test_rtc_counter() {
+  let mut counter = RtcCounter::default();
+  for _ in 0..MACHINE_CYCLES_PER_SECOND - 1 {
+    counter.tick_cycle();
+  }
+  assert_eq!(counter.seconds(), 0);
+  counter.tick_cycle();
+  assert_eq!(counter.seconds(), 1);
+  counter.tick_cycle();
+  counter.set_seconds(10);
+  for _ in 0..MACHINE_CYCLES_PER_SECOND - 1 {
+    counter.tick_cycle();
+  }
+  assert_eq!(counter.seconds(), 10);
+
+  counter.load(100, host_timestamp() - 60);
+  assert!((160..=161).contains(&counter.seconds()));
+}
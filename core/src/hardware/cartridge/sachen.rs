@@ -0,0 +1,67 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crate::gameboy::ROM_BANK_SIZE;
+
+/// Sachen MMC1/MMC2 mapper state.
+///
+/// The mapper boots in a locked state where reads from $0100-$014F have address line A7 forced
+/// high, so the boot ROM sees the Nintendo logo and header stored at $0180-$01CF. The real mapper
+/// unlocks after counting A15 edges during the boot ROM logo check, which is approximated by
+/// unlocking when the boot ROM is disabled. MMC2 additionally tracks a CGB boot sequence, which
+/// only matters on the Game Boy Color, so on DMG models both variants behave the same
+#[derive(Debug, Clone)]
+pub struct SachenState {
+  pub locked: bool,
+  base_bank: u8,
+  rom_bank: u8,
+  bank_mask: u8,
+}
+
+impl SachenState {
+  pub fn new() -> SachenState {
+    SachenState {
+      locked: true,
+      base_bank: 0x00,
+      rom_bank: 0x01,
+      bank_mask: 0x00,
+    }
+  }
+  /// Maps a $0000-$7FFF address to the address seen by the ROM chip
+  pub fn swizzle(&self, addr: u16) -> u16 {
+    match addr {
+      0x0100..=0x014f if self.locked => addr | 0x0080,
+      _ => addr,
+    }
+  }
+  pub fn write(&mut self, reladdr: u16, value: u8) {
+    // The outer bank registers are only writable while ROM bank bits 4-5 are set
+    let outer_writable = self.rom_bank & 0x30 == 0x30;
+    match reladdr >> 8 {
+      0x00..=0x1f if outer_writable => self.base_bank = value,
+      0x20..=0x3f => self.rom_bank = if value == 0 { 1 } else { value },
+      0x40..=0x5f if outer_writable => self.bank_mask = value,
+      _ => (),
+    }
+  }
+  pub fn rom_offsets(&self) -> (usize, usize) {
+    let lower = self.base_bank & self.bank_mask;
+    let upper = lower | (self.rom_bank & !self.bank_mask);
+    (
+      ROM_BANK_SIZE * lower as usize,
+      ROM_BANK_SIZE * upper as usize,
+    )
+  }
+}
@@ -0,0 +1,353 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use super::rtc::{host_timestamp, RtcCounter};
+
+/// TAMA5 internal RAM size in bytes
+pub const TAMA5_RAM_SIZE: usize = 32;
+/// RTC state appended to the save RAM: clock value and a UNIX timestamp
+pub const TAMA5_RTC_SAVE_SIZE: usize = 16;
+
+const SECONDS_PER_DAY: u64 = 86400;
+/// Number of nibble registers in the RTC time page
+const RTC_REGISTERS: u8 = 13;
+
+// Register numbers selected by writing to $A001
+const BANK_LO: u8 = 0x0;
+const BANK_HI: u8 = 0x1;
+const WRITE_LO: u8 = 0x4;
+const WRITE_HI: u8 = 0x5;
+const CONTROL: u8 = 0x6;
+const ADDR_LO: u8 = 0x7;
+const ACTIVE: u8 = 0xa;
+const READ_LO: u8 = 0xc;
+const READ_HI: u8 = 0xd;
+
+/// TAMA5 mapper state.
+///
+/// All accesses go through a nibble-wide register interface: writing to $A001 selects a register,
+/// and $A000 writes the low nibble of the selected register or reads the result of the last
+/// operation. The control register selects the operation that writing ADDR_LO performs:
+///
+/// - 0x0: write WRITE_HI:WRITE_LO to RAM
+/// - 0x2: read RAM, so READ_LO/READ_HI return the byte
+/// - 0x8: RTC access, where WRITE_LO selects a time register and ADDR_LO 0 writes WRITE_HI to it
+///
+/// The control register bit 0 is also RAM address bit 4
+#[derive(Debug, Clone, Default)]
+pub struct Tama5State {
+  reg: u8,
+  registers: [u8; 8],
+  pub rtc: Tama5Rtc,
+}
+
+impl Tama5State {
+  pub fn rom_bank(&self) -> u8 {
+    self.registers[BANK_LO as usize] | (self.registers[BANK_HI as usize] & 0b1) << 4
+  }
+  fn ram_addr(&self) -> usize {
+    ((self.registers[CONTROL as usize] as usize & 0b1) << 4)
+      | self.registers[ADDR_LO as usize] as usize
+  }
+  fn write_data(&self) -> u8 {
+    self.registers[WRITE_HI as usize] << 4 | self.registers[WRITE_LO as usize]
+  }
+  pub fn read(&self, addr: u16, ram: &[u8]) -> u8 {
+    if addr & 0b1 != 0 {
+      return 0xff;
+    }
+    match self.reg {
+      READ_LO | READ_HI => {
+        let value = match self.registers[CONTROL as usize] >> 1 {
+          0x1 => ram[self.ram_addr()],
+          0x4 => self.rtc.read(self.registers[WRITE_LO as usize]),
+          _ => 0x00,
+        };
+        let value = if self.reg == READ_HI {
+          value >> 4
+        } else {
+          value
+        };
+        0xf0 | (value & 0x0f)
+      }
+      // The mapper is always ready, which games check after selecting ACTIVE
+      ACTIVE => 0xf1,
+      _ => 0xf1,
+    }
+  }
+  /// Handles a write to $A000-$A001
+  pub fn write(&mut self, addr: u16, value: u8, ram: &mut [u8]) {
+    if addr & 0b1 != 0 {
+      self.reg = value;
+      return;
+    }
+    let reg = self.reg;
+    if reg as usize >= self.registers.len() {
+      return;
+    }
+    self.registers[reg as usize] = value & 0x0f;
+    if reg == ADDR_LO {
+      match self.registers[CONTROL as usize] >> 1 {
+        0x0 => ram[self.ram_addr()] = self.write_data(),
+        0x4 if self.registers[ADDR_LO as usize] == 0 => self.rtc.write(
+          self.registers[WRITE_LO as usize],
+          self.registers[WRITE_HI as usize],
+        ),
+        _ => (),
+      }
+    }
+  }
+}
+
+/// TAMA5 RTC, exposed as a TC8521-style page of BCD nibble registers: seconds, minutes, hours,
+/// day of week, day, month and two-digit year.
+///
+/// The clock runs on emulated time. Save data stores a host timestamp, so the time the emulator
+/// was closed is added when it's loaded
+#[derive(Debug, Clone, Default)]
+pub struct Tama5Rtc {
+  /// Seconds since 2000-01-01 00:00:00
+  counter: RtcCounter,
+}
+
+impl Tama5Rtc {
+  pub fn tick_cycle(&mut self) {
+    self.counter.tick_cycle();
+  }
+  fn now(&self) -> u64 {
+    self.counter.seconds()
+  }
+  fn fields(&self) -> [u8; RTC_REGISTERS as usize] {
+    let now = self.now();
+    let (days, time) = (now / SECONDS_PER_DAY, now % SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    let (second, minute, hour) = (time % 60, time / 60 % 60, time / 3600);
+    // 2000-01-01 was a Saturday
+    let weekday = (days + 6) % 7;
+    [
+      (second % 10) as u8,
+      (second / 10) as u8,
+      (minute % 10) as u8,
+      (minute / 10) as u8,
+      (hour % 10) as u8,
+      (hour / 10) as u8,
+      weekday as u8,
+      (day % 10) as u8,
+      (day / 10) as u8,
+      (month % 10) as u8,
+      (month / 10) as u8,
+      (year % 10) as u8,
+      (year / 10 % 10) as u8,
+    ]
+  }
+  pub fn read(&self, index: u8) -> u8 {
+    match index {
+      0..=12 => self.fields()[index as usize],
+      _ => 0x0,
+    }
+  }
+  pub fn write(&mut self, index: u8, value: u8) {
+    if index >= RTC_REGISTERS {
+      return;
+    }
+    let mut fields = self.fields();
+    fields[index as usize] = value;
+    // Digits above 9 and out-of-range values are clamped, so the fields always describe a
+    // valid date and time
+    let bcd = |lo: usize| u64::from(fields[lo].min(9)) + u64::from(fields[lo + 1].min(9)) * 10;
+    let (second, minute, hour) = (bcd(0).min(59), bcd(2).min(59), bcd(4).min(23));
+    let (month, year) = (bcd(9).clamp(1, 12), bcd(11));
+    let day = bcd(7).clamp(1, days_in_month(year, month));
+    let days = days_from_civil(year, month, day);
+    self
+      .counter
+      .set_seconds(days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second);
+  }
+  pub fn save(&self) -> [u8; TAMA5_RTC_SAVE_SIZE] {
+    let mut data = [0; TAMA5_RTC_SAVE_SIZE];
+    data[0..8].copy_from_slice(&self.now().to_le_bytes());
+    data[8..16].copy_from_slice(&host_timestamp().to_le_bytes());
+    data
+  }
+  pub fn load(&mut self, data: &[u8]) {
+    if data.len() < TAMA5_RTC_SAVE_SIZE {
+      return;
+    }
+    let mut seconds = [0; 8];
+    let mut timestamp = [0; 8];
+    seconds.copy_from_slice(&data[0..8]);
+    timestamp.copy_from_slice(&data[8..16]);
+    self
+      .counter
+      .load(u64::from_le_bytes(seconds), u64::from_le_bytes(timestamp));
+  }
+}
+
+/// Converts days since 2000-01-01 to (year since 2000, month, day)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+  // Count days from 0000-03-01, so that leap days are at the end of each year and 400-year era
+  let days = days + 730_425;
+  let era = days / 146_097;
+  let day_of_era = days % 146_097;
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year - 2000, month, day)
+}
+
+/// Converts (year since 2000, month, day) to days since 2000-01-01
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+  let year = year + 2000 - if month <= 2 { 1 } else { 0 };
+  let era = year / 400;
+  let year_of_era = year % 400;
+  let mp = if month > 2 { month - 3 } else { month + 9 };
+  let day_of_year = (153 * mp + 2) / 5 + day - 1;
+  let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  (era * 146_097 + day_of_era).saturating_sub(730_425)
+}
+
+/// Returns the number of days in a month of a year since 2000
+fn days_in_month(year: u64, month: u64) -> u64 {
+  let next = if month == 12 {
+    days_from_civil(year + 1, 1, 1)
+  } else {
+    days_from_civil(year, month + 1, 1)
+  };
+  next - days_from_civil(year, month, 1)
+}
+
+#[test]
+fn test_civil_days() {
+  assert_eq!(civil_from_days(0), (0, 1, 1));
+  assert_eq!(civil_from_days(59), (0, 2, 29));
+  assert_eq!(civil_from_days(366), (1, 1, 1));
+  for &days in &[0, 59, 60, 365, 366, 10_000, 36_524] {
+    let (year, month, day) = civil_from_days(days);
+    assert_eq!(days_from_civil(year, month, day), days);
+  }
+}
+
+/// Selects a register and writes a nibble to it
+#[cfg(test)]
+fn write_register(state: &mut Tama5State, ram: &mut [u8], reg: u8, value: u8) {
+  state.write(0xa001, reg, ram);
+  state.write(0xa000, value, ram);
+}
+
+#[cfg(test)]
+fn read_register(state: &mut Tama5State, ram: &mut [u8], reg: u8) -> u8 {
+  state.write(0xa001, reg, ram);
+  state.read(0xa000, ram)
+}
+
+#[cfg(test)]
+fn write_rtc(state: &mut Tama5State, ram: &mut [u8], index: u8, value: u8) {
+  write_register(state, ram, CONTROL, 0x8);
+  write_register(state, ram, WRITE_LO, index);
+  write_register(state, ram, WRITE_HI, value);
+  write_register(state, ram, ADDR_LO, 0x0);
+}
+
+#[cfg(test)]
+fn read_rtc(state: &mut Tama5State, ram: &mut [u8], index: u8) -> u8 {
+  write_register(state, ram, CONTROL, 0x8);
+  write_register(state, ram, WRITE_LO, index);
+  read_register(state, ram, READ_LO) & 0x0f
+}
+
+#[test]
+fn test_tama5_registers() {
+  let mut state = Tama5State::default();
+  let mut ram = [0; TAMA5_RAM_SIZE];
+  write_register(&mut state, &mut ram, BANK_LO, 0x5);
+  write_register(&mut state, &mut ram, BANK_HI, 0x1);
+  assert_eq!(state.rom_bank(), 0x15);
+  assert_eq!(read_register(&mut state, &mut ram, ACTIVE), 0xf1);
+  assert_eq!(state.read(0xa001, &ram), 0xff);
+
+  // RAM write to address 0x13: control bit 0 is address bit 4
+  write_register(&mut state, &mut ram, CONTROL, 0x1);
+  write_register(&mut state, &mut ram, WRITE_LO, 0xb);
+  write_register(&mut state, &mut ram, WRITE_HI, 0xa);
+  write_register(&mut state, &mut ram, ADDR_LO, 0x3);
+  assert_eq!(ram[0x13], 0xab);
+  assert_eq!(ram[0x03], 0x00);
+
+  // RAM read
+  ram[0x07] = 0x5c;
+  write_register(&mut state, &mut ram, CONTROL, 0x2);
+  write_register(&mut state, &mut ram, ADDR_LO, 0x7);
+  assert_eq!(read_register(&mut state, &mut ram, READ_LO), 0xfc);
+  assert_eq!(read_register(&mut state, &mut ram, READ_HI), 0xf5);
+}
+
+#[test]
+fn test_tama5_rtc() {
+  let mut state = Tama5State::default();
+  let mut ram = [0; TAMA5_RAM_SIZE];
+  // 2000-01-01 00:00:00, a Saturday
+  assert_eq!(read_rtc(&mut state, &mut ram, 6), 6);
+  assert_eq!(read_rtc(&mut state, &mut ram, 7), 1);
+
+  // Set 23:59:59 digit by digit
+  for &(index, value) in &[(0, 9), (1, 5), (2, 9), (3, 5), (4, 3), (5, 2)] {
+    write_rtc(&mut state, &mut ram, index, value);
+    assert_eq!(read_rtc(&mut state, &mut ram, index), value);
+  }
+  for _ in 0..super::rtc::MACHINE_CYCLES_PER_SECOND {
+    state.rtc.tick_cycle();
+  }
+  let time = (0..6)
+    .map(|index| read_rtc(&mut state, &mut ram, index))
+    .collect::<Vec<_>>();
+  assert_eq!(time, [0, 0, 0, 0, 0, 0]);
+  assert_eq!(read_rtc(&mut state, &mut ram, 7), 2);
+  assert_eq!(read_rtc(&mut state, &mut ram, 6), 0);
+}
+
+#[test]
+fn test_tama5_rtc_range() {
+  let mut state = Tama5State::default();
+  let mut ram = [0; TAMA5_RAM_SIZE];
+  // Hour 25 is clamped to 23
+  write_rtc(&mut state, &mut ram, 4, 5);
+  write_rtc(&mut state, &mut ram, 5, 2);
+  assert_eq!(read_rtc(&mut state, &mut ram, 4), 3);
+  assert_eq!(read_rtc(&mut state, &mut ram, 5), 2);
+  // Non-BCD digits are clamped to 9
+  write_rtc(&mut state, &mut ram, 2, 0xf);
+  assert_eq!(read_rtc(&mut state, &mut ram, 2), 9);
+  // February 31st is clamped to the last day of February (2000 is a leap year)
+  write_rtc(&mut state, &mut ram, 9, 2);
+  write_rtc(&mut state, &mut ram, 8, 3);
+  assert_eq!(read_rtc(&mut state, &mut ram, 8), 2);
+  assert_eq!(read_rtc(&mut state, &mut ram, 7), 9);
+  // Month 0 is clamped to January
+  write_rtc(&mut state, &mut ram, 9, 0);
+  assert_eq!(read_rtc(&mut state, &mut ram, 9), 1);
+}
+
+#[test]
+fn test_tama5_rtc_save() {
+  let mut rtc = Tama5Rtc::default();
+  rtc.counter.set_seconds(1_000_000);
+  let mut loaded = Tama5Rtc::default();
+  loaded.load(&rtc.save());
+  assert!((1_000_000..=1_000_001).contains(&loaded.now()));
+}
@@ -17,7 +17,7 @@ use bitflags::bitflags;
 use std::fmt;
 use std::fmt::{Binary, Formatter, LowerHex, UpperHex};
 
-use crate::hardware::interrupts::{InterruptLine, InterruptRequest, Interrupts};
+use crate::hardware::interrupts::{InterruptLine, InterruptRequest};
 use crate::GbKey;
 
 /// Gameboy joypad.
@@ -29,7 +29,10 @@ use crate::GbKey;
 /// The joypad register can be used to access the state of four keys at a time.
 ///
 /// # Joypad interrupt
-/// Whenever a key is pressed, a joypad interrupt is requested.
+/// A joypad interrupt is requested whenever one of the P10-P13 input lines goes from high
+/// to low. Only keys in the groups selected with P14/P15 affect the input lines, so
+/// pressing an unselected key doesn't request an interrupt, but selecting a group with a
+/// key already held down does.
 #[derive(Clone)]
 pub struct Joypad {
   pressed_directional: P1,
@@ -51,27 +54,31 @@ impl Joypad {
     // so they automatically become 1 and no mask is needed
     !self.register.bits
   }
-  pub fn set_register(&mut self, value: u8) {
-    // Invert bits before converting to P1
-    self.register = P1::from_bits_truncate(!value);
-    self.update_register();
+  pub fn set_register<I: InterruptRequest>(&mut self, value: u8, intr_req: &mut I) {
+    // Invert bits before converting to P1. The input lines are kept, so update_register can
+    // detect which of them go low
+    let select = P1::from_bits_truncate(!value) & P1::WRITABLE;
+    self.register = (self.register & P1::INPUT) | select;
+    self.update_register(intr_req);
   }
 
-  pub fn key_down(&mut self, key: GbKey, interrupts: &mut Interrupts) {
+  pub fn key_down<I: InterruptRequest>(&mut self, key: GbKey, intr_req: &mut I) {
     self.pressed_directional.insert(P1::directional(&key));
     self.pressed_button.insert(P1::button(&key));
-    self.update_register();
-    interrupts.request_t12_interrupt(InterruptLine::JOYPAD);
+    self.update_register(intr_req);
   }
-  pub fn key_up(&mut self, key: GbKey) {
+  pub fn key_up<I: InterruptRequest>(&mut self, key: GbKey, intr_req: &mut I) {
     self.pressed_directional.remove(P1::directional(&key));
     self.pressed_button.remove(P1::button(&key));
-    self.update_register();
+    self.update_register(intr_req);
   }
 
   /// Updates the register state based on select bits P14-P15 and the
-  /// pressed buttons
-  fn update_register(&mut self) {
+  /// pressed buttons.
+  ///
+  /// Requests a joypad interrupt if any input line P10-P13 went low
+  fn update_register<I: InterruptRequest>(&mut self, intr_req: &mut I) {
+    let old_lines = self.register & P1::INPUT;
     self.register &= P1::WRITABLE;
     if self.register.contains(P1::SELECT_DIRECTIONAL) {
       self.register.insert(self.pressed_directional);
@@ -79,6 +86,10 @@ impl Joypad {
     if self.register.contains(P1::SELECT_BUTTON) {
       self.register.insert(self.pressed_button);
     }
+    let new_lines = self.register & P1::INPUT;
+    if !(new_lines - old_lines).is_empty() {
+      intr_req.request_t12_interrupt(InterruptLine::JOYPAD);
+    }
   }
 }
 
@@ -111,6 +122,10 @@ bitflags!(
     const SELECT_DIRECTIONAL = 1 << 4; // P14: Select dpad
     const SELECT_BUTTON      = 1 << 5; // P15: Select buttons
 
+    /// P10-P13 input lines
+    const INPUT =
+      P1::P10.bits | P1::P11.bits | P1::P12.bits | P1::P13.bits;
+
     /// Only select bits are writable
     const WRITABLE =
       P1::SELECT_DIRECTIONAL.bits | P1::SELECT_BUTTON.bits;
@@ -141,3 +156,53 @@ impl P1 {
     }
   }
 }
+
+#[test]
+fn test_joypad_interrupt_unselected_key() {
+  let mut joypad = Joypad::new();
+  let mut intr = InterruptLine::empty();
+  // Select buttons only
+  joypad.set_register(0x10, &mut intr);
+  joypad.key_down(GbKey::Right, &mut intr);
+  assert!(intr.is_empty());
+  assert_eq!(joypad.get_register() & 0x0f, 0x0f);
+}
+
+#[test]
+fn test_joypad_interrupt_selected_key() {
+  let mut joypad = Joypad::new();
+  let mut intr = InterruptLine::empty();
+  // Select the dpad only
+  joypad.set_register(0x20, &mut intr);
+  joypad.key_down(GbKey::Right, &mut intr);
+  assert_eq!(intr, InterruptLine::JOYPAD);
+  assert_eq!(joypad.get_register() & 0x0f, 0x0e);
+}
+
+#[test]
+fn test_joypad_interrupt_select_held_key() {
+  let mut joypad = Joypad::new();
+  let mut intr = InterruptLine::empty();
+  joypad.set_register(0x30, &mut intr);
+  joypad.key_down(GbKey::Start, &mut intr);
+  assert!(intr.is_empty());
+  joypad.set_register(0x10, &mut intr);
+  assert_eq!(intr, InterruptLine::JOYPAD);
+  assert_eq!(joypad.get_register() & 0x0f, 0x07);
+}
+
+#[test]
+fn test_joypad_interrupt_same_select() {
+  let mut joypad = Joypad::new();
+  let mut intr = InterruptLine::empty();
+  joypad.set_register(0x10, &mut intr);
+  joypad.key_down(GbKey::A, &mut intr);
+  assert_eq!(intr, InterruptLine::JOYPAD);
+  let mut intr = InterruptLine::empty();
+  joypad.set_register(0x10, &mut intr);
+  // The low nibble is read-only, so writing it doesn't affect the input lines
+  joypad.set_register(0x1f, &mut intr);
+  joypad.set_register(0x10, &mut intr);
+  assert!(intr.is_empty());
+  assert_eq!(joypad.get_register() & 0x0f, 0x0e);
+}
@@ -283,6 +283,21 @@ impl Ppu {
     }
     self.oam[(addr as usize & 0xff)] = value;
   }
+  /// Video RAM, ignoring mode-based access restrictions
+  pub fn vram(&self) -> &[u8] {
+    &self.vram[..]
+  }
+  pub fn vram_mut(&mut self) -> &mut [u8] {
+    &mut self.vram[..]
+  }
+  /// OAM, ignoring mode-based access restrictions. Only the first 160 bytes are accessible
+  /// through the memory bus
+  pub fn oam(&self) -> &[u8] {
+    &self.oam[..0xa0]
+  }
+  pub fn oam_mut(&mut self) -> &mut [u8] {
+    &mut self.oam[..0xa0]
+  }
   pub fn read_video_ram(&self, addr: u16) -> u8 {
     if self.mode == Mode::AccessVram {
       return UNDEFINED_READ;
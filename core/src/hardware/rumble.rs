@@ -0,0 +1,124 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use crate::emulation::{EmuTime, RumbleState};
+
+/// Duty cycle measurement window: one frame in machine cycles
+const WINDOW_CYCLES: u64 = 17556;
+
+/// Tracks the rumble motor duty cycle.
+///
+/// Games control the motor strength by toggling it rapidly, so the intensity is the fraction of
+/// the last complete frame-length window the motor was on
+#[derive(Clone, Copy, Debug)]
+pub struct Rumble {
+  motor_on: bool,
+  last_change: EmuTime,
+  window_start: EmuTime,
+  on_cycles: u64,
+  intensity: f32,
+  reported: RumbleState,
+}
+
+impl Rumble {
+  pub fn new() -> Rumble {
+    Rumble {
+      motor_on: false,
+      last_change: EmuTime::zero(),
+      window_start: EmuTime::zero(),
+      on_cycles: 0,
+      intensity: 0.0,
+      reported: RumbleState {
+        motor_on: false,
+        intensity: 0.0,
+      },
+    }
+  }
+  pub fn is_motor_on(&self) -> bool {
+    self.motor_on
+  }
+  pub fn set_motor(&mut self, motor_on: bool, now: EmuTime) {
+    self.advance(now);
+    self.accumulate(now);
+    self.motor_on = motor_on;
+  }
+  pub fn state(&self, now: EmuTime) -> RumbleState {
+    let mut rumble = *self;
+    rumble.advance(now);
+    RumbleState {
+      motor_on: rumble.motor_on,
+      intensity: rumble.intensity,
+    }
+  }
+  /// Returns true if the state has changed since the previous poll
+  pub fn poll(&mut self, now: EmuTime) -> bool {
+    let state = self.state(now);
+    let changed = state != self.reported;
+    self.reported = state;
+    changed
+  }
+  fn accumulate(&mut self, until: EmuTime) {
+    if self.motor_on {
+      self.on_cycles += (until - self.last_change).machine_cycles;
+    }
+    self.last_change = until;
+  }
+  /// Finishes the current window if `now` is past its end
+  fn advance(&mut self, now: EmuTime) {
+    let window_end = self.window_start + EmuTime::from_machine_cycles(WINDOW_CYCLES);
+    if now < window_end {
+      return;
+    }
+    self.accumulate(window_end);
+    self.intensity = self.on_cycles as f32 / WINDOW_CYCLES as f32;
+    self.on_cycles = 0;
+    let skipped_windows = (now - window_end).machine_cycles / WINDOW_CYCLES;
+    if skipped_windows > 0 {
+      self.intensity = if self.motor_on { 1.0 } else { 0.0 };
+    }
+    self.window_start = window_end + EmuTime::from_machine_cycles(skipped_windows * WINDOW_CYCLES);
+    self.last_change = self.window_start;
+  }
+}
+
+#[test]
+fn test_rumble_duty_cycle() {
+  let mut rumble = Rumble::new();
+  let at = EmuTime::from_machine_cycles;
+  rumble.set_motor(true, at(0));
+  rumble.set_motor(false, at(WINDOW_CYCLES / 4));
+  assert_eq!(rumble.state(at(WINDOW_CYCLES / 2)).intensity, 0.0);
+  assert_eq!(rumble.state(at(WINDOW_CYCLES)).intensity, 0.25);
+  rumble.set_motor(true, at(WINDOW_CYCLES + 1));
+  let state = rumble.state(at(WINDOW_CYCLES * 5));
+  assert!(state.motor_on);
+  assert_eq!(state.intensity, 1.0);
+}
+
+#[test]
+fn test_rumble_poll() {
+  let mut rumble = Rumble::new();
+  let at = EmuTime::from_machine_cycles;
+  assert!(!rumble.poll(at(0)));
+  // Toggling within a window is only reported once
+  rumble.set_motor(true, at(10));
+  rumble.set_motor(false, at(20));
+  rumble.set_motor(true, at(30));
+  assert!(rumble.poll(at(40)));
+  assert!(!rumble.poll(at(50)));
+  // The new intensity is reported when the window ends
+  assert!(rumble.poll(at(WINDOW_CYCLES)));
+  assert!(!rumble.poll(at(WINDOW_CYCLES + 10)));
+}
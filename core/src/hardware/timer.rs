@@ -57,6 +57,10 @@ impl Timer {
       enabled: false,
     }
   }
+  /// DIV-APU signal used to clock the APU frame sequencer (DIV bit 4)
+  pub fn div_apu(&self) -> bool {
+    self.internal_counter & (1 << 10) != 0
+  }
   fn counter_bit(&self) -> bool {
     (self.internal_counter & self.tac.counter_mask()) != 0
   }
@@ -81,9 +85,26 @@ impl Timer {
       self.internal_counter = self.internal_counter.wrapping_add(1);
     }
   }
+  /// Returns the value of a timer register (DIV, TIMA, TMA or TAC) without ticking the timer
+  pub fn peek_reg(&self, addr: u16) -> u8 {
+    match addr & 0xff {
+      0x04 => self.div(),
+      0x05 => self.counter,
+      0x06 => self.modulo,
+      0x07 => self.tac(),
+      _ => 0xff,
+    }
+  }
+  fn div(&self) -> u8 {
+    (self.internal_counter >> 6) as u8
+  }
+  fn tac(&self) -> u8 {
+    const TAC_UNUSED: u8 = 0b11111_000;
+    TAC_UNUSED | self.tac.bits()
+  }
   pub fn div_read_cycle<I: InterruptRequest>(&mut self, intr_req: &mut I) -> u8 {
     self.tick_cycle(intr_req);
-    (self.internal_counter >> 6) as u8
+    self.div()
   }
   pub fn div_write_cycle<I: InterruptRequest>(&mut self, intr_req: &mut I) {
     self.tick_cycle(intr_req);
@@ -118,8 +139,7 @@ impl Timer {
   }
   pub fn tac_read_cycle<I: InterruptRequest>(&mut self, intr_req: &mut I) -> u8 {
     self.tick_cycle(intr_req);
-    const TAC_UNUSED: u8 = 0b11111_000;
-    TAC_UNUSED | self.tac.bits()
+    self.tac()
   }
   pub fn tac_write_cycle<I: InterruptRequest>(&mut self, value: u8, intr_req: &mut I) {
     self.tick_cycle(intr_req);
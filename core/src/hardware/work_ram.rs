@@ -25,6 +25,13 @@ impl WorkRam {
     }
   }
 
+  pub fn data(&self) -> &[u8] {
+    &self.ram[..]
+  }
+  pub fn data_mut(&mut self) -> &mut [u8] {
+    &mut self.ram[..]
+  }
+
   pub fn read_lower(&self, addr: u16) -> u8 {
     self.ram[(addr as usize) & 0x1fff]
   }
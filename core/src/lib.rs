@@ -13,12 +13,18 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+pub mod camera;
+pub mod cheats;
 pub mod config;
 mod cpu;
+pub mod debugger;
+pub mod disasm;
 pub mod emulation;
 pub mod gameboy;
+pub mod gdb;
 mod hardware;
 pub mod machine;
+pub mod trace;
 mod util;
 
 #[derive(Debug)]
@@ -13,19 +13,38 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use std::io;
+
+use crate::camera::CameraImage;
+use crate::cheats::Cheats;
 use crate::config::HardwareConfig;
 use crate::cpu::register_file::RegisterFile;
 use crate::cpu::{Cpu, Step};
-use crate::emulation::{EmuEvents, EmuTime};
+use crate::debugger::{Breakpoint, BreakpointHit, BreakpointId};
+use crate::disasm::{self, Instruction};
+use crate::emulation::{EmuEvents, EmuTime, RumbleState};
 use crate::gameboy;
 use crate::hardware::Hardware;
+use crate::trace::Tracer;
 use crate::GbKey;
 
-#[derive(Clone)]
 pub struct Machine {
   cpu: Cpu,
   hardware: Hardware,
   step: Step,
+  tracer: Option<Tracer>,
+}
+
+impl Clone for Machine {
+  /// Clones the machine state. The clone doesn't have a tracer
+  fn clone(&self) -> Machine {
+    Machine {
+      cpu: self.cpu.clone(),
+      hardware: self.hardware.clone(),
+      step: self.step,
+      tracer: None,
+    }
+  }
 }
 
 impl Machine {
@@ -34,24 +53,73 @@ impl Machine {
       cpu: Cpu::new(),
       hardware: Hardware::new(config),
       step: Step::Running,
+      tracer: None,
     }
   }
   pub fn emulate_step(&mut self) -> (EmuEvents, EmuTime) {
+    self.hardware.peripherals.breakpoints.clear_hit();
     let step = self.cpu.execute_step(&mut self.hardware, self.step);
     self.step = step;
+    self.trace();
+    self.apply_frame_cheats();
+    self.hardware.poll_rumble();
     (self.hardware.ack_emu_events(), self.hardware.emu_time())
   }
   pub fn emulate(&mut self, target_time: EmuTime) -> (EmuEvents, EmuTime) {
+    self.hardware.peripherals.breakpoints.clear_hit();
     let mut step = self.step;
     loop {
       step = self.cpu.execute_step(&mut self.hardware, step);
+      if self.tracer.is_some() {
+        self.step = step;
+        self.trace();
+      }
       if !self.hardware.emu_events().is_empty() || self.hardware.emu_time() >= target_time {
         break;
       }
     }
     self.step = step;
+    self.apply_frame_cheats();
+    self.hardware.poll_rumble();
     (self.hardware.ack_emu_events(), self.hardware.emu_time())
   }
+  /// Traces the instruction that is about to be executed
+  fn trace(&mut self) {
+    let pc = self.pc();
+    if let (Some(tracer), Step::Running) = (&mut self.tracer, self.step) {
+      let pcmem = [
+        self.hardware.peek(pc),
+        self.hardware.peek(pc.wrapping_add(1)),
+        self.hardware.peek(pc.wrapping_add(2)),
+        self.hardware.peek(pc.wrapping_add(3)),
+      ];
+      let bank = self.hardware.peripherals.bank_at(pc);
+      tracer.trace(&self.cpu.regs, pc, pcmem, bank, self.hardware.emu_time());
+    }
+  }
+  /// Sets the instruction tracer, and returns the previous one. Instructions are traced by
+  /// `emulate` and `emulate_step` right before they are executed
+  pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+    std::mem::replace(&mut self.tracer, tracer)
+  }
+  /// Writes buffered trace lines
+  pub fn flush_trace(&mut self) -> io::Result<()> {
+    match &mut self.tracer {
+      Some(tracer) => tracer.flush(),
+      None => Ok(()),
+    }
+  }
+  /// Applies GameShark codes if VBlank has just started
+  fn apply_frame_cheats(&mut self) {
+    if self.hardware.emu_events().contains(EmuEvents::VSYNC) {
+      self.hardware.apply_game_shark_codes();
+    }
+  }
+  /// Sets the active cheats. Game Genie codes patch ROM reads, and GameShark codes are written to
+  /// RAM at every VBlank. Call this again after enabling or disabling cheats
+  pub fn set_cheats(&mut self, cheats: &Cheats) {
+    self.hardware.set_cheats(cheats);
+  }
   pub fn emu_time(&self) -> EmuTime {
     self.hardware.emu_time()
   }
@@ -61,6 +129,106 @@ impl Machine {
   pub fn key_up(&mut self, key: GbKey) {
     self.hardware.key_up(key);
   }
+  /// Returns the battery-backed cartridge data, or None if the cartridge has no battery
+  pub fn save_data(&self) -> Option<Vec<u8>> {
+    self.hardware.save_data()
+  }
+  pub fn load_save_data(&mut self, data: &[u8]) {
+    self.hardware.load_save_data(data);
+  }
+  /// Sets the accelerometer input of tilt sensor cartridges (MBC7), in units of g.
+  ///
+  /// Positive x tilts the right side down and positive y tilts the bottom side down
+  pub fn set_tilt(&mut self, x: f32, y: f32) {
+    self.hardware.set_tilt(x, y);
+  }
+  /// Returns the ROM data of flash carts, including any changes made by the program, or None if
+  /// the cartridge isn't a flash cart
+  pub fn flash_data(&self) -> Option<&[u8]> {
+    self.hardware.flash_data()
+  }
+  /// Returns the rumble motor state. `EmuEvents::RUMBLE` is raised when the state has changed
+  /// since the previous `emulate` or `emulate_step` call
+  pub fn rumble_state(&self) -> RumbleState {
+    self.hardware.rumble_state()
+  }
+  /// Sets the images seen by the Pocket Camera sensor.
+  ///
+  /// Each capture uses the next image, looping back to the first one, so a single image works as
+  /// a still picture
+  pub fn set_camera_frames(&mut self, frames: Vec<CameraImage>) {
+    self.hardware.set_camera_frames(frames);
+  }
+  /// Reads a byte from the memory map without advancing time or triggering any side effects.
+  ///
+  /// VRAM, OAM and wave RAM are readable in all PPU and APU modes, and I/O registers return their
+  /// current value
+  pub fn peek(&self, addr: u16) -> u8 {
+    self.hardware.peek(addr)
+  }
+  /// Disassembles the instruction at an address in the memory map without side effects
+  pub fn disassemble(&self, addr: u16) -> Instruction {
+    let bytes = [
+      self.peek(addr),
+      self.peek(addr.wrapping_add(1)),
+      self.peek(addr.wrapping_add(2)),
+    ];
+    disasm::disassemble(&bytes, addr).expect("Instructions are at most 3 bytes long")
+  }
+  /// Writes a byte to RAM in the memory map without advancing time. VRAM and OAM are writable in
+  /// all PPU modes. Writes to ROM and I/O registers other than IF and IE are ignored, so
+  /// cartridge mapper state is never changed
+  pub fn poke(&mut self, addr: u16, value: u8) {
+    self.hardware.poke(addr, value);
+  }
+  /// Video RAM ($8000-$9FFF)
+  pub fn vram(&self) -> &[u8] {
+    self.hardware.vram()
+  }
+  /// Object attribute memory ($FE00-$FE9F)
+  pub fn oam(&self) -> &[u8] {
+    self.hardware.oam()
+  }
+  /// Work RAM ($C000-$DFFF)
+  pub fn wram(&self) -> &[u8] {
+    self.hardware.wram()
+  }
+  /// High RAM ($FF80-$FFFE)
+  pub fn hram(&self) -> &[u8] {
+    &self.hardware.hram()[..0x7f]
+  }
+  /// Returns a 16 KiB cartridge ROM bank, independent of the current mapping
+  pub fn rom_bank(&self, bank: usize) -> Option<&[u8]> {
+    self.hardware.rom_bank(bank)
+  }
+  /// Returns a 8 KiB cartridge RAM bank, independent of the current mapping
+  pub fn ram_bank(&self, bank: usize) -> Option<&[u8]> {
+    self.hardware.ram_bank(bank)
+  }
+  /// Adds a breakpoint or watchpoint. When it's hit, `EmuEvents::BREAKPOINT` is raised and
+  /// emulation stops after the current instruction, or right before executing the instruction
+  /// for execution breakpoints
+  pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+    self.hardware.peripherals.breakpoints.add(breakpoint)
+  }
+  pub fn remove_breakpoint(&mut self, id: BreakpointId) -> bool {
+    self.hardware.peripherals.breakpoints.remove(id)
+  }
+  pub fn clear_breakpoints(&mut self) {
+    self.hardware.peripherals.breakpoints.clear();
+  }
+  /// Returns the first breakpoint hit during the last `emulate` or `emulate_step` call
+  pub fn breakpoint_hit(&self) -> Option<BreakpointHit> {
+    self.hardware.peripherals.breakpoints.hit()
+  }
+  /// Returns the address of the next instruction to be executed
+  pub fn pc(&self) -> u16 {
+    match self.step {
+      // The opcode has already been fetched, so PC points to the byte after it
+      Step::Running => self.cpu.regs.pc.wrapping_sub(1),
+      Step::Halt | Step::InterruptDispatch => self.cpu.regs.pc,
+    }
+  }
   pub fn regs(&self) -> RegisterFile {
     self.cpu.regs
   }
@@ -68,3 +236,116 @@ impl Machine {
     self.hardware.screen_buffer()
   }
 }
+
+/// Trace output that stays readable after the tracer has been given to a machine
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct TraceBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl io::Write for TraceBuffer {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.lock().unwrap().extend_from_slice(buf);
+    Ok(buf.len())
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+fn trace_test_machine() -> (Machine, TraceBuffer) {
+  use crate::config::{Cartridge, CartridgeRamSize, CartridgeRomSize, CartridgeType, Model};
+  use crate::trace::TraceFilter;
+  let mut rom = vec![0x00; 0x8000];
+  let program: &[u8] = &[
+    0x31, 0xfe, 0xff, // $0000: LD SP, $FFFE
+    0x3e, 0xf0, // $0003: LD A, $F0
+    0xe0, 0x05, // $0005: LDH (TIMA), A
+    0x3e, 0x04, // $0007: LD A, $04
+    0xe0, 0xff, // $0009: LDH (IE), A
+    0x3e, 0x05, // $000B: LD A, $05
+    0xe0, 0x07, // $000D: LDH (TAC), A
+    0xfb, // $000F: EI
+    0x76, // $0010: HALT
+    0x00, // $0011: NOP
+    0x18, 0xfe, // $0012: JR $0012
+  ];
+  rom[..program.len()].copy_from_slice(program);
+  // Timer interrupt handler
+  rom[0x0050..0x0052].copy_from_slice(&[0x3c, 0xd9]); // INC A; RETI
+  let mut machine = Machine::new(HardwareConfig {
+    model: Model::Dmg,
+    bootrom: None,
+    cartridge: Cartridge {
+      data: rom.into(),
+      title: String::new(),
+      cartridge_type: CartridgeType::NoMbc {
+        ram: false,
+        battery: false,
+      },
+      rom_size: CartridgeRomSize::NoRomBanks,
+      ram_size: CartridgeRamSize::NoRam,
+      header: None,
+      rom_info: None,
+      save_path: None,
+      flash_path: None,
+    },
+  });
+  let buffer = TraceBuffer::default();
+  machine.set_tracer(Some(Tracer::new(buffer.clone(), TraceFilter::default())));
+  (machine, buffer)
+}
+
+#[cfg(test)]
+const EXPECTED_TRACE: &[&str] = &[
+  "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 PCMEM:31,FE,FF,3E",
+  "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0003 PCMEM:3E,F0,E0,05",
+  "A:F0 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0005 PCMEM:E0,05,3E,04",
+  "A:F0 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0007 PCMEM:3E,04,E0,FF",
+  "A:04 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0009 PCMEM:E0,FF,3E,05",
+  "A:04 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:000B PCMEM:3E,05,E0,07",
+  "A:05 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:000D PCMEM:E0,07,FB,76",
+  "A:05 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:000F PCMEM:FB,76,00,18",
+  "A:05 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0010 PCMEM:76,00,18,FE",
+  // HALT and the interrupt dispatch don't produce lines of their own
+  "A:05 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFC PC:0050 PCMEM:3C,D9,00,00",
+  "A:06 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFC PC:0051 PCMEM:D9,00,00,00",
+  "A:06 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0011 PCMEM:00,18,FE,00",
+  "A:06 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0012 PCMEM:18,FE,00,00",
+];
+
+#[cfg(test)]
+fn check_trace(buffer: &TraceBuffer) {
+  let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+  let lines = text.lines().collect::<Vec<_>>();
+  assert!(lines.len() > EXPECTED_TRACE.len());
+  assert_eq!(&lines[..EXPECTED_TRACE.len()], EXPECTED_TRACE);
+  // The rest of the trace is the JR loop
+  let last = EXPECTED_TRACE[EXPECTED_TRACE.len() - 1];
+  assert!(lines[EXPECTED_TRACE.len()..]
+    .iter()
+    .all(|&line| line == last));
+  assert!(text.ends_with('\n'));
+}
+
+#[test]
+fn test_trace_emulate_step() {
+  let (mut machine, buffer) = trace_test_machine();
+  while machine.emu_time().machine_cycles < 200 {
+    machine.emulate_step();
+  }
+  machine.flush_trace().unwrap();
+  check_trace(&buffer);
+}
+
+#[test]
+fn test_trace_emulate() {
+  let (mut machine, buffer) = trace_test_machine();
+  let target_time = EmuTime::from_machine_cycles(200);
+  while machine.emu_time() < target_time {
+    machine.emulate(target_time);
+  }
+  machine.flush_trace().unwrap();
+  check_trace(&buffer);
+}
@@ -0,0 +1,139 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+//! Instruction trace logging in the Gameboy Doctor format
+//!
+//! Every traced instruction produces one line with the register state before the instruction is
+//! executed, and the four bytes starting at PC:
+//!
+//! `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::cpu::register_file::RegisterFile;
+use crate::emulation::EmuTime;
+
+/// Selects which instructions are traced
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceFilter {
+  /// Only instructions with a PC in this range are traced
+  pub pc: RangeInclusive<u16>,
+  /// Only instructions in this cartridge ROM or RAM bank are traced
+  pub bank: Option<usize>,
+  /// Tracing starts once this many machine cycles have been emulated
+  pub after_cycles: u64,
+}
+
+impl Default for TraceFilter {
+  fn default() -> TraceFilter {
+    TraceFilter {
+      pc: 0x0000..=0xffff,
+      bank: None,
+      after_cycles: 0,
+    }
+  }
+}
+
+impl TraceFilter {
+  fn matches(&self, pc: u16, bank: Option<usize>, time: EmuTime) -> bool {
+    time.machine_cycles >= self.after_cycles
+      && self.pc.contains(&pc)
+      && (self.bank.is_none() || bank == self.bank)
+  }
+}
+
+/// Writes an instruction trace through a buffer.
+///
+/// Tracing stops at the first write error, which is returned by `flush`.
+pub struct Tracer {
+  writer: BufWriter<Box<dyn Write + Send>>,
+  filter: TraceFilter,
+  error: Option<io::Error>,
+}
+
+impl Tracer {
+  pub fn new<W: Write + Send + 'static>(writer: W, filter: TraceFilter) -> Tracer {
+    Tracer {
+      writer: BufWriter::with_capacity(1 << 16, Box::new(writer)),
+      filter,
+      error: None,
+    }
+  }
+  /// Creates a tracer that writes to a new file, replacing an existing one
+  pub fn from_path(path: &Path, filter: TraceFilter) -> io::Result<Tracer> {
+    Ok(Tracer::new(File::create(path)?, filter))
+  }
+  pub fn filter(&self) -> &TraceFilter {
+    &self.filter
+  }
+  /// Writes all buffered lines, and returns the write error that stopped tracing, if any
+  pub fn flush(&mut self) -> io::Result<()> {
+    if let Some(error) = self.error.take() {
+      return Err(error);
+    }
+    self.writer.flush()
+  }
+  pub(crate) fn trace(
+    &mut self,
+    regs: &RegisterFile,
+    pc: u16,
+    pcmem: [u8; 4],
+    bank: Option<usize>,
+    time: EmuTime,
+  ) {
+    if self.error.is_some() || !self.filter.matches(pc, bank, time) {
+      return;
+    }
+    let result = writeln!(
+      self.writer,
+      "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} \
+       PCMEM:{:02X},{:02X},{:02X},{:02X}",
+      regs.a,
+      regs.f.bits(),
+      regs.b,
+      regs.c,
+      regs.d,
+      regs.e,
+      regs.h,
+      regs.l,
+      regs.sp,
+      pc,
+      pcmem[0],
+      pcmem[1],
+      pcmem[2],
+      pcmem[3]
+    );
+    if let Err(error) = result {
+      self.error = Some(error);
+    }
+  }
+}
+
+#[test]
+fn test_trace_filter() {
+  let filter = TraceFilter {
+    pc: 0x4000..=0x7fff,
+    bank: Some(2),
+    after_cycles: 100,
+  };
+  let time = EmuTime::from_machine_cycles(100);
+  assert!(filter.matches(0x4000, Some(2), time));
+  assert!(!filter.matches(0x3fff, Some(0), time));
+  assert!(!filter.matches(0x4000, Some(1), time));
+  assert!(!filter.matches(0x4000, Some(2), EmuTime::from_machine_cycles(99)));
+  assert!(TraceFilter::default().matches(0xff80, None, EmuTime::zero()));
+}
@@ -0,0 +1,115 @@
+// This file is part of Mooneye GB.
+// Copyright (C) 2014-2020 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// Mooneye GB is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Mooneye GB is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
+use anyhow::{anyhow, Error};
+use mooneye_gb::config::Cartridge;
+use mooneye_gb::disasm;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "
+Usage:
+  mooneye-gb disasm [options] <rom>
+
+Options:
+  --bank BANK      Disassemble only one 16 KiB ROM bank
+  --start ADDR     First address to disassemble (hex)
+  --end ADDR       Last address to disassemble (hex)
+";
+
+const ROM_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug)]
+pub struct Args {
+  flag_bank: Option<usize>,
+  flag_start: Option<u16>,
+  flag_end: Option<u16>,
+  arg_rom: PathBuf,
+}
+
+fn parse_path(s: &OsStr) -> Result<PathBuf, &'static str> {
+  Ok(s.into())
+}
+
+fn parse_addr(s: &str) -> Result<u16, Error> {
+  let digits = s
+    .trim_start_matches('$')
+    .trim_start_matches("0x")
+    .trim_start_matches("0X");
+  u16::from_str_radix(digits, 16).map_err(|_| anyhow!("Invalid address \"{}\"", s))
+}
+
+pub fn parse_args(mut args: pico_args::Arguments) -> Result<Args, Error> {
+  let flag_bank = args.opt_value_from_str("--bank")?;
+  let flag_start = args.opt_value_from_fn("--start", parse_addr)?;
+  let flag_end = args.opt_value_from_fn("--end", parse_addr)?;
+  let arg_rom = args.free_from_os_str(parse_path)?;
+  let _ = args.finish();
+  Ok(Args {
+    flag_bank,
+    flag_start,
+    flag_end,
+    arg_rom,
+  })
+}
+
+/// Prints a disassembly of ROM banks to stdout. Bank 0 is disassembled at $0000-$3FFF, and other
+/// banks at $4000-$7FFF where they are mapped by the cartridge
+pub fn run(args: Args) -> Result<(), Error> {
+  let cartridge = Cartridge::from_path_lenient(&args.arg_rom).map_err(|err| {
+    anyhow!(
+      "Failed to read rom from \"{}\" ({})",
+      args.arg_rom.display(),
+      err
+    )
+  })?;
+  let bank_count = cartridge.data.len().div_ceil(ROM_BANK_SIZE);
+  let banks = match args.flag_bank {
+    Some(bank) if bank >= bank_count => {
+      return Err(anyhow!(
+        "ROM bank {} doesn't exist ({} banks)",
+        bank,
+        bank_count
+      ))
+    }
+    Some(bank) => bank..bank + 1,
+    None => 0..bank_count,
+  };
+  let start = args.flag_start.unwrap_or(0x0000);
+  let end = args.flag_end.unwrap_or(0x7fff);
+  for bank in banks {
+    let offset = bank * ROM_BANK_SIZE;
+    let data = &cartridge.data[offset..cartridge.data.len().min(offset + ROM_BANK_SIZE)];
+    let base = if bank == 0 { 0x0000 } else { 0x4000 };
+    for instruction in disasm::disassemble_all(data, base) {
+      if instruction.addr < start || instruction.addr > end {
+        continue;
+      }
+      let start = (instruction.addr - base) as usize;
+      let bytes: Vec<_> = data[start..start + instruction.len as usize]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+      println!(
+        "{:02x}:{:04x}  {:<8}  {}",
+        bank,
+        instruction.addr,
+        bytes.join(" "),
+        instruction
+      );
+    }
+  }
+  Ok(())
+}
@@ -21,10 +21,15 @@ use glium::glutin::event_loop::{ControlFlow, EventLoop};
 use glium::{glutin, Api, Display, Surface, Version};
 use imgui_winit_support::HiDpiMode;
 use log::{error, info};
-use mooneye_gb::config::{Bootrom, Cartridge, HardwareConfig};
+use mooneye_gb::cheats::Cheats;
+use mooneye_gb::config::{Bootrom, Cartridge, CartridgeType, HardwareConfig};
 use mooneye_gb::emulation::{EmuEvents, EmuTime};
+use mooneye_gb::gdb::GdbServer;
 use mooneye_gb::machine::Machine;
+use mooneye_gb::trace::Tracer;
 use mooneye_gb::*;
+use std::fs;
+use std::mem;
 use std::path::Path;
 use std::time::Duration;
 
@@ -38,10 +43,18 @@ mod gui;
 mod renderer;
 
 enum FrontendState {
-  WaitBootrom(Option<Cartridge>, gui::WaitBootromScreen),
+  WaitBootrom(Option<Cartridge>, DebugTools, gui::WaitBootromScreen),
   InGame(InGameState),
 }
 
+/// Debugging tools enabled from the command line. They are moved to the next machine when
+/// another ROM is loaded
+#[derive(Default)]
+pub struct DebugTools {
+  pub tracer: Option<Tracer>,
+  pub gdb: Option<GdbServer>,
+}
+
 impl FrontendState {
   pub fn update_delta_time(&mut self, delta: Duration) {
     if let FrontendState::InGame(state) = self {
@@ -49,7 +62,7 @@ impl FrontendState {
     }
   }
   pub fn handle_gilrs(&mut self, event: gilrs::EventType) {
-    if let FrontendState::InGame(InGameState { machine, .. }) = self {
+    if let FrontendState::InGame(InGameState { machine, tilt, .. }) = self {
       match event {
         EventType::ButtonPressed(button, _) => {
           if let Some(key) = map_button(button) {
@@ -62,7 +75,10 @@ impl FrontendState {
           }
         }
         EventType::AxisChanged(axis, value, _) => {
-          if let Some((key, state)) = map_axis(axis, value) {
+          let tilt_value = tilt.as_mut().and_then(|tilt| tilt.handle_axis(axis, value));
+          if let Some((x, y)) = tilt_value {
+            machine.set_tilt(x, y);
+          } else if let Some((key, state)) = map_axis(axis, value) {
             if state {
               machine.key_down(key);
             } else {
@@ -77,11 +93,20 @@ impl FrontendState {
   pub fn handle_keyboard(&mut self, input: glutin::event::KeyboardInput) {
     use glium::glutin::event::{ElementState, VirtualKeyCode};
     if let FrontendState::InGame(InGameState {
-      machine, screen, ..
+      machine,
+      screen,
+      tilt,
+      ..
     }) = self
     {
       if let Some(keycode) = input.virtual_keycode {
-        if let Some(key) = map_keycode(keycode) {
+        let pressed = input.state == ElementState::Pressed;
+        let tilt_value = tilt
+          .as_mut()
+          .and_then(|tilt| tilt.handle_key(keycode, pressed));
+        if let Some((x, y)) = tilt_value {
+          machine.set_tilt(x, y);
+        } else if let Some(key) = map_keycode(keycode) {
           match input.state {
             ElementState::Pressed => machine.key_down(key),
             ElementState::Released => machine.key_up(key),
@@ -96,30 +121,44 @@ impl FrontendState {
   }
   pub fn tick(&mut self, renderer: &mut Renderer, ui: &imgui::Ui) {
     match self {
-      FrontendState::WaitBootrom(_, screen) => screen.render(ui),
+      FrontendState::WaitBootrom(_, _, screen) => screen.render(ui),
       FrontendState::InGame(state) => {
         state.tick(renderer, ui);
       }
     }
   }
+  pub fn save(&mut self) {
+    if let FrontendState::InGame(state) = self {
+      state.save();
+    }
+  }
   pub fn drop_file(&mut self, path: &Path) {
     match self {
-      FrontendState::WaitBootrom(cartridge, screen) => match Bootrom::from_path(&path) {
+      FrontendState::WaitBootrom(cartridge, debug, screen) => match Bootrom::from_path(&path) {
         Ok(bootrom) => {
           if let Err(error) = bootrom.save_to_data_dir() {
             error!("Failed to save boot rom: {}", error);
           }
-          *self = FrontendState::from_roms(Some(bootrom), cartridge.clone());
+          let debug = mem::take(debug);
+          *self = FrontendState::from_roms(Some(bootrom), cartridge.clone(), debug);
         }
         Err(e) => screen.set_error(format!("{}", e)),
       },
-      FrontendState::InGame(state) => match Cartridge::from_path(path) {
+      FrontendState::InGame(state) => match Cartridge::from_path_lenient(path) {
         Ok(cartridge) => {
-          *self = FrontendState::InGame(InGameState::from_config(HardwareConfig {
-            cartridge,
-            bootrom: state.config.bootrom.clone(),
-            ..state.config
-          }));
+          state.save();
+          let debug = DebugTools {
+            tracer: state.machine.set_tracer(None),
+            gdb: state.gdb.take(),
+          };
+          *self = FrontendState::InGame(InGameState::from_config(
+            HardwareConfig {
+              cartridge,
+              bootrom: state.config.bootrom.clone(),
+              ..state.config
+            },
+            debug,
+          ));
         }
         Err(e) => state.screen.set_error(format!("{}", e)),
       },
@@ -135,11 +174,51 @@ struct InGameState {
   perf_counter: PerfCounter,
   delta: Duration,
   emu_time: EmuTime,
+  tilt: Option<TiltInput>,
+  gdb: Option<GdbServer>,
 }
 
 impl InGameState {
-  pub fn from_config(config: HardwareConfig) -> InGameState {
-    let machine = Machine::new(config.clone());
+  pub fn from_config(config: HardwareConfig, debug: DebugTools) -> InGameState {
+    let mut machine = Machine::new(config.clone());
+    machine.set_tracer(debug.tracer);
+    let mut gdb = debug.gdb;
+    if let Some(gdb) = &mut gdb {
+      gdb.attach(&mut machine);
+    }
+    if let Some(path) = &config.cartridge.save_path {
+      match fs::read(path) {
+        Ok(data) => {
+          info!("Loaded save data from {}", path.display());
+          machine.load_save_data(&data);
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => error!("Failed to read save data from {}: {}", path.display(), e),
+      }
+      // Cheats are stored next to the ROM like the save file, e.g. game.cht for game.gb
+      let cheats_path = path.with_extension("cht");
+      if cheats_path.is_file() {
+        match Cheats::from_path(&cheats_path) {
+          Ok(cheats) => {
+            info!(
+              "Loaded {} cheats from {}",
+              cheats.cheats.len(),
+              cheats_path.display()
+            );
+            machine.set_cheats(&cheats);
+          }
+          Err(e) => error!(
+            "Failed to read cheats from {}: {}",
+            cheats_path.display(),
+            e
+          ),
+        }
+      }
+    }
+    let tilt = match config.cartridge.cartridge_type {
+      CartridgeType::Mbc7 => Some(TiltInput::default()),
+      _ => None,
+    };
     let screen = gui::InGameScreen::new(&config);
     let fps_counter = FpsCounter::new();
     let perf_counter = PerfCounter::new();
@@ -151,6 +230,30 @@ impl InGameState {
       fps_counter,
       perf_counter,
       delta: Duration::default(),
+      tilt,
+      gdb,
+    }
+  }
+  pub fn save(&mut self) {
+    if let Err(e) = self.machine.flush_trace() {
+      error!("Failed to write instruction trace: {}", e);
+    }
+    if let (Some(path), Some(data)) = (&self.config.cartridge.save_path, self.machine.save_data()) {
+      match fs::write(path, data) {
+        Ok(_) => info!("Saved save data to {}", path.display()),
+        Err(e) => error!("Failed to write save data to {}: {}", path.display(), e),
+      }
+    }
+    if let (Some(path), Some(data)) = (&self.config.cartridge.flash_path, self.machine.flash_data())
+    {
+      match fs::write(path, data) {
+        Ok(_) => info!("Saved flash cart ROM to {}", path.display()),
+        Err(e) => error!(
+          "Failed to write flash cart ROM to {}: {}",
+          path.display(),
+          e
+        ),
+      }
     }
   }
   pub fn update_delta_time(&mut self, delta: Duration) {
@@ -167,6 +270,31 @@ impl InGameState {
     let machine_cycles =
       EmuTime::from_machine_cycles(((self.delta * CPU_SPEED_HZ as u32).as_secs() as u64) / 4);
 
+    if let Some(gdb) = &mut self.gdb {
+      match gdb.poll(&mut self.machine) {
+        Ok(true) => (),
+        Ok(false) => {
+          info!("GDB detached");
+          self.gdb = None;
+        }
+        Err(e) => {
+          error!("GDB connection failed: {}", e);
+          self.gdb = None;
+        }
+      }
+    }
+    if let Some(gdb) = &mut self.gdb {
+      if gdb.take_step_events().contains(EmuEvents::VSYNC) {
+        renderer.update_pixels(self.machine.screen_buffer());
+      }
+      // Single steps have moved the machine ahead
+      self.emu_time = self.machine.emu_time();
+      if !gdb.is_running() {
+        self.screen.render(ui);
+        return;
+      }
+    }
+
     let target_time = self.emu_time + machine_cycles;
     loop {
       let (events, end_time) = self.machine.emulate(target_time);
@@ -175,6 +303,16 @@ impl InGameState {
         renderer.update_pixels(self.machine.screen_buffer());
       }
 
+      if events.contains(EmuEvents::BREAKPOINT) {
+        if let Some(gdb) = &mut self.gdb {
+          if let Err(e) = gdb.breakpoint_hit(&self.machine) {
+            error!("GDB connection failed: {}", e);
+          }
+          self.emu_time = end_time;
+          break;
+        }
+      }
+
       if end_time >= target_time {
         self.perf_counter.update(end_time - self.emu_time, delta_s);
         self.emu_time = end_time;
@@ -185,28 +323,88 @@ impl InGameState {
   }
 }
 
+/// Tilt sensor input for MBC7 cartridges, driven by the arrow keys or the left stick
+#[derive(Default)]
+struct TiltInput {
+  left: bool,
+  right: bool,
+  up: bool,
+  down: bool,
+  stick_x: f32,
+  stick_y: f32,
+}
+
+impl TiltInput {
+  /// Returns the new tilt value if the key controls tilt
+  fn handle_key(
+    &mut self,
+    keycode: glutin::event::VirtualKeyCode,
+    pressed: bool,
+  ) -> Option<(f32, f32)> {
+    use glium::glutin::event::VirtualKeyCode::*;
+    match keycode {
+      Left => self.left = pressed,
+      Right => self.right = pressed,
+      Up => self.up = pressed,
+      Down => self.down = pressed,
+      _ => return None,
+    }
+    Some(self.value())
+  }
+  /// Returns the new tilt value if the axis controls tilt
+  fn handle_axis(&mut self, axis: Axis, value: f32) -> Option<(f32, f32)> {
+    match axis {
+      Axis::LeftStickX => self.stick_x = value,
+      Axis::LeftStickY => self.stick_y = value,
+      _ => return None,
+    }
+    Some(self.value())
+  }
+  fn value(&self) -> (f32, f32) {
+    let x = (self.right as i32 - self.left as i32) as f32 + self.stick_x;
+    let y = (self.down as i32 - self.up as i32) as f32 + self.stick_y;
+    (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0))
+  }
+}
+
 impl FrontendState {
-  pub fn from_roms(bootrom: Option<Bootrom>, cartridge: Option<Cartridge>) -> FrontendState {
+  pub fn from_roms(
+    bootrom: Option<Bootrom>,
+    cartridge: Option<Cartridge>,
+    debug: DebugTools,
+  ) -> FrontendState {
     use self::FrontendState::*;
     match (bootrom, cartridge) {
-      (Some(bootrom), Some(cartridge)) => InGame(InGameState::from_config(HardwareConfig {
-        model: bootrom.model,
-        bootrom: Some(bootrom.data),
-        cartridge,
-      })),
-      (None, Some(cartridge)) => WaitBootrom(Some(cartridge), gui::WaitBootromScreen::default()),
-      (Some(bootrom), None) => InGame(InGameState::from_config(HardwareConfig {
-        model: bootrom.model,
-        bootrom: Some(bootrom.data),
-        cartridge: Cartridge::no_cartridge(),
-      })),
-      _ => WaitBootrom(None, gui::WaitBootromScreen::default()),
+      (Some(bootrom), Some(cartridge)) => InGame(InGameState::from_config(
+        HardwareConfig {
+          model: bootrom.model,
+          bootrom: Some(bootrom.data),
+          cartridge,
+        },
+        debug,
+      )),
+      (None, Some(cartridge)) => {
+        WaitBootrom(Some(cartridge), debug, gui::WaitBootromScreen::default())
+      }
+      (Some(bootrom), None) => InGame(InGameState::from_config(
+        HardwareConfig {
+          model: bootrom.model,
+          bootrom: Some(bootrom.data),
+          cartridge: Cartridge::no_cartridge(),
+        },
+        debug,
+      )),
+      _ => WaitBootrom(None, debug, gui::WaitBootromScreen::default()),
     }
   }
 }
 
-pub fn run(bootrom: Option<Bootrom>, cartridge: Option<Cartridge>) -> Result<(), Error> {
-  let mut state = FrontendState::from_roms(bootrom, cartridge);
+pub fn run(
+  bootrom: Option<Bootrom>,
+  cartridge: Option<Cartridge>,
+  debug: DebugTools,
+) -> Result<(), Error> {
+  let mut state = FrontendState::from_roms(bootrom, cartridge, debug);
 
   let mut gilrs = Gilrs::new().map_err(|_| anyhow!("Failed to initialize gamepad support"))?;
 
@@ -286,7 +484,10 @@ pub fn run(bootrom: Option<Bootrom>, cartridge: Option<Cartridge>) -> Result<(),
       Event::WindowEvent { event, .. } => match event {
         WindowEvent::Resized(..) => renderer.update_dimensions(&display),
         WindowEvent::DroppedFile(path) => state.drop_file(&path),
-        WindowEvent::CloseRequested | WindowEvent::Destroyed => *control_flow = ControlFlow::Exit,
+        WindowEvent::CloseRequested | WindowEvent::Destroyed => {
+          state.save();
+          *control_flow = ControlFlow::Exit
+        }
         WindowEvent::KeyboardInput { input, .. } => state.handle_keyboard(input),
         _ => (),
       },
@@ -16,7 +16,7 @@
 use imgui::{im_str, Condition, ImString, StyleColor, StyleVar, Ui, Window};
 use std::time::Instant;
 
-use mooneye_gb::config::HardwareConfig;
+use mooneye_gb::config::{CartridgeHeader, HardwareConfig};
 
 pub trait Screen {
   fn render(&mut self, ui: &Ui<'_>);
@@ -91,6 +91,8 @@ pub struct InGameScreen {
   pub perf: f64,
   model: ImString,
   cartridge_title: ImString,
+  cartridge_info: Vec<ImString>,
+  cartridge_warnings: Vec<ImString>,
   show_info_overlay: bool,
   error_overlay: Option<ErrorOverlay>,
 }
@@ -102,6 +104,26 @@ impl InGameScreen {
       perf: 0.0,
       model: ImString::new(format!("{}", config.model)),
       cartridge_title: ImString::new(config.cartridge.title.clone()),
+      cartridge_info: config
+        .cartridge
+        .rom_info
+        .iter()
+        .map(|info| ImString::new(format!("{} ({})", info.name, info.region)))
+        .chain(
+          config
+            .cartridge
+            .header
+            .as_ref()
+            .map(header_info)
+            .unwrap_or_default(),
+        )
+        .collect(),
+      cartridge_warnings: config
+        .cartridge
+        .header
+        .as_ref()
+        .map(header_warnings)
+        .unwrap_or_default(),
       show_info_overlay: false,
       error_overlay: None,
     }
@@ -127,6 +149,12 @@ impl Screen for InGameScreen {
         .build(ui, || {
           ui.text(&self.model);
           ui.text(&self.cartridge_title);
+          for info in &self.cartridge_info {
+            ui.text(info);
+          }
+          for warning in &self.cartridge_warnings {
+            ui.text_colored([1.0, 0.0, 0.0, 1.0], warning);
+          }
           ui.text(format!("FPS: {:.0}, speed: {:.0} %", self.fps, self.perf));
         });
       bg.pop(ui);
@@ -138,3 +166,38 @@ impl Screen for InGameScreen {
     }
   }
 }
+
+fn header_info(header: &CartridgeHeader) -> Vec<ImString> {
+  vec![
+    ImString::new(format!(
+      "{}{}, licensee: {}",
+      header.cgb_flag,
+      if header.sgb_flag { " + SGB" } else { "" },
+      header.licensee
+    )),
+    ImString::new(format!(
+      "Destination: {}, version: {}",
+      header.destination, header.mask_rom_version
+    )),
+  ]
+}
+
+fn header_warnings(header: &CartridgeHeader) -> Vec<ImString> {
+  let mut warnings = Vec::new();
+  if !header.logo_valid {
+    warnings.push(ImString::new("Invalid Nintendo logo"));
+  }
+  if !header.header_checksum.is_valid() {
+    warnings.push(ImString::new(format!(
+      "Bad header checksum: {:02x} (expected {:02x})",
+      header.header_checksum.stored, header.header_checksum.computed
+    )));
+  }
+  if !header.global_checksum.is_valid() {
+    warnings.push(ImString::new(format!(
+      "Bad global checksum: {:04x} (expected {:04x})",
+      header.global_checksum.stored, header.global_checksum.computed
+    )));
+  }
+  warnings
+}
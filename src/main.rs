@@ -15,14 +15,19 @@
 // along with Mooneye GB.  If not, see <http://www.gnu.org/licenses/>.
 #![windows_subsystem = "windows"]
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use log::{error, info, warn};
-use mooneye_gb::config::{Bootrom, Cartridge, Model};
+use mooneye_gb::config::{Bootrom, Cartridge, CartridgeType, Model};
+use mooneye_gb::gdb::GdbServer;
+use mooneye_gb::trace::{TraceFilter, Tracer};
 use simplelog::{LevelFilter, TermLogger, TerminalMode};
-use std::ffi::OsStr;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 use std::process;
 
+mod disasm;
 mod fps_counter;
 mod frame_times;
 mod frontend;
@@ -37,6 +42,7 @@ const USAGE: &str = concat!(
 
 Usage:
   mooneye-gb [options] [<rom>]
+  mooneye-gb disasm [options] <rom>
   mooneye-gb (-h | --help)
 
 Options:
@@ -44,6 +50,18 @@ Options:
   -m MODEL, --model MODEL  Emulate a specific Game Boy model.
                            Valid values: dmg0, dmg, mgb, sgb, sgb2.
   -b FILE, --bootrom FILE  Use a boot ROM
+  -p FILE, --patch FILE    Apply an IPS/UPS/BPS patch to the ROM.
+                           By default a patch next to the ROM is used.
+  --flash-cart             Emulate an MBC5 cartridge as a flash cart, and
+                           write flash changes to <rom>.flash.gb, which is
+                           loaded instead of the ROM and patch next time.
+  --trace FILE             Write an instruction trace in the Gameboy Doctor
+                           format.
+  --trace-pc START-END     Trace only instructions in a hex address range.
+  --trace-bank BANK        Trace only instructions in a cartridge bank.
+  --trace-after CYCLES     Start tracing after a number of machine cycles.
+  --gdb PORT               Wait for GDB to connect to a TCP port on localhost
+                           before starting.
 "
 );
 
@@ -52,6 +70,13 @@ struct Args {
   help: bool,
   flag_model: Option<Model>,
   flag_bootrom: Option<PathBuf>,
+  flag_patch: Option<PathBuf>,
+  flag_flash_cart: bool,
+  flag_trace: Option<PathBuf>,
+  flag_trace_pc: Option<RangeInclusive<u16>>,
+  flag_trace_bank: Option<usize>,
+  flag_trace_after: Option<u64>,
+  flag_gdb: Option<u16>,
   arg_rom: Option<PathBuf>,
 }
 
@@ -59,22 +84,59 @@ fn parse_path(s: &OsStr) -> Result<PathBuf, &'static str> {
   Ok(s.into())
 }
 
+fn parse_pc_range(s: &str) -> Result<RangeInclusive<u16>, Error> {
+  let parse = |s: &str| {
+    u16::from_str_radix(s.trim_start_matches('$').trim_start_matches("0x"), 16)
+      .map_err(|_| anyhow!("Invalid address range \"{}\"", s))
+  };
+  match s.find('-') {
+    Some(idx) => Ok(parse(&s[..idx])?..=parse(&s[idx + 1..])?),
+    None => Err(anyhow!("Invalid address range \"{}\"", s)),
+  }
+}
+
 fn parse_args(mut args: pico_args::Arguments) -> Result<Args, Error> {
   let help = args.contains(["-h", "--help"]);
   let flag_model = args.opt_value_from_str(["-m", "--model"])?;
   let flag_bootrom = args.opt_value_from_os_str(["-b", "--bootrom"], parse_path)?;
+  let flag_patch = args.opt_value_from_os_str(["-p", "--patch"], parse_path)?;
+  let flag_flash_cart = args.contains("--flash-cart");
+  let flag_trace = args.opt_value_from_os_str("--trace", parse_path)?;
+  let flag_trace_pc = args.opt_value_from_fn("--trace-pc", parse_pc_range)?;
+  let flag_trace_bank = args.opt_value_from_str("--trace-bank")?;
+  let flag_trace_after = args.opt_value_from_str("--trace-after")?;
+  let flag_gdb = args.opt_value_from_str("--gdb")?;
   let arg_rom = args.opt_free_from_os_str(parse_path)?;
   let _ = args.finish();
   Ok(Args {
     help,
     flag_model,
     flag_bootrom,
+    flag_patch,
+    flag_flash_cart,
+    flag_trace,
+    flag_trace_pc,
+    flag_trace_bank,
+    flag_trace_after,
+    flag_gdb,
     arg_rom,
   })
 }
 
 fn main() -> Result<(), Error> {
-  match parse_args(pico_args::Arguments::from_env()) {
+  let mut args: Vec<OsString> = env::args_os().skip(1).collect();
+  if args.first().map(|arg| arg == "disasm").unwrap_or(false) {
+    args.remove(0);
+    return match disasm::parse_args(pico_args::Arguments::from_vec(args)) {
+      Err(e) => {
+        eprintln!("{}", e);
+        eprintln!("{}", disasm::USAGE);
+        process::exit(1);
+      }
+      Ok(args) => disasm::run(args),
+    };
+  }
+  match parse_args(pico_args::Arguments::from_vec(args)) {
     Err(e) => {
       eprintln!("{}", e);
       eprintln!("{}", USAGE);
@@ -105,6 +167,12 @@ fn read_boot_rom(path: &Path, expected_model: Option<Model>) -> Bootrom {
   bootrom
 }
 
+/// Returns the path flash cart changes are written to. The ROM file, archive and patch are
+/// left untouched, and the flash ROM already includes any patch
+fn flash_rom_path(rom_path: &Path) -> PathBuf {
+  rom_path.with_extension("flash.gb")
+}
+
 fn run(args: Args) -> Result<(), Error> {
   let _ = TermLogger::init(
     LevelFilter::Debug,
@@ -126,14 +194,77 @@ fn run(args: Args) -> Result<(), Error> {
     (None, None) => Bootrom::lookup(&[]),
   };
 
+  let flag_patch = args.flag_patch;
+  let flag_flash_cart = args.flag_flash_cart;
   let cartridge = args.arg_rom.map(|path| {
-    Cartridge::from_path(&path).unwrap_or_else(|err| {
+    let flash_path = if flag_flash_cart {
+      Some(flash_rom_path(&path))
+    } else {
+      None
+    };
+    let result = match (
+      flash_path.as_ref().filter(|path| path.is_file()),
+      flag_patch,
+    ) {
+      (Some(flash_path), patch_path) => {
+        if patch_path.is_some() {
+          warn!(
+            "Ignoring the patch, because {} already contains the patched ROM",
+            flash_path.display()
+          );
+        }
+        info!("Loading flash cart ROM from {}", flash_path.display());
+        // Save data stays next to the original ROM
+        Cartridge::from_path_patched(flash_path, None).map(|cartridge| Cartridge {
+          save_path: Some(path.with_extension("sav")),
+          ..cartridge
+        })
+      }
+      (None, Some(ref patch_path)) => {
+        Cartridge::from_path_patched(&path, Some(patch_path.as_path()))
+      }
+      (None, None) => Cartridge::from_path_lenient(&path),
+    };
+    let mut cartridge = result.unwrap_or_else(|err| {
       error!("Failed to read rom from \"{}\" ({})", path.display(), err);
       process::exit(1)
+    });
+    if let Some(flash_path) = flash_path {
+      match cartridge.cartridge_type {
+        CartridgeType::Mbc5 { .. } => cartridge.flash_path = Some(flash_path),
+        _ => warn!("Only MBC5 cartridges can be emulated as flash carts"),
+      }
+    }
+    cartridge
+  });
+
+  let filter = TraceFilter {
+    pc: args.flag_trace_pc.unwrap_or(0x0000..=0xffff),
+    bank: args.flag_trace_bank,
+    after_cycles: args.flag_trace_after.unwrap_or(0),
+  };
+  let tracer = args.flag_trace.map(|path| {
+    Tracer::from_path(&path, filter).unwrap_or_else(|err| {
+      error!(
+        "Failed to create trace file \"{}\" ({})",
+        path.display(),
+        err
+      );
+      process::exit(1)
+    })
+  });
+
+  let gdb = args.flag_gdb.map(|port| {
+    GdbServer::listen(port).unwrap_or_else(|err| {
+      error!(
+        "Failed to accept a GDB connection on port {} ({})",
+        port, err
+      );
+      process::exit(1)
     })
   });
 
-  frontend::run(bootrom, cartridge)?;
+  frontend::run(bootrom, cartridge, frontend::DebugTools { tracer, gdb })?;
 
   Ok(())
 }